@@ -15,6 +15,7 @@ struct RayMarchResult {
     color: vec3f,
     distance: f32,
     color_debug: vec4f,
+    material: u32, // Payload of the voxel, index into `VoxelMaterials`
 }
 
 struct Intersection {
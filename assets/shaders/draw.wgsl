@@ -12,6 +12,7 @@
     VOXEL_TREE_DEPTH,
     VOXEL_IDX_EMPTY,
     VOXEL_COUNT,
+    VOXEL_MASK_LEN,
     VOXEL_SIZE,
     VOXEL_DIM,
     VOXEL_SIZES,
@@ -34,6 +35,11 @@ var <workgroup> num_divided: atomic<u32>;
 var <workgroup> sum_r: atomic<u32>;
 var <workgroup> sum_g: atomic<u32>;
 var <workgroup> sum_b: atomic<u32>;
+var <workgroup> mask_occupied: array<atomic<u32>, VOXEL_MASK_LEN>;
+var <workgroup> mask_divided: array<atomic<u32>, VOXEL_MASK_LEN>;
+var <workgroup> material_counts: array<atomic<u32>, 256>;
+// `count << 8 | (255 - material)` of the most common material
+var <workgroup> material_majority: atomic<u32>;
 var <workgroup> parent_ptr: u32;
 var <workgroup> lod_ptr: u32;
 
@@ -44,6 +50,31 @@ struct DrawParams {
     world_max: vec3i,
 }
 
+fn reset_materials(lidx: i32) {
+    for (var m = u32(lidx); m < 256u; m += u32(VOXEL_COUNT)) {
+        atomicStore(&material_counts[m], 0u);
+    }
+
+    if (lidx == 0) {
+        atomicStore(&material_majority, 0u);
+    }
+}
+
+// Call once the counts are complete, needs a barrier before `majority_material`
+fn vote_material(lidx: i32) {
+    for (var m = u32(lidx); m < 256u; m += u32(VOXEL_COUNT)) {
+        let count = atomicLoad(&material_counts[m]);
+        if (count > 0u) {
+            atomicMax(&material_majority, (count << 8u) | (255u - m));
+        }
+    }
+}
+
+// Most common material of the occupied cells, the lowest one wins ties like in `voxel_lod.rs`
+fn majority_material() -> u32 {
+    return 255u - (atomicLoad(&material_majority) & 0xffu);
+}
+
 fn draw_begin(comp: ComputeBuiltins) -> DrawParams {
     let min = vox::push_constants.min.xyz;
     let max = vox::push_constants.max.xyz;
@@ -77,6 +108,11 @@ fn draw_begin(comp: ComputeBuiltins) -> DrawParams {
     atomicStore(&sum_r, 0u);
     atomicStore(&sum_g, 0u);
     atomicStore(&sum_b, 0u);
+    reset_materials(lidx);
+
+    if (lidx < VOXEL_MASK_LEN) {
+        atomicStore(&mask_occupied[lidx], 0u);
+    }
     
     return DrawParams(draw_buffer[lidx], ipos, world_min, world_max);
 }
@@ -114,14 +150,19 @@ fn draw_end(comp: ComputeBuiltins, voxel: u32) {
     // Simply calculate occupied cells
     if (draw_buffer[lidx] != VOXEL_IDX_EMPTY) {
         atomicAdd(&num_occupied, 1u);
+        atomicOr(&mask_occupied[u32(lidx) >> 5u], 1u << (u32(lidx) & 31u));
 
         let cur = vec3u(unpack4x8unorm(draw_buffer[lidx]).xyz * 255.f);
         atomicAdd(&sum_r, cur.x);
         atomicAdd(&sum_g, cur.y);
         atomicAdd(&sum_b, cur.z);
+        atomicAdd(&material_counts[draw_buffer[lidx] >> 24u], 1u);
     }
 
     workgroupBarrier();
+
+    vote_material(lidx);
+    workgroupBarrier();
     
     let num_occupied_v = atomicLoad(&num_occupied);
     let num_different_v = atomicLoad(&num_different);
@@ -133,14 +174,17 @@ fn draw_end(comp: ComputeBuiltins, voxel: u32) {
         // }
 
         vox::set_draw_area(0u, u32(widx), vox::DrawResult(VOXEL_IDX_EMPTY, VOXEL_IDX_EMPTY));
-        
-        // TODO: destroy leaf if allocated
+
+        // The parent drops its reference to the brick
+        if (lidx == 0 && parent_ptr != VOXEL_IDX_EMPTY) {
+            vox::free_leaf(parent_ptr);
+        }
         return;
     }
     
     let sum_color_u = vec3u(atomicLoad(&sum_r), atomicLoad(&sum_g), atomicLoad(&sum_b));
     let mean_color = vec3f(sum_color_u) / f32(num_occupied_v) / 255.f;
-    var mean_color_u = pack4x8unorm(vec4f(mean_color, 0.));
+    var mean_color_u = pack4x8unorm(vec4f(mean_color, 0.)) | (majority_material() << 24u);
 //    if (num_occupied_v < u32(VOXEL_COUNT) / 16u) {
 //        mean_color_u = VOXEL_IDX_EMPTY;
 //    }
@@ -152,19 +196,32 @@ fn draw_end(comp: ComputeBuiltins, voxel: u32) {
         // }
 
         vox::set_draw_area(0u, u32(widx), vox::DrawResult(VOXEL_IDX_EMPTY, mean_color_u));
-        
-        // TODO: destroy leaf if allocated
+
+        // Collapsed into the parent's LOD
+        if (lidx == 0 && parent_ptr != VOXEL_IDX_EMPTY) {
+            vox::free_leaf(parent_ptr);
+        }
         return;
     }
 
     // Allocate chunk in global memory
     if (lidx == 0 && parent_ptr == VOXEL_IDX_EMPTY) {
-        parent_ptr = atomicAdd(&vox::info.leafs_len, 1u);
+        parent_ptr = vox::alloc_leaf();
     }
     
     let gptr = workgroupUniformLoad(&parent_ptr);
+    if (gptr == VOXEL_IDX_EMPTY) {
+        // Out of leafs, the brick is dropped until the pool grows
+        vox::set_draw_area(0u, u32(widx), vox::DrawResult(VOXEL_IDX_EMPTY, mean_color_u));
+        return;
+    }
+
     vox::set_draw_area(0u, u32(widx), vox::DrawResult(gptr, mean_color_u));
     vox::leafs[gptr].voxels[lidx].color = draw_buffer[lidx];
+
+    if (lidx < VOXEL_MASK_LEN) {
+        vox::leafs[gptr].mask[lidx] = atomicLoad(&mask_occupied[lidx]);
+    }
 }
 
 @compute @workgroup_size(VOXEL_DIM, VOXEL_DIM, VOXEL_DIM)
@@ -216,6 +273,12 @@ fn draw_nodes(
     atomicStore(&sum_r, 0u);
     atomicStore(&sum_g, 0u);
     atomicStore(&sum_b, 0u);
+    reset_materials(lidx);
+
+    if (lidx < VOXEL_MASK_LEN) {
+        atomicStore(&mask_occupied[lidx], 0u);
+        atomicStore(&mask_divided[lidx], 0u);
+    }
     
     if (all(ipos >= min) && all(ipos < max)) {
         let cpos = ipos - min;
@@ -238,18 +301,24 @@ fn draw_nodes(
     // Simply calculate occupied cells
     if (draw_buffer[lidx] != VOXEL_IDX_EMPTY) {
         atomicAdd(&num_occupied, 1u);
+        atomicOr(&mask_occupied[u32(lidx) >> 5u], 1u << (u32(lidx) & 31u));
 
         let cur = vec3u(unpack4x8unorm(draw_buffer[lidx]).xyz * 255.f);
         atomicAdd(&sum_r, cur.x);
         atomicAdd(&sum_g, cur.y);
         atomicAdd(&sum_b, cur.z);
+        atomicAdd(&material_counts[draw_buffer[lidx] >> 24u], 1u);
     }
     
     if (child_ptr != VOXEL_IDX_EMPTY) {
         atomicAdd(&num_divided, 1u);
+        atomicOr(&mask_divided[u32(lidx) >> 5u], 1u << (u32(lidx) & 31u));
     }
 
     workgroupBarrier();
+
+    vote_material(lidx);
+    workgroupBarrier();
     
     let num_occupied_v = atomicLoad(&num_occupied);
     let num_different_v = atomicLoad(&num_different);
@@ -261,13 +330,17 @@ fn draw_nodes(
             vox::set_draw_area(draw_area_index, u32(widx), vox::DrawResult(VOXEL_IDX_EMPTY, VOXEL_IDX_EMPTY));
         // }
 
-        // TODO: destroy leaf if allocated
+        // The root is never freed, it's reserved by `clear`
+        if (lidx == 0 && parent_ptr != VOXEL_IDX_EMPTY && parent_ptr != 0u) {
+            vox::free_node(parent_ptr);
+            vox::free_leaf(lod_ptr);
+        }
         return;
     }
 
     let sum_color_u = vec3u(atomicLoad(&sum_r), atomicLoad(&sum_g), atomicLoad(&sum_b));
     let mean_color = vec3f(sum_color_u) / f32(num_occupied_v) / 255.f;
-    var mean_color_u = pack4x8unorm(vec4f(mean_color, 0.));
+    var mean_color_u = pack4x8unorm(vec4f(mean_color, 0.)) | (majority_material() << 24u);
 //    if (num_occupied_v < u32(VOXEL_COUNT) / 16u) {
 //        mean_color_u = VOXEL_IDX_EMPTY;
 //    }
@@ -279,22 +352,45 @@ fn draw_nodes(
         // }
 
         vox::set_draw_area(draw_area_index, u32(widx), vox::DrawResult(VOXEL_IDX_EMPTY, mean_color_u));
-        
-        // TODO: destroy leaf if allocated
+
+        if (lidx == 0 && parent_ptr != VOXEL_IDX_EMPTY && parent_ptr != 0u) {
+            vox::free_node(parent_ptr);
+            vox::free_leaf(lod_ptr);
+        }
         return;
     }
 
     // Allocate chunk in global memory
     if (lidx == 0 && parent_ptr == VOXEL_IDX_EMPTY) {
-        parent_ptr = atomicAdd(&vox::info.nodes_len, 1u);
-        lod_ptr = atomicAdd(&vox::info.leafs_len, 1u);
+        parent_ptr = vox::alloc_node();
+        lod_ptr = vox::alloc_leaf();
+
+        // Give back the half that fit
+        if (parent_ptr == VOXEL_IDX_EMPTY && lod_ptr != VOXEL_IDX_EMPTY) {
+            vox::free_leaf(lod_ptr);
+        }
+
+        if (parent_ptr != VOXEL_IDX_EMPTY && lod_ptr == VOXEL_IDX_EMPTY) {
+            vox::free_node(parent_ptr);
+            parent_ptr = VOXEL_IDX_EMPTY;
+        }
     }
     
     let gptr = workgroupUniformLoad(&parent_ptr);
     let lptr = workgroupUniformLoad(&lod_ptr);
+    if (gptr == VOXEL_IDX_EMPTY) {
+        // Out of nodes or leafs, the node is dropped until the pools grow
+        vox::set_draw_area(draw_area_index, u32(widx), vox::DrawResult(VOXEL_IDX_EMPTY, mean_color_u));
+        return;
+    }
 
     vox::set_draw_area(draw_area_index, u32(widx), vox::DrawResult(gptr, mean_color_u));
     vox::nodes[gptr].leaf = lptr;
     vox::nodes[gptr].indices[lidx] = child_ptr;
     vox::leafs[lptr].voxels[lidx].color = draw_buffer[lidx];
+
+    if (lidx < VOXEL_MASK_LEN) {
+        vox::nodes[gptr].mask[lidx] = atomicLoad(&mask_divided[lidx]);
+        vox::leafs[lptr].mask[lidx] = atomicLoad(&mask_occupied[lidx]);
+    }
 }
\ No newline at end of file
@@ -8,17 +8,34 @@
     draw_begin,
     draw_end,
 }
+#import voxel_tracer::voxel_write as vox
 #import voxel_tracer::voxel_common::{
     VOXEL_IDX_EMPTY,
     VOXEL_TREE_DEPTH,
     VOXEL_COUNT,
     VOXEL_DIM,
+    VOXEL_MASK_LEN,
     pos_to_idx,
+    mask_word,
+    mask_bit,
 }
 
-@group(1) @binding(0) var<storage, read_write> import_nodes: array<array<u32, VOXEL_COUNT>>;
-@group(1) @binding(1) var<storage, read_write> import_leafs: array<array<u32, VOXEL_COUNT>>;
+// Layouts of `VoxelNode` and `VoxelLeaf` from `voxel_tree.rs`
+struct ImportNode {
+    leaf: u32,
+    mask: array<u32, VOXEL_MASK_LEN>,
+    indices: array<u32, VOXEL_COUNT>,
+}
+
+struct ImportLeaf {
+    mask: array<u32, VOXEL_MASK_LEN>,
+    voxels: array<u32, VOXEL_COUNT>,
+}
 
+@group(1) @binding(0) var<storage, read_write> import_nodes: array<ImportNode>;
+@group(1) @binding(1) var<storage, read_write> import_leafs: array<ImportLeaf>;
+
+// `world_pos` is in the local coords of the imported tree
 fn query_import(world_pos: vec3i) -> u32 {
     let depth = u32(VOXEL_TREE_DEPTH - 1);
     var parent_idx = 0u;
@@ -27,27 +44,37 @@ fn query_import(world_pos: vec3i) -> u32 {
     for (var i = 0u; i < depth; i++) {
         voxel_size *= u32(VOXEL_DIM);
     }
+
+    // Voxels of the batch outside of the drawn region may fall outside of the tree
+    let size = i32(voxel_size) * VOXEL_DIM;
+    if (any(world_pos < vec3i(0)) || any(world_pos >= vec3i(size))) {
+        return VOXEL_IDX_EMPTY;
+    }
     
     for (var i = 0u; i < depth; i++) {
         let lpos = (world_pos / vec3i(voxel_size)) % vec3i(VOXEL_DIM);
         let idx = pos_to_idx(lpos);
 
-        var child_idx = import_nodes[parent_idx][idx];
-        if (child_idx == VOXEL_IDX_EMPTY) {
-            return VOXEL_IDX_EMPTY;
+        if ((import_nodes[parent_idx].mask[mask_word(idx)] & mask_bit(idx)) == 0u) {
+            // Collapsed uniform brick or empty
+            let lod_idx = import_nodes[parent_idx].leaf;
+            if (lod_idx == VOXEL_IDX_EMPTY) {
+                return VOXEL_IDX_EMPTY;
+            }
+            return import_leafs[lod_idx].voxels[idx];
         }
 
-        parent_idx = child_idx;
+        parent_idx = import_nodes[parent_idx].indices[idx];
         voxel_size = voxel_size / u32(VOXEL_DIM);
     }
 
     let lpos = world_pos % vec3i(VOXEL_DIM);
     let idx = pos_to_idx(lpos);
-    return import_leafs[parent_idx][idx];
+    return import_leafs[parent_idx].voxels[idx];
 }
 
 fn draw_import(params: DrawParams) -> u32 {
-    return query_import(params.world_pos);
+    return query_import(params.world_pos + vox::push_constants.import_offset.xyz);
 }
 
 
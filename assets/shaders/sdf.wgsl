@@ -68,7 +68,7 @@ fn trace(pos: vec3<f32>, dir: vec3<f32>) -> RayMarchResult {
         if (dst < min_dst) {
             let normal = normal_world(p);
             let color = normal * 0.5 + 0.5;
-            return RayMarchResult(normal, color, ray_len, vec4f(0.));
+            return RayMarchResult(normal, color, ray_len, vec4f(0.), 0u);
         }
         
         if (dst > max_dst) {
@@ -78,6 +78,6 @@ fn trace(pos: vec3<f32>, dir: vec3<f32>) -> RayMarchResult {
         ray_len += dst;
     }
 
-    return RayMarchResult(vec3<f32>(), vec3<f32>(), DST_MAX, vec4f(0.));
+    return RayMarchResult(vec3<f32>(), vec3<f32>(), DST_MAX, vec4f(0.), 0u);
 }
 
@@ -4,35 +4,67 @@ const VOXEL_SIZE: f32 = 1.0f;
 const VOXEL_DIM: i32 = #{VOXEL_DIM};
 const VOXEL_COUNT: i32 = VOXEL_DIM * VOXEL_DIM * VOXEL_DIM;
 const VOXEL_TREE_DEPTH: i32 = #{VOXEL_TREE_DEPTH};
-// const VOXEL_MASK_LEN: i32 = #{VOXEL_MASK_LEN};
+const VOXEL_MASK_LEN: i32 = #{VOXEL_MASK_LEN};
 
 const VOXEL_IDX_EMPTY: u32 = #{VOXEL_IDX_EMPTY};
 
+// World position of the min corner of the GPU world, `VoxelWorldDims::origin`
+const VOXEL_WORLD_ORIGIN = vec3f(
+    f32(#{VOXEL_WORLD_ORIGIN_X}),
+    f32(#{VOXEL_WORLD_ORIGIN_Y}),
+    f32(#{VOXEL_WORLD_ORIGIN_Z}),
+);
+
+// Size of a cell at every depth, only `0..=VOXEL_TREE_DEPTH` are meaningful.
+// Has `VOXEL_SIZES_LEN` entries from `render.rs` so depth can go up to 15.
 const VOXEL_SIZES = array(
-    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(u32(VOXEL_TREE_DEPTH) - 0))),
-    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(u32(VOXEL_TREE_DEPTH) - 1))),
-    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(u32(VOXEL_TREE_DEPTH) - 2))),
-    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(u32(VOXEL_TREE_DEPTH) - 3))),
-    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(u32(VOXEL_TREE_DEPTH) - 4))),
-    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(u32(VOXEL_TREE_DEPTH) - 5))),
-    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(u32(VOXEL_TREE_DEPTH) - 6))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 0))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 1))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 2))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 3))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 4))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 5))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 6))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 7))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 8))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 9))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 10))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 11))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 12))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 13))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 14))),
+    VOXEL_SIZE * f32(pow(f32(VOXEL_DIM), f32(VOXEL_TREE_DEPTH - 15))),
 );
 
 fn pos_to_idx(ipos: vec3<i32>) -> u32 {
     return u32(ipos.x * VOXEL_DIM * VOXEL_DIM + ipos.y * VOXEL_DIM + ipos.z);
 }
 
+// Index of the `mask` word for the voxel `idx`
+fn mask_word(idx: u32) -> u32 {
+    return idx >> 5u;
+}
+
+// Bit of the voxel `idx` inside of its `mask` word
+fn mask_bit(idx: u32) -> u32 {
+    return 1u << (idx & 31u);
+}
+
 struct Voxel {
     color: u32,
 }
 
 // TODO: rename to VoxelBrick or VoxelBlock or something like that
 struct VoxelLeaf {
+    // Bit is set for every non-empty voxel
+    mask: array<u32, VOXEL_MASK_LEN>,
     voxels: array<Voxel, VOXEL_COUNT>,
 }
 
 struct VoxelNode {
     leaf: u32, 
+    // Bit is set for every allocated child in `indices`
+    mask: array<u32, VOXEL_MASK_LEN>,
     // Indices to either `nodes` or `leafs` depending on the current depth
     indices: array<u32, VOXEL_COUNT>,
 }
@@ -2,7 +2,6 @@
 #import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
 #import bevy_pbr::pbr_types::PbrInput 
 #import bevy_pbr::pbr_types::pbr_input_new
-#import bevy_pbr::pbr_types::STANDARD_MATERIAL_FLAGS_UNLIT_BIT
 #import bevy_pbr::pbr_types::STANDARD_MATERIAL_FLAGS_ALPHA_MODE_OPAQUE
 #import bevy_pbr::pbr_deferred_functions::deferred_gbuffer_from_pbr_input
 
@@ -11,7 +10,16 @@
 #import voxel_tracer::sdf as sdf
 #import voxel_tracer::voxel_read as vox
 
+// Same as `VoxelMaterial` in `voxel_material.rs`
+struct VoxelMaterial {
+    perceptual_roughness: f32,
+    metallic: f32,
+    emissive: f32,
+    reflectance: f32,
+}
+
 @group(1) @binding(0) var<uniform> view : View;
+@group(1) @binding(1) var<storage, read> materials: array<VoxelMaterial>;
 
 struct FragmentOutputWithDepth {
     @location(0) normal: vec4<f32>,
@@ -56,17 +64,25 @@ fn fragment(in: FullscreenVertexOutput) -> FragmentOutputWithDepth {
     //}
     
     //let color = res.normal + 1. * 0.5; 
-    var color = res.color * (dot(res.normal, normalize(vec3f(1., -1., -1.,))) * .5 + .5);
+    var color = res.color;
     // color = res.normal * 0.5 + 0.5;
     color += (res.color_debug.xyz) * res.color_debug.w;
 
+    // The table always has 256 entries, see `VoxelMaterials::gpu_table`
+    let material = materials[res.material & 0xffu];
+
     var pbr_input = pbr_input_new();
     
     pbr_input.frag_coord = vec4(in.uv, 0.5, 1.);
     // pbr_input.material.base_color = vec4f(res.normal * 0.5 + 0.5, 1.);
     pbr_input.material.base_color = vec4f(color, 1.);
-    pbr_input.material.flags |= STANDARD_MATERIAL_FLAGS_UNLIT_BIT;
-    //pbr_input.N = res.position;
+    pbr_input.material.perceptual_roughness = material.perceptual_roughness;
+    pbr_input.material.metallic = material.metallic;
+    pbr_input.material.reflectance = material.reflectance;
+    pbr_input.material.emissive = vec4f(res.color * material.emissive, 1.);
+    pbr_input.world_position = vec4f(pos + dir * res.distance, 1.);
+    pbr_input.world_normal = res.normal;
+    pbr_input.N = res.normal;
  
     // TODO take into account distance from the camera to the near clipping plane
     let depth = view_z_to_depth_ndc(-res.distance);
@@ -15,7 +15,10 @@
     VOXEL_TREE_DEPTH,
     VOXEL_SIZES,
     VOXEL_IDX_EMPTY,
+    VOXEL_WORLD_ORIGIN,
     pos_to_idx,
+    mask_word,
+    mask_bit,
     Voxel,
     VoxelLeaf,
     VoxelNode
@@ -39,36 +42,26 @@ fn get_voxel_leaf(index: u32, ipos: vec3<i32>) -> bool {
     let i = pos_to_idx(ipos);
     let leaf = &leafs[index];
     
-    // let ti = i >> 5;
-    // let oi = ti * 32;
-    // 
-    // return ((*leaf).mask[ti] & (1u << (i - oi))) > 0;
-    
-    return (*leaf).voxels[i].color != VOXEL_IDX_EMPTY;
+    return ((*leaf).mask[mask_word(i)] & mask_bit(i)) != 0u;
 }
 
 fn get_voxel_nodes(index: u32, ipos: vec3<i32>) -> bool {
     let i = pos_to_idx(ipos);
     let node = &nodes[index];
     
-    // let ti = i >> 5;
-    // let oi = ti * 32;
-    // 
-    // return ((*node).mask[ti] & (1u << (i - oi))) > 0;
-
-    return (*node).indices[i] != VOXEL_IDX_EMPTY;
+    return ((*node).mask[mask_word(i)] & mask_bit(i)) != 0u;
 }
 
 fn get_voxel_nodes_lod(index: u32, ipos: vec3<i32>) -> u32 {
     let i = pos_to_idx(ipos);
-    let node = &nodes[index];
+    let lod = &leafs[nodes[index].leaf];
     
-    // let ti = i >> 5;
-    // let oi = ti * 32;
-    // 
-    // return ((*node).mask[ti] & (1u << (i - oi))) > 0;
+    // Skip the voxel fetch for empty LOD cells
+    if (((*lod).mask[mask_word(i)] & mask_bit(i)) == 0u) {
+        return VOXEL_IDX_EMPTY;
+    }
 
-    return leafs[(*node).leaf].voxels[i].color;
+    return (*lod).voxels[i].color;
 }
 
 struct RayMarchFrame {
@@ -77,14 +70,17 @@ struct RayMarchFrame {
     tmax: vec3<f32>,
 }
 
-fn trace(pos: vec3<f32>, dir: vec3<f32>) -> RayMarchResult {
+fn trace(world_pos: vec3<f32>, dir: vec3<f32>) -> RayMarchResult {
     var frames: array<RayMarchFrame, VOXEL_TREE_DEPTH>;
+
+    // The tree spans `[0, VOXEL_SIZES[0])` from its origin
+    let pos = world_pos - VOXEL_WORLD_ORIGIN;
     
     var inter_t = 0.f;
     if (!is_inside(pos, vec3f(0.), vec3f(VOXEL_SIZES[0]))) {
         let intersection = ray_bbox(pos, dir, vec3f(0.), vec3f(VOXEL_SIZES[0u]));
         if (!intersection.has) {
-            return RayMarchResult(vec3<f32>(), vec3<f32>(), DST_MAX, vec4(0.));
+            return RayMarchResult(vec3<f32>(), vec3<f32>(), DST_MAX, vec4(0.), 0u);
         }
         inter_t = intersection.t;
     }
@@ -206,7 +202,7 @@ fn trace(pos: vec3<f32>, dir: vec3<f32>) -> RayMarchResult {
                 }
                 
                 let debug_alpha = 1. - pow(0.99, f32(i));
-                return RayMarchResult(normal, color, distance + inter_t, vec4f(1., 1., 1., debug_alpha));
+                return RayMarchResult(normal, color, distance + inter_t, vec4f(1., 1., 1., debug_alpha), voxel.color >> 24u);
             }
         }
         else if (get_voxel_nodes(index, ipos)) {
@@ -281,7 +277,7 @@ fn trace(pos: vec3<f32>, dir: vec3<f32>) -> RayMarchResult {
                 }
                 
                 let debug_alpha = 1. - pow(0.99, f32(i));
-                return RayMarchResult(normal, color, distance + inter_t, vec4f(1., 1., 1., debug_alpha));
+                return RayMarchResult(normal, color, distance + inter_t, vec4f(1., 1., 1., debug_alpha), voxel >> 24u);
             }
         }
         
@@ -294,5 +290,5 @@ fn trace(pos: vec3<f32>, dir: vec3<f32>) -> RayMarchResult {
     }
     
     let debug_alpha = 1. - pow(0.99, f32(i));
-    return RayMarchResult(vec3<f32>(), vec3<f32>(), DST_MAX, vec4f(1., 1., 1., debug_alpha));
+    return RayMarchResult(vec3<f32>(), vec3<f32>(), DST_MAX, vec4f(1., 1., 1., debug_alpha), 0u);
 }
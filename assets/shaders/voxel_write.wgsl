@@ -32,14 +32,20 @@ struct VoxelInfo {
     leafs_cap: u32,
     leafs_len: atomic<u32>,
     leafs_free_count: atomic<u32>,
+
+    // Set when an allocation didn't fit, the CPU grows the pool and draws again
+    nodes_overflow: atomic<u32>,
+    leafs_overflow: atomic<u32>,
 }
 
 @group(0) @binding(0) var<storage, read_write> info : VoxelInfo;
 @group(0) @binding(1) var<storage, read_write> nodes: array<VoxelNode>;
 @group(0) @binding(2) var<storage, read_write> leafs: array<VoxelLeaf>;
 
-@group(0) @binding(3) var<storage, read_write> free_nodes: array<u32>;
-@group(0) @binding(4) var<storage, read_write> free_leafs: array<u32>;
+// Slots released by draws, `info.*_free_count` long. Taken entries are set to
+// `VOXEL_IDX_EMPTY`, see `alloc_leaf`.
+@group(0) @binding(3) var<storage, read_write> free_nodes: array<atomic<u32>>;
+@group(0) @binding(4) var<storage, read_write> free_leafs: array<atomic<u32>>;
 
 struct DrawResult {
     idx: u32, // to `leafs` or `nodes`
@@ -56,6 +62,8 @@ struct PushConstants {
     world_max: vec4i, // excluding
     wsize_children: vec4i,
     depth: u32,
+    // Added to GPU world coords to get the coords of the imported tree, `draw_import` only
+    import_offset: vec4i,
 }
 
 var <push_constant> push_constants: PushConstants;
@@ -80,6 +88,10 @@ fn set_draw_area(draw_area_index: u32, index: u32, value: DrawResult) {
 
 
 fn clear_nodes(idx: u32) {
+    for (var i = 0; i < VOXEL_MASK_LEN; i++) {
+        nodes[idx].mask[i] = 0u;
+    }
+
     for (var i = 0; i < VOXEL_COUNT; i++) {
         nodes[idx].leaf = VOXEL_IDX_EMPTY;
         nodes[idx].indices[i] = VOXEL_IDX_EMPTY;
@@ -91,6 +103,10 @@ fn clear_nodes(idx: u32) {
 }
 
 fn clear_leafs(idx: u32) {
+    for (var i = 0; i < VOXEL_MASK_LEN; i++) {
+        leafs[idx].mask[i] = 0u;
+    }
+
     for (var i = 0; i < VOXEL_COUNT; i++) {
         leafs[idx].voxels[i].color = VOXEL_IDX_EMPTY;
     }
@@ -103,6 +119,17 @@ fn clear(idx: u32) {
     atomicStore(&info.nodes_free_count, 0u);
     atomicStore(&info.leafs_free_count, 0u);
 
+    atomicStore(&info.nodes_overflow, 0u);
+    atomicStore(&info.leafs_overflow, 0u);
+
+    if (idx < arrayLength(&free_nodes)) {
+        atomicStore(&free_nodes[idx], VOXEL_IDX_EMPTY);
+    }
+
+    if (idx < arrayLength(&free_leafs)) {
+        atomicStore(&free_leafs[idx], VOXEL_IDX_EMPTY);
+    }
+
     if (idx < info.leafs_cap) {
         clear_leafs(idx);
     }
@@ -111,6 +138,80 @@ fn clear(idx: u32) {
     }
 }
 
+// A freed slot if there is one, otherwise a new one. `VOXEL_IDX_EMPTY` once the pool
+// is full, the overflow is counted for the CPU to grow it.
+fn alloc_leaf() -> u32 {
+    var count = atomicLoad(&info.leafs_free_count);
+    for (var i = 0; i < SPIN_LOCK_MAX && count > 0u; i++) {
+        let res = atomicCompareExchangeWeak(&info.leafs_free_count, count, count - 1u);
+        if (res.exchanged) {
+            // Empty while the `free_leaf` that pushed it hasn't stored it yet, that
+            // slot is lost until the next clear
+            let idx = atomicExchange(&free_leafs[count - 1u], VOXEL_IDX_EMPTY);
+            if (idx != VOXEL_IDX_EMPTY) {
+                return idx;
+            }
+            break;
+        }
+        count = res.old_value;
+    }
+
+    let idx = atomicAdd(&info.leafs_len, 1u);
+    if (idx >= info.leafs_cap) {
+        atomicAdd(&info.leafs_overflow, 1u);
+        return VOXEL_IDX_EMPTY;
+    }
+    return idx;
+}
+
+fn alloc_node() -> u32 {
+    var count = atomicLoad(&info.nodes_free_count);
+    for (var i = 0; i < SPIN_LOCK_MAX && count > 0u; i++) {
+        let res = atomicCompareExchangeWeak(&info.nodes_free_count, count, count - 1u);
+        if (res.exchanged) {
+            let idx = atomicExchange(&free_nodes[count - 1u], VOXEL_IDX_EMPTY);
+            if (idx != VOXEL_IDX_EMPTY) {
+                return idx;
+            }
+            break;
+        }
+        count = res.old_value;
+    }
+
+    let idx = atomicAdd(&info.nodes_len, 1u);
+    if (idx >= info.nodes_cap) {
+        atomicAdd(&info.nodes_overflow, 1u);
+        return VOXEL_IDX_EMPTY;
+    }
+    return idx;
+}
+
+// Pushes a slot no longer referenced by its parent. It's dropped when the free list
+// is full and stays allocated until the next clear.
+fn free_leaf(idx: u32) {
+    var count = atomicLoad(&info.leafs_free_count);
+    for (var i = 0; i < SPIN_LOCK_MAX && count < arrayLength(&free_leafs); i++) {
+        let res = atomicCompareExchangeWeak(&info.leafs_free_count, count, count + 1u);
+        if (res.exchanged) {
+            atomicStore(&free_leafs[count], idx);
+            return;
+        }
+        count = res.old_value;
+    }
+}
+
+fn free_node(idx: u32) {
+    var count = atomicLoad(&info.nodes_free_count);
+    for (var i = 0; i < SPIN_LOCK_MAX && count < arrayLength(&free_nodes); i++) {
+        let res = atomicCompareExchangeWeak(&info.nodes_free_count, count, count + 1u);
+        if (res.exchanged) {
+            atomicStore(&free_nodes[count], idx);
+            return;
+        }
+        count = res.old_value;
+    }
+}
+
 // Depending on query's depth is either:
 // - `nodes[parent_idx].indices[idx]` 
 // - `leafs[parent_idx].voxels[idx]
@@ -3,20 +3,49 @@ use bevy::{
     ecs::entity::Entities,
     input::mouse::MouseMotion,
     prelude::*,
+    window::PrimaryWindow,
 };
 
+use crate::{voxel_collision::*, voxel_trace::*, voxel_tree::*};
+
 #[derive(Component)]
 pub struct GameCamera;
 
+// Walking body in voxels, the camera sits at the eye
+const WALK_HALF_WIDTH: f32 = 3.;
+const WALK_HEIGHT: f32 = 16.;
+const WALK_EYE_HEIGHT: f32 = 14.;
+const WALK_STEP_HEIGHT: f32 = 4.;
+const WALK_SPEED: f32 = 40.;
+const WALK_JUMP_SPEED: f32 = 80.;
+const WALK_GRAVITY: f32 = 300.;
+const WALK_GROUND_DIST: f32 = 0.05;
+
+/// `F2` switches `GameCamera` between flying through everything and walking on the voxel
+/// trees with gravity
+#[derive(Resource, Default, Debug)]
+pub struct GameCameraWalk {
+    pub enabled: bool,
+    pub velocity: Vec3,
+    pub is_grounded: bool,
+}
+
 pub fn update_game_camera(
     time: Res<Time>,
     input: Res<ButtonInput<KeyCode>>,
     buttons: Res<ButtonInput<MouseButton>>,
     mut motion_evr: EventReader<MouseMotion>,
     mut q: Query<&mut Transform, With<GameCamera>>,
+    mut walk: ResMut<GameCameraWalk>,
+    voxel_trees: Res<Assets<VoxelTree>>,
 ) {
     let mut transform = q.single_mut();
 
+    if input.just_pressed(KeyCode::F2) {
+        walk.enabled = !walk.enabled;
+        walk.velocity = Vec3::ZERO;
+    }
+
     let speed = if input.pressed(KeyCode::ShiftLeft) {
         500.
     } else {
@@ -65,7 +94,17 @@ pub fn update_game_camera(
 
     let factor = 0.01;
 
-    transform.translation += v.normalize_or_zero() * time.delta_seconds() * speed;
+    if walk.enabled {
+        walk_game_camera(
+            &mut transform,
+            &mut walk,
+            &voxel_trees,
+            &input,
+            time.delta_seconds(),
+        );
+    } else {
+        transform.translation += v.normalize_or_zero() * time.delta_seconds() * speed;
+    }
 
     let (yaw, pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
 
@@ -75,6 +114,168 @@ pub fn update_game_camera(
     transform.rotation = Quat::from_rotation_y(yaw) * Quat::from_rotation_x(pitch);
 }
 
+fn walk_game_camera(
+    transform: &mut Transform,
+    walk: &mut GameCameraWalk,
+    voxel_trees: &Assets<VoxelTree>,
+    input: &ButtonInput<KeyCode>,
+    dt: f32,
+) {
+    // Horizontal movement doesn't depend on the pitch
+    let forward = (-Vec3::from(transform.local_z()) * Vec3::new(1., 0., 1.)).normalize_or_zero();
+    let right = Vec3::from(transform.local_x());
+
+    let mut v = Vec3::ZERO;
+
+    if input.pressed(KeyCode::KeyW) {
+        v += forward;
+    }
+
+    if input.pressed(KeyCode::KeyS) {
+        v -= forward;
+    }
+
+    if input.pressed(KeyCode::KeyA) {
+        v -= right;
+    }
+
+    if input.pressed(KeyCode::KeyD) {
+        v += right;
+    }
+
+    let speed = if input.pressed(KeyCode::ShiftLeft) {
+        WALK_SPEED * 2.
+    } else {
+        WALK_SPEED
+    };
+
+    walk.velocity.y -= WALK_GRAVITY * dt;
+    if walk.is_grounded && input.just_pressed(KeyCode::Space) {
+        walk.velocity.y = WALK_JUMP_SPEED;
+    }
+
+    let horizontal = (v * Vec3::new(1., 0., 1.)).normalize_or_zero() * speed * dt;
+    let delta = horizontal + Vec3::Y * walk.velocity.y * dt;
+
+    let min = transform.translation - Vec3::new(WALK_HALF_WIDTH, WALK_EYE_HEIGHT, WALK_HALF_WIDTH);
+    let max = transform.translation
+        + Vec3::new(
+            WALK_HALF_WIDTH,
+            WALK_HEIGHT - WALK_EYE_HEIGHT,
+            WALK_HALF_WIDTH,
+        );
+
+    let mut res = sweep_voxel_trees(voxel_trees, min, max, delta);
+
+    // Step up: lift the body, move and put it back down, keep it if it got further
+    if walk.is_grounded && (res.hit.x || res.hit.z) {
+        let up = sweep_voxel_trees(voxel_trees, min, max, Vec3::Y * WALK_STEP_HEIGHT);
+        let side = sweep_voxel_trees(voxel_trees, min + up.delta, max + up.delta, horizontal);
+        let raised = up.delta + side.delta;
+        let down = sweep_voxel_trees(
+            voxel_trees,
+            min + raised,
+            max + raised,
+            -Vec3::Y * up.delta.y,
+        );
+
+        let stepped = raised + down.delta;
+        if down.hit.y && stepped.xz().length() > res.delta.xz().length() {
+            res = SweepResult {
+                delta: stepped,
+                hit: BVec3::new(side.hit.x, true, side.hit.z),
+            };
+        }
+    }
+
+    transform.translation += res.delta;
+    if res.hit.y {
+        walk.velocity.y = 0.;
+    }
+
+    let (min, max) = (min + res.delta, max + res.delta);
+    walk.is_grounded = voxel_trees
+        .iter()
+        .any(|(_, tree)| tree.is_on_ground(min, max, WALK_GROUND_DIST));
+}
+
+/// Sweeps against every tree in turn, each one can only shorten the movement
+fn sweep_voxel_trees(
+    voxel_trees: &Assets<VoxelTree>,
+    min: Vec3,
+    max: Vec3,
+    delta: Vec3,
+) -> SweepResult {
+    let mut res = SweepResult {
+        delta,
+        hit: BVec3::FALSE,
+    };
+
+    for (_, tree) in voxel_trees.iter() {
+        let tree_res = tree.sweep_aabb(min, max, res.delta);
+        res.delta = tree_res.delta;
+        res.hit |= tree_res.hit;
+    }
+
+    res
+}
+
+/// Voxel under the cursor, present only while the cursor ray hits a voxel tree
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct VoxelHit {
+    pub tree: AssetId<VoxelTree>,
+    /// Point where the ray enters the voxel, in world coords
+    pub position: Vec3,
+    /// Face normal, zero if the camera is inside the voxel
+    pub normal: Vec3,
+    /// Min corner of the hit cell
+    pub voxel_pos: IVec3,
+    /// Greater than 1 if the hit was resolved by a LOD
+    pub voxel_size: i32,
+    pub voxel: Voxel,
+    pub distance: f32,
+}
+
+/// Traces the ray from `GameCamera` through the cursor against every voxel tree
+pub fn pick_voxel(
+    mut commands: Commands,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    q: Query<(&Camera, &GlobalTransform), With<GameCamera>>,
+    voxel_trees: Res<Assets<VoxelTree>>,
+) {
+    // No camera while loading or after it was despawned
+    let Ok((camera, camera_transform)) = q.get_single() else {
+        commands.remove_resource::<VoxelHit>();
+        return;
+    };
+
+    let ray = windows
+        .get_single()
+        .ok()
+        .and_then(|window| window.cursor_position())
+        .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor));
+
+    let hit = ray.and_then(|ray| {
+        voxel_trees
+            .iter()
+            .filter_map(|(id, tree)| Some((id, tree.trace(ray.origin, *ray.direction)?)))
+            .min_by(|(_, a), (_, b)| a.distance.total_cmp(&b.distance))
+    });
+
+    match hit {
+        Some((tree, res)) => commands.insert_resource(VoxelHit {
+            tree,
+            position: res.position,
+            normal: res.normal,
+            voxel_pos: res.voxel_pos,
+            voxel_size: res.voxel_size,
+            voxel: res.voxel,
+            distance: res.distance,
+        }),
+        None => commands.remove_resource::<VoxelHit>(),
+    }
+}
+
 #[derive(Default)]
 pub struct CameraDiagnosticsPlugin;
 
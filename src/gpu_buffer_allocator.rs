@@ -1,12 +1,8 @@
-use std::marker::PhantomData;
-
 use bevy::{
-    log::tracing_subscriber::filter::targets::Iter,
     prelude::*,
     render::{
         render_resource::{
-            encase::internal::{BufferMut, WriteInto, Writer},
-            BindingResource, Buffer, BufferDescriptor, BufferUsages, ShaderType,
+            BindingResource, Buffer, BufferDescriptor, BufferUsages, CommandEncoder,
         },
         renderer::{RenderDevice, RenderQueue},
     },
@@ -14,81 +10,73 @@ use bevy::{
 
 pub type GpuIdx = u32;
 
-pub struct GpuBufferAllocator<T>
-where
-    T: ShaderType + WriteInto,
-{
-    label: &'static str,
-    len: GpuIdx,
-    cap: GpuIdx,
+/// Pool of fixed size items in a storage buffer.
+/// The item size is chosen at runtime, e.g. bricks of `VoxelWorldDims`.
+/// Freed slots are reused before the pool grows into its capacity, see `SlotAllocator`.
+pub struct GpuBufferAllocator {
+    item_size: u64,
     buffer: Buffer,
-    _phantom: PhantomData<T>,
+    slots: SlotAllocator,
 }
 
-impl<T> GpuBufferAllocator<T>
-where
-    T: ShaderType + WriteInto,
-{
+impl GpuBufferAllocator {
     pub fn new(
         label: &'static str,
+        item_size: u64,
         capacity: GpuIdx,
-        fill: Option<&T>,
         device: &RenderDevice,
-        queue: &RenderQueue,
     ) -> Self {
-        let buffer = device.create_buffer(&BufferDescriptor {
-            label: Some(label),
-            size: (capacity * u64::from(T::min_size()) as GpuIdx).into(),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
-            mapped_at_creation: false,
-        });
-
-        let size = u64::from(T::min_size()) as GpuIdx * capacity;
-
-        if let Some(fill) = fill {
-            let mut buffer_view = queue
-                .write_buffer_with(&buffer, 0, (size as u64).try_into().unwrap())
-                .unwrap();
-
-            let mut offset = 0usize;
-            for _ in 0..capacity {
-                fill.write_into(&mut Writer::new(&fill, &mut *buffer_view, offset).unwrap());
-                offset += u64::from(T::min_size()) as usize;
-            }
-            assert_eq!(offset, size as usize);
-        }
+        let buffer = create_buffer(label, capacity as u64 * item_size, device);
 
         Self {
-            label,
-            len: 0,
-            cap: capacity,
+            item_size,
             buffer,
-            _phantom: PhantomData,
+            slots: SlotAllocator::new(label, capacity),
         }
     }
 
-    pub fn alloc(&mut self) -> GpuIdx {
-        let res = self.len;
-        self.len += 1;
-        assert!(self.len <= self.cap);
-        res
+    /// See `SlotAllocator::alloc`. The content of a reused slot is whatever was written
+    /// to it last.
+    pub fn alloc(&mut self) -> Option<GpuIdx> {
+        self.slots.alloc()
+    }
+
+    /// Reallocates the buffer for `capacity` items and copies the items over. Bind groups
+    /// referencing the old buffer have to be recreated.
+    pub fn grow(&mut self, capacity: GpuIdx, device: &RenderDevice, encoder: &mut CommandEncoder) {
+        let buffer = create_buffer(self.slots.label, capacity as u64 * self.item_size, device);
+        encoder.copy_buffer_to_buffer(&self.buffer, 0, &buffer, 0, self.size_bytes());
+
+        self.buffer = buffer;
+        self.slots.grow(capacity);
     }
 
     pub fn free(&mut self, idx: GpuIdx) {
-        todo!("Make a freelist")
+        self.slots.free(idx);
+    }
+
+    pub fn is_free(&self, idx: GpuIdx) -> bool {
+        self.slots.is_free(idx)
     }
 
-    pub fn write(&mut self, idx: GpuIdx, data: &T, queue: &RenderQueue) {
-        assert!(idx < self.len);
+    pub fn live_count(&self) -> GpuIdx {
+        self.slots.live_count()
+    }
 
-        let offset = u64::from(T::min_size()) * idx as u64;
-        let size = u64::from(T::min_size());
+    pub fn free_count(&self) -> GpuIdx {
+        self.slots.free_count()
+    }
 
-        let mut buffer_view = queue
-            .write_buffer_with(&self.buffer, offset, T::min_size())
-            .unwrap();
+    pub fn len(&self) -> GpuIdx {
+        self.slots.len()
+    }
 
-        data.write_into(&mut Writer::new(&data, &mut *buffer_view, 0).unwrap());
+    /// `data` is the raw item, `item_size` bytes
+    pub fn write(&mut self, idx: GpuIdx, data: &[u8], queue: &RenderQueue) {
+        assert!(idx < self.len() && !self.is_free(idx));
+        assert_eq!(data.len() as u64, self.item_size);
+
+        queue.write_buffer(&self.buffer, self.item_size * idx as u64, data);
     }
 
     pub fn buffer(&self) -> &Buffer {
@@ -99,11 +87,183 @@ where
         self.buffer.as_entire_binding()
     }
 
+    pub fn item_size(&self) -> u64 {
+        self.item_size
+    }
+
     pub fn size_bytes(&self) -> u64 {
-        self.cap as u64 * u64::from(T::min_size())
+        self.size() as u64 * self.item_size
     }
 
     pub fn size(&self) -> GpuIdx {
+        self.slots.cap()
+    }
+}
+
+/// Slot bookkeeping of `GpuBufferAllocator`, kept apart from the buffer
+pub struct SlotAllocator {
+    label: &'static str,
+    // Slots below `len` have been handed out at least once
+    len: GpuIdx,
+    cap: GpuIdx,
+    free_list: Vec<GpuIdx>,
+    // Bit per slot below `len`, set while the slot is in `free_list`
+    free_mask: Vec<u32>,
+}
+
+impl SlotAllocator {
+    /// `label` names the pool in panic messages
+    pub fn new(label: &'static str, capacity: GpuIdx) -> Self {
+        Self {
+            label,
+            len: 0,
+            cap: capacity,
+            free_list: Vec::new(),
+            free_mask: Vec::new(),
+        }
+    }
+
+    /// The most recently freed slot, or a new one. `None` once the capacity is used up,
+    /// see `grow`.
+    pub fn alloc(&mut self) -> Option<GpuIdx> {
+        if let Some(idx) = self.free_list.pop() {
+            self.set_free(idx, false);
+            return Some(idx);
+        }
+
+        if self.len == self.cap {
+            return None;
+        }
+
+        let res = self.len;
+        self.len += 1;
+        if self.free_mask.len() * 32 < self.len as usize {
+            self.free_mask.push(0);
+        }
+        Some(res)
+    }
+
+    pub fn grow(&mut self, capacity: GpuIdx) {
+        assert!(capacity >= self.cap);
+        self.cap = capacity;
+    }
+
+    /// Panics on slots that were never allocated or are already free
+    pub fn free(&mut self, idx: GpuIdx) {
+        assert!(
+            idx < self.len,
+            "{}: freeing slot {idx} that was never allocated",
+            self.label
+        );
+        assert!(
+            !self.is_free(idx),
+            "{}: double free of slot {idx}",
+            self.label
+        );
+
+        self.set_free(idx, true);
+        self.free_list.push(idx);
+    }
+
+    pub fn is_free(&self, idx: GpuIdx) -> bool {
+        self.free_mask[idx as usize / 32] & (1 << (idx % 32)) != 0
+    }
+
+    fn set_free(&mut self, idx: GpuIdx, is_free: bool) {
+        let word = &mut self.free_mask[idx as usize / 32];
+        if is_free {
+            *word |= 1 << (idx % 32);
+        } else {
+            *word &= !(1 << (idx % 32));
+        }
+    }
+
+    /// Allocated slots that are not free
+    pub fn live_count(&self) -> GpuIdx {
+        self.len - self.free_count()
+    }
+
+    /// Freed slots waiting to be reused
+    pub fn free_count(&self) -> GpuIdx {
+        self.free_list.len() as GpuIdx
+    }
+
+    /// Slots handed out so far, live and free, the rest of the capacity is untouched
+    pub fn len(&self) -> GpuIdx {
+        self.len
+    }
+
+    pub fn cap(&self) -> GpuIdx {
         self.cap
     }
 }
+
+fn create_buffer(label: &'static str, size: u64, device: &RenderDevice) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: Some(label),
+        size,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_slots_are_reused_first() {
+        let mut slots = SlotAllocator::new("test", 40);
+        for idx in 0..34 {
+            assert_eq!(slots.alloc(), Some(idx));
+        }
+
+        slots.free(3);
+        slots.free(33);
+        assert!(slots.is_free(3) && slots.is_free(33));
+        assert_eq!(slots.free_count(), 2);
+        assert_eq!(slots.live_count(), 32);
+
+        assert_eq!(slots.alloc(), Some(33));
+        assert_eq!(slots.alloc(), Some(3));
+        assert_eq!(slots.alloc(), Some(34));
+        assert!(!slots.is_free(3) && !slots.is_free(33));
+        assert_eq!(slots.free_count(), 0);
+        assert_eq!(slots.live_count(), 35);
+        assert_eq!(slots.len(), 35);
+    }
+
+    #[test]
+    fn alloc_stops_at_capacity() {
+        let mut slots = SlotAllocator::new("test", 2);
+        slots.alloc();
+        slots.alloc();
+        assert_eq!(slots.alloc(), None);
+
+        slots.free(0);
+        assert_eq!(slots.alloc(), Some(0));
+        assert_eq!(slots.alloc(), None);
+
+        slots.grow(3);
+        assert_eq!(slots.alloc(), Some(2));
+        assert_eq!(slots.cap(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "double free of slot 1")]
+    fn double_free_panics() {
+        let mut slots = SlotAllocator::new("test", 4);
+        slots.alloc();
+        slots.alloc();
+        slots.free(1);
+        slots.free(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "never allocated")]
+    fn freeing_unallocated_slot_panics() {
+        let mut slots = SlotAllocator::new("test", 4);
+        slots.alloc();
+        slots.free(1);
+    }
+}
@@ -1,26 +1,168 @@
-use std::marker::PhantomData;
+use std::{
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+};
 
 use bevy::{
     prelude::*,
     render::{
-        render_resource::{encase::{internal::{WriteInto, Writer}, StorageBuffer}, Buffer, BufferDescriptor, BufferUsages, ShaderType},
+        render_resource::{
+            encase::{
+                internal::{CreateFrom, Reader, WriteInto},
+                StorageBuffer,
+            },
+            Buffer, BufferDescriptor, BufferUsages, CommandEncoder, MapMode, ShaderType,
+        },
         renderer::{RenderDevice, RenderQueue},
     },
 };
+use crossbeam_channel::{Receiver, Sender};
+
+/// Staging buffers of a `GpuReadback`, reads are dropped while all of them are in flight
+pub const GPU_READBACK_RING_LEN: usize = 3;
+
+const STAGING_FREE: u8 = 0;
+// Copy recorded, waiting for `GpuReadback::map`
+const STAGING_COPIED: u8 = 1;
+// `map_async` issued, the callback frees the buffer
+const STAGING_MAPPING: u8 = 2;
+
+struct StagingBuffer {
+    buffer: Buffer,
+    state: Arc<AtomicU8>,
+}
+
+/// Reads a `T` back from GPU buffers without stalling the frame. `read` records a copy into
+/// a ring of staging buffers, `map` starts mapping them once the copy was submitted and the
+/// values arrive in `try_recv` when the GPU is done with them, usually a few frames later.
+pub struct GpuReadback<T> {
+    label: &'static str,
+    ring: Vec<StagingBuffer>,
+    next: usize,
+    tx: Sender<T>,
+    rx: Receiver<T>,
+}
+
+impl<T> GpuReadback<T>
+where
+    T: ShaderType + CreateFrom + Send + 'static,
+{
+    pub fn new(label: &'static str, device: &RenderDevice) -> Self {
+        let ring = (0..GPU_READBACK_RING_LEN)
+            .map(|_| StagingBuffer {
+                buffer: device.create_buffer(&BufferDescriptor {
+                    label: Some(label),
+                    size: T::min_size().into(),
+                    usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+                    mapped_at_creation: false,
+                }),
+                state: Arc::new(AtomicU8::new(STAGING_FREE)),
+            })
+            .collect();
+
+        let (tx, rx) = crossbeam_channel::unbounded();
+
+        Self {
+            label,
+            ring,
+            next: 0,
+            tx,
+            rx,
+        }
+    }
+
+    /// Records a copy of the start of `src` into a free staging buffer, returns `false` if
+    /// all of them are still in flight
+    pub fn read(&mut self, src: &Buffer, encoder: &mut CommandEncoder) -> bool {
+        for _ in 0..self.ring.len() {
+            let staging = &self.ring[self.next];
+            self.next = (self.next + 1) % self.ring.len();
+
+            if staging
+                .state
+                .compare_exchange(
+                    STAGING_FREE,
+                    STAGING_COPIED,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok()
+            {
+                encoder.copy_buffer_to_buffer(src, 0, &staging.buffer, 0, staging.buffer.size());
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Starts mapping the copies recorded by `read`, call after they were submitted. The
+    /// callbacks run when wgpu polls the device, which happens on every submit.
+    pub fn map(&mut self) {
+        for staging in &self.ring {
+            if staging
+                .state
+                .compare_exchange(
+                    STAGING_COPIED,
+                    STAGING_MAPPING,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_err()
+            {
+                continue;
+            }
+
+            let label = self.label;
+            let buffer = staging.buffer.clone();
+            let state = staging.state.clone();
+            let tx = self.tx.clone();
+
+            staging
+                .buffer
+                .slice(..)
+                .map_async(MapMode::Read, move |res| {
+                    match res {
+                        Ok(()) => {
+                            let value = {
+                                let view = buffer.slice(..).get_mapped_range();
+                                T::create_from(&mut Reader::new::<T>(&*view, 0).unwrap())
+                            };
+                            buffer.unmap();
+                            // The receiver only goes away with the readback
+                            tx.send(value).ok();
+                        }
+                        Err(err) => error!("Failed to map {label}: {err}"),
+                    }
+
+                    state.store(STAGING_FREE, Ordering::Release);
+                });
+        }
+    }
+
+    /// Oldest value read back and not received yet
+    pub fn try_recv(&self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+}
 
 pub struct GpuRwBuffer<T>
 where
-    T: ShaderType + WriteInto,
+    T: ShaderType + WriteInto + CreateFrom + Send + 'static,
 {
     label: &'static str,
     scratch: StorageBuffer<Vec<u8>>,
     buffer: Buffer,
+    readback: GpuReadback<T>,
     _phantom: PhantomData<T>,
 }
 
 impl<T> GpuRwBuffer<T>
 where
-    T: ShaderType + WriteInto,
+    T: ShaderType + WriteInto + CreateFrom + Send + 'static,
 {
     pub fn new(label: &'static str, device: &RenderDevice) -> Self {
         let buffer = device.create_buffer(&BufferDescriptor {
@@ -34,16 +176,32 @@ where
             label,
             scratch: StorageBuffer::new(Vec::new()),
             buffer,
+            readback: GpuReadback::new(label, device),
             _phantom: default(),
         }
     }
-    
+
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+
     pub fn write(&mut self, data: &T, queue: &RenderQueue) {
         self.scratch.write(data);
         queue.write_buffer(&self.buffer, 0, self.scratch.as_ref());
     }
-    
-    pub fn read(&self, queue: &RenderQueue) {
 
+    /// Records a readback of the buffer, see `GpuReadback::read`
+    pub fn read(&mut self, encoder: &mut CommandEncoder) -> bool {
+        self.readback.read(&self.buffer, encoder)
+    }
+
+    /// See `GpuReadback::map`
+    pub fn map(&mut self) {
+        self.readback.map();
+    }
+
+    /// See `GpuReadback::try_recv`
+    pub fn try_recv(&self) -> Option<T> {
+        self.readback.try_recv()
     }
 }
@@ -1,9 +1,20 @@
 use bevy::prelude::*;
 use dot_vox::DotVoxData;
 
-use crate::{math::IMat4, Voxel, VoxelTree};
+use crate::{math::IMat4, Voxel, VoxelError, VoxelTree};
 
+/// Bits 0-1 and 2-3 are the columns of the non-zero entries of the first two rows, they
+/// have to differ and be below 3. Bits 4-6 are the signs of the rows.
+pub fn is_valid_rot(rot: u8) -> bool {
+    let index_nz1 = rot & 0b11;
+    let index_nz2 = (rot >> 2) & 0b11;
+    index_nz1 < 3 && index_nz2 < 3 && index_nz1 != index_nz2
+}
+
+/// Panics on encodings that fail `is_valid_rot`
 pub fn rot_to_mat(rot: u8) -> IMat4 {
+    assert!(is_valid_rot(rot), "Invalid rotation {rot:#09b}");
+
     let mut res = IMat4::ZERO;
 
     let index_nz1 = rot & 0b11;
@@ -22,7 +33,14 @@ pub fn rot_to_mat(rot: u8) -> IMat4 {
     res
 }
 
-pub fn place_vox_model(tree: &mut VoxelTree, vox: &DotVoxData, model_id: u32, tr: &IMat4) {
+/// Voxels outside of the tree are skipped, the first of them is returned as an error
+pub fn place_vox_model(
+    tree: &mut VoxelTree,
+    vox: &DotVoxData,
+    model_id: u32,
+    tr: &IMat4,
+) -> Result<(), VoxelError> {
+    let mut res = Ok(());
     let model = &vox.models[model_id as usize];
 
     let translate = -IVec3::new(
@@ -65,14 +83,21 @@ pub fn place_vox_model(tree: &mut VoxelTree, vox: &DotVoxData, model_id: u32, tr
         //     color: color as u32,
         // };
 
-        tree.set_voxel(
-            pos.xyz(),
-            Voxel::from_color(IVec3::new(color.r as i32, color.g as i32, color.b as i32)),
-        );
+        let voxel = Voxel::from_color(IVec3::new(color.r as i32, color.g as i32, color.b as i32));
+        if let Err(err) = tree.set_voxel(pos.xyz(), voxel) {
+            res = res.and(Err(err));
+        }
     }
+
+    res
 }
 
-pub fn place_vox_scene_node(tree: &mut VoxelTree, vox: &DotVoxData, node_idx: u32, tr: &IMat4) {
+pub fn place_vox_scene_node(
+    tree: &mut VoxelTree,
+    vox: &DotVoxData,
+    node_idx: u32,
+    tr: &IMat4,
+) -> Result<(), VoxelError> {
     let node = &vox.scenes[node_idx as usize];
     match &node {
         dot_vox::SceneNode::Transform {
@@ -101,26 +126,31 @@ pub fn place_vox_scene_node(tree: &mut VoxelTree, vox: &DotVoxData, node_idx: u3
             attributes: _,
             children,
         } => {
+            let mut res = Ok(());
             for child in children {
-                place_vox_scene_node(tree, vox, *child, tr)
+                res = res.and(place_vox_scene_node(tree, vox, *child, tr));
             }
+            res
         }
         dot_vox::SceneNode::Shape {
             attributes: _,
             models,
         } => {
+            let mut res = Ok(());
             for dot_vox::ShapeModel {
                 model_id,
                 attributes: _,
             } in models
             {
-                place_vox_model(tree, vox, *model_id, tr);
+                res = res.and(place_vox_model(tree, vox, *model_id, tr));
             }
+            res
         }
     }
 }
 
-pub fn place_vox(tree: &mut VoxelTree, vox: &DotVoxData, offset: IVec3) {
+/// `offset` is in world coords of the tree, see `VoxelTree::origin`
+pub fn place_vox(tree: &mut VoxelTree, vox: &DotVoxData, offset: IVec3) -> Result<(), VoxelError> {
     let t = IMat4::from_translation(offset);
     let r = IMat4::from_cols(
         IVec4::new(1, 0, 0, 0),
@@ -131,5 +161,5 @@ pub fn place_vox(tree: &mut VoxelTree, vox: &DotVoxData, offset: IVec3) {
 
     let tr = t * r;
 
-    place_vox_scene_node(tree, vox, 0, &tr);
+    place_vox_scene_node(tree, vox, 0, &tr)
 }
@@ -17,11 +17,9 @@ use bevy::{
     pbr::{DefaultOpaqueRendererMethod, DirectionalLightShadowMap},
     prelude::*,
     render::{
-        extract_resource::ExtractResource,
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
         graph::CameraDriverLabel,
-        render_asset::{
-            PrepareAssetError, RenderAsset, RenderAssetPlugin, RenderAssetUsages, RenderAssets,
-        },
+        render_asset::RenderAssetUsages,
         render_graph::{self, RenderGraph, RenderGraphApp, RenderLabel, ViewNodeRunner},
         render_resource::{
             binding_types::{storage_buffer_read_only, texture_storage_2d, uniform_buffer},
@@ -30,7 +28,7 @@ use bevy::{
         renderer::{RenderContext, RenderDevice, RenderQueue},
         settings::{Backends, InstanceFlags, RenderCreation, WgpuSettings},
         texture::ImageSampler,
-        Render, RenderApp, RenderPlugin, RenderSet,
+        ExtractSchedule, Render, RenderApp, RenderPlugin, RenderSet,
     },
     window::WindowPlugin,
 };
@@ -40,7 +38,10 @@ use std::{borrow::Cow, fs};
 use camera::*;
 use import::*;
 use render::*;
+use voxel_io::*;
+use voxel_material::*;
 use voxel_tree::*;
+use voxel_validate::*;
 
 mod camera;
 mod gpu_buffer_allocator;
@@ -48,8 +49,23 @@ mod gpu_rw_buffer;
 mod import;
 mod math;
 mod render;
+mod sdf;
 mod ui;
+mod voxel_collision;
+mod voxel_csg;
+mod voxel_dag;
+mod voxel_edit;
+mod voxel_history;
+mod voxel_io;
+mod voxel_iter;
+mod voxel_lod;
+mod voxel_material;
+mod voxel_mesh;
+mod voxel_nets;
+mod voxel_prefab;
+mod voxel_trace;
 mod voxel_tree;
+mod voxel_validate;
 
 fn main() {
     let mut app = App::new();
@@ -70,11 +86,7 @@ fn main() {
                 .set(RenderPlugin {
                     render_creation: RenderCreation::Automatic(WgpuSettings {
                         instance_flags: InstanceFlags::default().with_env() | InstanceFlags::DEBUG,
-                        // constrained_limits: WgpuLimits {
-                        //     max_storage_buffer_binding_size: u32::MAX, // 4GiB
-                        //     max_buffer_size: (u32::MAX as u64),
-                        //     ..default()
-                        // }.into(),
+                        // The device gets the adapter limits, `VoxelSettings` is fitted to them
                         ..default()
                     }),
                     ..default()
@@ -83,9 +95,11 @@ fn main() {
             ui::GameUiPlugin,
         ))
         .add_plugins(WorldInspectorPlugin::new().run_if(input_toggle_active(false, KeyCode::F1)))
+        .init_resource::<GameCameraWalk>()
         .add_systems(Startup, setup)
         .add_systems(Update, update_game_camera)
-        .add_systems(Update, update_gizmos);
+        .add_systems(Update, pick_voxel.after(update_game_camera))
+        .add_systems(Update, update_gizmos.after(pick_voxel));
 
     // let render_graph = bevy_mod_debugdump::render_graph_dot(&app, &default());
     // fs::write("render_graph.graph", render_graph);
@@ -102,6 +116,7 @@ fn setup(
     mut meshes: ResMut<Assets<Mesh>>,
     mut voxel_trees: ResMut<Assets<VoxelTree>>,
     asset_server: Res<AssetServer>,
+    dims: Res<VoxelWorldDims>,
 ) {
     // TODO: is there better way?
     std::mem::forget(asset_server.load::<Shader>("shaders/common.wgsl"));
@@ -113,14 +128,21 @@ fn setup(
     std::mem::forget(asset_server.load::<Shader>("shaders/draw_procedural.wgsl"));
     std::mem::forget(asset_server.load::<Shader>("shaders/draw_import.wgsl"));
 
-    let mut voxel_tree = VoxelTree::new(VOXEL_TREE_DEPTH as u8);
+    let mut voxel_tree = VoxelTree::new_centered(dims.depth, dims.dim);
     // gen_test_scene(&mut voxel_tree, 4i32.pow(DEPTH as u32), Vec3::new(1., 0.5, 1.));
 
     let model_path = "assets/Church_Of_St_Sophia.vox";
     // let model_path = "assets/monu2.vox";
 
     let vox_model = dot_vox::load(model_path).expect("Failed to load");
-    place_vox(&mut voxel_tree, &vox_model, IVec3::new(2000, 50, 2000));
+    if let Err(err) = place_vox(&mut voxel_tree, &vox_model, IVec3::ZERO) {
+        warn!("{model_path} doesn't fit into the voxel tree: {err}");
+    }
+
+    let stats = voxel_tree.dedup();
+    info!("Deduplicated {model_path}, {stats}");
+    // A new tree is drawn entirely, only the edits after the import are patched
+    voxel_tree.track_dirty = true;
     // place_vox(&mut voxel_tree, &vox_model, IVec3::new(200, 50, 200));
 
     std::mem::forget(voxel_trees.add(voxel_tree));
@@ -178,23 +200,38 @@ struct VoxelTracerLabel;
 impl Plugin for VoxelTracerPlugin {
     fn build(&self, app: &mut App) {
         // app.add_plugins(ExtractResourcePlugin::<VoxelTracer>::default());
-        app.add_plugins(RenderAssetPlugin::<GpuVoxelTree>::default());
         app.add_plugins(FrameTimeDiagnosticsPlugin::default());
         app.add_plugins(EntityCountDiagnosticsPlugin::default());
         app.add_plugins(SystemInformationDiagnosticsPlugin::default());
         app.add_plugins(CameraDiagnosticsPlugin::default());
         app.add_plugins(VoxelWorldDiagnosticsPlugin::default());
+        app.init_resource::<VoxelWorldDims>();
+        app.init_resource::<VoxelValidation>();
+        app.init_resource::<VoxelMaterials>();
+        app.init_resource::<VoxelSettings>();
+        app.add_plugins(ExtractResourcePlugin::<VoxelMaterials>::default());
+        app.add_plugins(ExtractResourcePlugin::<VoxelSettings>::default());
+        app.add_plugins(ExtractResourcePlugin::<VoxelValidation>::default());
+        app.add_systems(Update, request_voxel_validation);
         app.init_asset::<VoxelTree>();
+        app.init_asset_loader::<VoxelTreeLoader>();
         let render_app = app.sub_app_mut(RenderApp);
 
+        render_app.init_resource::<ExtractedVoxelTrees>();
+        render_app.init_resource::<GpuVoxelTrees>();
+        render_app.add_systems(ExtractSchedule, extract_voxel_trees);
         render_app.add_systems(
             Render,
             (
+                prepare_voxel_materials.in_set(RenderSet::PrepareResources),
+                prepare_voxel_pools.in_set(RenderSet::PrepareResources),
+                prepare_voxel_trees.in_set(RenderSet::PrepareResources),
                 prepare_voxel_bind_groups.in_set(RenderSet::PrepareBindGroups),
                 prepare_voxel_view_bind_groups
                     .in_set(RenderSet::PrepareBindGroups)
                     .after(prepare_voxel_bind_groups),
                 render_world_send.after(RenderSet::Render),
+                validate_voxel_gpu_scene.after(RenderSet::Render),
             ),
         );
 
@@ -224,7 +261,18 @@ impl Plugin for VoxelTracerPlugin {
         let (tx, rx) = crossbeam_channel::unbounded();
         app.insert_resource(MainWorldReceiver(rx));
 
+        let mut dims = *app.world().resource::<VoxelWorldDims>();
+        if !dims.is_supported() {
+            error!("Unsupported voxel world dims {dims:?}, falling back to the defaults");
+            dims = default();
+            app.insert_resource(dims);
+        }
+
+        let settings = *app.world().resource::<VoxelSettings>();
+
         let render_app = app.sub_app_mut(RenderApp);
+        render_app.insert_resource(dims);
+        render_app.insert_resource(settings);
         render_app.init_resource::<VoxelGpuScene>();
         render_app.init_resource::<VoxelPipelines>();
         render_app.insert_resource(RenderWorldSender(tx));
@@ -236,6 +284,17 @@ struct LoadingCell {
     pos: IVec3,
 }
 
-pub fn update_gizmos(mut gizmos: Gizmos) {
-    gizmos.cuboid(Transform::from_scale(Vec3::splat(10.)), GREEN);
+/// Outlines the hovered voxel
+pub fn update_gizmos(mut gizmos: Gizmos, hit: Option<Res<VoxelHit>>) {
+    let Some(hit) = hit else {
+        return;
+    };
+
+    let size = hit.voxel_size as f32;
+    let center = hit.voxel_pos.as_vec3() + size * 0.5;
+    // Slightly larger so the outline isn't hidden by the voxel faces
+    gizmos.cuboid(
+        Transform::from_translation(center).with_scale(Vec3::splat(size * 1.02)),
+        GREEN,
+    );
 }
@@ -1,5 +1,11 @@
 use core::num;
-use std::{io::Cursor, marker::PhantomData, num::NonZeroU64, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    io::Cursor,
+    marker::PhantomData,
+    num::NonZeroU64,
+    sync::Arc,
+};
 
 use bevy::{
     core_pipeline::{
@@ -11,7 +17,7 @@ use bevy::{
     diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, DiagnosticsStore, RegisterDiagnostic},
     ecs::{
         query::QueryItem,
-        system::lifetimeless::{Read, SResMut, Write},
+        system::lifetimeless::{Read, Write},
     },
     math::U64Vec3,
     prelude::*,
@@ -26,28 +32,168 @@ use bevy::{
         },
         texture::GpuImage,
         view::{ViewDepthTexture, ViewUniform, ViewUniformOffset, ViewUniforms},
+        Extract,
     },
     utils::info,
 };
 use crossbeam_channel::{Receiver, Sender};
 use encase::internal::{ReadFrom, Reader};
 use gpu_buffer_allocator::{GpuBufferAllocator, GpuIdx};
+use gpu_rw_buffer::GpuReadback;
+use voxel_iter::{TreeWalk, WalkItem};
 
 use crate::*;
 
-const WORKGROUP_SIZE: UVec3 = UVec3::new(8, 8, 8);
+// Length of `VOXEL_SIZES` in `voxel_common.wgsl`, holds sizes for depths `0..=depth`
+const VOXEL_SIZES_LEN: u8 = 16;
+
+/// Depth and brick dimension of the GPU world, every `GpuVoxelTree` drawn into it has to match.
+/// Insert it into the main world before the plugins are finished to change it.
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VoxelWorldDims {
+    pub depth: u8,
+    pub dim: u8,
+    // World position of the GPU world's min corner, trees are drawn relative to it
+    pub origin: IVec3,
+}
+
+impl Default for VoxelWorldDims {
+    /// Centered like `VoxelTree::new_centered`
+    fn default() -> Self {
+        let depth = VOXEL_TREE_DEPTH as u8;
+        let dim = VOXEL_DIM as u8;
+
+        Self {
+            depth,
+            dim,
+            origin: IVec3::splat(-((dim as i32).pow(depth as u32) / 2)),
+        }
+    }
+}
+
+impl VoxelWorldDims {
+    /// Dims the shaders can handle, `VOXEL_SIZES` limits the depth to `VOXEL_SIZES_LEN - 1`
+    pub fn is_supported(&self) -> bool {
+        is_valid_tree_dims(self.depth, self.dim) && self.depth < VOXEL_SIZES_LEN
+    }
+
+    /// Draw shaders process a brick per workgroup
+    pub fn workgroup_size(&self) -> UVec3 {
+        UVec3::splat(self.dim as u32)
+    }
+
+    /// Bytes of `VoxelNode` from `voxel_common.wgsl`
+    pub fn node_size(&self) -> u64 {
+        4 * (1 + mask_len(self.dim) + voxel_count(self.dim)) as u64
+    }
+
+    /// Bytes of `VoxelLeaf` from `voxel_common.wgsl`
+    pub fn leaf_size(&self) -> u64 {
+        4 * (mask_len(self.dim) + voxel_count(self.dim)) as u64
+    }
+
+    /// Voxels along each axis
+    pub fn size(&self) -> u32 {
+        (self.dim as u32).pow(self.depth as u32)
+    }
+}
+
+/// Memory budgets of the GPU world. Insert it into the main world before the plugins are
+/// finished to change it, only `max_pool_bytes` is read again afterwards.
+#[derive(Resource, ExtractResource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VoxelSettings {
+    /// Initial size of the node pool, it grows on demand, see `prepare_voxel_pools`
+    pub nodes_bytes: u64,
+    /// Initial size of the leaf pool
+    pub leafs_bytes: u64,
+    /// Size the pools can grow to, also limited by the device
+    pub max_pool_bytes: u64,
+    /// Size of each free list
+    pub memlist_bytes: u64,
+    /// Workgroups along each axis of a draw batch, the draw areas hold a result per workgroup
+    pub draw_dispatch: u32,
+}
+
+impl Default for VoxelSettings {
+    fn default() -> Self {
+        Self {
+            nodes_bytes: 16 * 1024 * 1024,  // 16MiB
+            leafs_bytes: 128 * 1024 * 1024, // 128MiB
+            max_pool_bytes: u64::MAX,
+            memlist_bytes: 4 * 1024 * 1024, // 4MiB, can address 2GiB of chunks, 2048 bytes each
+            draw_dispatch: 128,             // 16MiB draw areas
+        }
+    }
+}
+
+impl VoxelSettings {
+    /// Largest storage buffer the device can bind
+    pub fn max_binding_bytes(limits: &WgpuLimits) -> u64 {
+        (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size)
+    }
+
+    /// Workgroups of a draw batch
+    pub fn draw_max_dispatch(&self) -> u64 {
+        (self.draw_dispatch as u64).pow(3)
+    }
+
+    /// Bytes of each draw area, a `DrawResult` from `voxel_write.wgsl` per workgroup
+    pub fn draw_area_bytes(&self) -> u64 {
+        self.draw_max_dispatch() * 4 * 2
+    }
+
+    /// Voxels along each axis drawn by a single batch
+    pub fn draw_batch_size(&self, dims: &VoxelWorldDims) -> u32 {
+        self.draw_dispatch * dims.dim as u32
+    }
+
+    /// Copy that fits into `limits`, buffers and draw batches are shrunk on smaller devices
+    pub fn validated(&self, dims: &VoxelWorldDims, limits: &WgpuLimits) -> Self {
+        assert!(
+            self.nodes_bytes >= 2 * dims.node_size() // The first one is reserved for root node
+                && self.leafs_bytes >= dims.leaf_size()
+                && self.max_pool_bytes >= self.nodes_bytes.max(self.leafs_bytes)
+                && self.memlist_bytes >= 4
+                && self.draw_dispatch > 0,
+            "Invalid voxel settings for {dims:?}: {self:?}"
+        );
 
-const DRAW_MAX_DISPATCH: u64 = 128 * 128 * 128;
+        let max_bytes = Self::max_binding_bytes(limits);
+        let mut res = *self;
+
+        res.max_pool_bytes = res.max_pool_bytes.min(max_bytes);
+        res.nodes_bytes = res.nodes_bytes.min(res.max_pool_bytes);
+        res.leafs_bytes = res.leafs_bytes.min(res.max_pool_bytes);
+        res.memlist_bytes = res.memlist_bytes.min(max_bytes);
+        while res.draw_dispatch > 1
+            && (res.draw_area_bytes() > max_bytes
+                || res.draw_dispatch > limits.max_compute_workgroups_per_dimension)
+        {
+            res.draw_dispatch /= 2;
+        }
+
+        if res != *self {
+            warn!("Voxel settings lowered to fit the device; {self:?} -> {res:?}");
+        }
+
+        res
+    }
+}
 
 #[derive(Debug, Clone, Copy, ShaderType, Default)]
 pub struct VoxelGpuSceneInfo {
-    nodes_cap: u32,
-    nodes_len: u32,
-    nodes_free_count: u32,
-
-    leafs_cap: u32,
-    leafs_len: u32,
-    leafs_free_count: u32,
+    pub nodes_cap: u32,
+    pub nodes_len: u32,
+    pub nodes_free_count: u32,
+
+    pub leafs_cap: u32,
+    pub leafs_len: u32,
+    pub leafs_free_count: u32,
+
+    // Allocations that didn't fit since the last clear, their bricks were dropped.
+    // See `prepare_voxel_pools`.
+    pub nodes_overflow: u32,
+    pub leafs_overflow: u32,
 }
 
 #[derive(Resource, Deref)]
@@ -69,6 +215,8 @@ impl Plugin for VoxelWorldDiagnosticsPlugin {
             .add_systems(Update, Self::diagnostic_system);
         app.register_diagnostic(Diagnostic::new(Self::NODES_FREE_COUNT))
             .add_systems(Update, Self::diagnostic_system);
+        app.register_diagnostic(Diagnostic::new(Self::NODES_DROPPED))
+            .add_systems(Update, Self::diagnostic_system);
 
         app.register_diagnostic(Diagnostic::new(Self::LEAFS).with_suffix("%"))
             .add_systems(Update, Self::diagnostic_system);
@@ -78,6 +226,8 @@ impl Plugin for VoxelWorldDiagnosticsPlugin {
             .add_systems(Update, Self::diagnostic_system);
         app.register_diagnostic(Diagnostic::new(Self::LEAFS_FREE_COUNT))
             .add_systems(Update, Self::diagnostic_system);
+        app.register_diagnostic(Diagnostic::new(Self::LEAFS_DROPPED))
+            .add_systems(Update, Self::diagnostic_system);
     }
 }
 
@@ -86,11 +236,14 @@ impl VoxelWorldDiagnosticsPlugin {
     const NODES_CAP: DiagnosticPath = DiagnosticPath::const_new("voxel/nodes_cap");
     const NODES_LEN: DiagnosticPath = DiagnosticPath::const_new("voxel/nodes_len");
     const NODES_FREE_COUNT: DiagnosticPath = DiagnosticPath::const_new("voxel/nodes_free_count");
+    // Bricks that didn't fit into the full pool, `VoxelGpuSceneInfo::nodes_overflow`
+    const NODES_DROPPED: DiagnosticPath = DiagnosticPath::const_new("voxel/nodes_dropped");
 
     const LEAFS: DiagnosticPath = DiagnosticPath::const_new("voxel/leafs");
     const LEAFS_CAP: DiagnosticPath = DiagnosticPath::const_new("voxel/leafs_cap");
     const LEAFS_LEN: DiagnosticPath = DiagnosticPath::const_new("voxel/leafs_len");
     const LEAFS_FREE_COUNT: DiagnosticPath = DiagnosticPath::const_new("voxel/leafs_free_count");
+    const LEAFS_DROPPED: DiagnosticPath = DiagnosticPath::const_new("voxel/leafs_dropped");
 
     pub fn diagnostic_system(mut diagnostics: Diagnostics, receiver: Res<MainWorldReceiver>) {
         while let Ok(data) = receiver.try_recv() {
@@ -103,28 +256,31 @@ impl VoxelWorldDiagnosticsPlugin {
             diagnostics.add_measurement(&Self::NODES_CAP, || data.nodes_cap as f64);
             diagnostics.add_measurement(&Self::NODES_LEN, || data.nodes_len as f64);
             diagnostics.add_measurement(&Self::NODES_FREE_COUNT, || data.nodes_free_count as f64);
+            diagnostics.add_measurement(&Self::NODES_DROPPED, || data.nodes_overflow as f64);
 
             diagnostics.add_measurement(&Self::LEAFS, || leafs_ratio * 100.);
             diagnostics.add_measurement(&Self::LEAFS_CAP, || data.leafs_cap as f64);
             diagnostics.add_measurement(&Self::LEAFS_LEN, || data.leafs_len as f64);
             diagnostics.add_measurement(&Self::LEAFS_FREE_COUNT, || data.leafs_free_count as f64);
+            diagnostics.add_measurement(&Self::LEAFS_DROPPED, || data.leafs_overflow as f64);
         }
     }
 }
 
-#[derive(Reflect, Clone, ShaderType, Debug)]
-pub struct GpuVoxelNode {
-    //pub mask: [u32; VOXEL_MASK_LEN],
-    pub indices: [u32; VOXEL_COUNT],
-}
-
 #[derive(Resource)]
 pub struct VoxelGpuScene {
+    // `VoxelSettings` the buffers were created with
+    pub settings: VoxelSettings,
+
     pub info: StorageBuffer<VoxelGpuSceneInfo>,
-    pub info_copy_dest: Buffer,
+    // Reads `info` back for `render_world_send`
+    pub info_readback: GpuReadback<VoxelGpuSceneInfo>,
+
+    pub nodes: GpuBufferAllocator,
+    pub leafs: GpuBufferAllocator,
 
-    pub nodes: GpuBufferAllocator<VoxelNode>,
-    pub leafs: GpuBufferAllocator<GpuVoxelNode>,
+    // `VoxelMaterials::gpu_table`, rewritten when the resource changes
+    pub materials: StorageBuffer<Vec<VoxelMaterial>>,
 
     pub free_nodes: Buffer,
     pub free_leafs: Buffer,
@@ -134,23 +290,53 @@ pub struct VoxelGpuScene {
     pub bind_group_layout_view: BindGroupLayout,
     pub bind_group_layout_voxel: BindGroupLayout,
     pub bind_group_layout_voxel_import: BindGroupLayout,
+
+    // Bumped every time a `GpuVoxelTree` is uploaded whole or removed, e.g. on hot-reload
+    pub trees_generation: u32,
+    // Bumped every time a `GpuVoxelTree` is patched with edits
+    pub edits_generation: u32,
+    // Bumped every time `nodes` or `leafs` grow, the world is drawn again from scratch
+    pub pools_generation: u32,
+    // Last `info` read back by `render_world_send`
+    pub last_info: VoxelGpuSceneInfo,
+    // `pools_generation` a full pool was reported for, logged once per generation
+    pub overflow_logged: Option<u32>,
 }
 
 impl FromWorld for VoxelGpuScene {
     fn from_world(world: &mut World) -> Self {
         let device = world.resource::<RenderDevice>();
         let queue = world.resource::<RenderQueue>();
+        let dims = *world.resource::<VoxelWorldDims>();
+
+        // Checked by `VoxelTracerPlugin::finish`
+        assert!(
+            dims.is_supported(),
+            "Unsupported voxel world dims: {dims:?}"
+        );
 
-        let bytes_nodes = 256 * 1024 * 1024; // 256MiB
-        let bytes_leafs = 2 * 1024 * 1024 * 1024; // 2GiB
+        let limits = device.limits();
+        let invocations = dims.workgroup_size().element_product();
+        assert!(
+            invocations <= limits.max_compute_invocations_per_workgroup
+                && dims.dim as u32 <= limits.max_compute_workgroup_size_x
+                && dims.dim as u32 <= limits.max_compute_workgroup_size_y
+                && dims.dim as u32 <= limits.max_compute_workgroup_size_z,
+            "A brick of {dims:?} doesn't fit into a workgroup, max invocations: {}",
+            limits.max_compute_invocations_per_workgroup,
+        );
 
-        let bytes_memlist: u64 = 4 * 1024 * 1024; // 4MiB, can address 2GiB of chunks, 2048 bytes each
+        let settings = world.resource::<VoxelSettings>().validated(&dims, &limits);
 
-        let bytes_drawarea: u64 = DRAW_MAX_DISPATCH * 4 * 2;
-        assert_eq!(16 * 1024 * 1024, bytes_drawarea); // 16MiB, max dispatch is 128x128x128
+        // Initial sizes, the pools grow on demand, see `prepare_voxel_pools`
+        let bytes_nodes = settings.nodes_bytes;
+        let bytes_leafs = settings.leafs_bytes;
 
-        let num_nodes = bytes_nodes / std::mem::size_of::<VoxelNode>();
-        let num_leafs = bytes_leafs / std::mem::size_of::<VoxelLeaf>();
+        let bytes_memlist = settings.memlist_bytes;
+        let bytes_drawarea = settings.draw_area_bytes();
+
+        let num_nodes = bytes_nodes / dims.node_size();
+        let num_leafs = bytes_leafs / dims.leaf_size();
 
         info!(
             "Allocating gpu voxel scene; bytes_nodes: {}, bytes_leafs: {}",
@@ -159,29 +345,39 @@ impl FromWorld for VoxelGpuScene {
 
         let nodes = GpuBufferAllocator::new(
             "voxel_nodes_buffer",
+            dims.node_size(),
             num_nodes as GpuIdx,
-            None,
             device,
-            queue,
         );
         let leafs = GpuBufferAllocator::new(
             "voxel_leafs_buffer",
+            dims.leaf_size(),
             num_leafs as GpuIdx,
-            None,
             device,
-            queue,
         );
 
+        let mut materials = StorageBuffer::from(
+            world
+                .get_resource::<VoxelMaterials>()
+                .cloned()
+                .unwrap_or_default()
+                .gpu_table(),
+        );
+        materials.set_label(Some("voxel_materials_buffer"));
+        materials.write_buffer(device, queue);
+
         let nodes_size = nodes.size_bytes().try_into().unwrap();
         let leafs_size = leafs.size_bytes().try_into().unwrap();
 
         let mut info: StorageBuffer<_> = VoxelGpuSceneInfo {
             nodes_len: 1, // The first one is reserved for root node
-            nodes_cap: nodes.size() as u32,
+            nodes_cap: nodes.size(),
             nodes_free_count: 0,
             leafs_len: 0,
-            leafs_cap: leafs.size() as u32,
+            leafs_cap: leafs.size(),
             leafs_free_count: 0,
+            nodes_overflow: 0,
+            leafs_overflow: 0,
         }
         .into();
 
@@ -189,12 +385,7 @@ impl FromWorld for VoxelGpuScene {
 
         info.write_buffer(device, queue);
 
-        let mut info_copy_dest = device.create_buffer(&BufferDescriptor {
-            label: Some("voxel_info_copy_dest_buffer"),
-            size: VoxelGpuSceneInfo::SHADER_SIZE.into(),
-            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
-            mapped_at_creation: false,
-        });
+        let info_readback = GpuReadback::new("voxel_info_readback_buffer", device);
 
         let free_nodes = device.create_buffer(&BufferDescriptor {
             label: Some("voxel_free_nodes_buffer"),
@@ -226,9 +417,10 @@ impl FromWorld for VoxelGpuScene {
 
         Self {
             info,
-            info_copy_dest,
+            info_readback,
             nodes,
             leafs,
+            materials,
             free_nodes,
             free_leafs,
             draw_area_0,
@@ -237,7 +429,10 @@ impl FromWorld for VoxelGpuScene {
                 "voxel_view_bind_group_layout",
                 &BindGroupLayoutEntries::sequential(
                     ShaderStages::FRAGMENT,
-                    (uniform_buffer::<ViewUniform>(true),),
+                    (
+                        uniform_buffer::<ViewUniform>(true),
+                        storage_buffer_read_only::<Vec<VoxelMaterial>>(false),
+                    ),
                 ),
             ),
             bind_group_layout_voxel: device.create_bind_group_layout(
@@ -265,6 +460,12 @@ impl FromWorld for VoxelGpuScene {
                     ),
                 ),
             ),
+            trees_generation: 0,
+            edits_generation: 0,
+            pools_generation: 0,
+            last_info: default(),
+            overflow_logged: None,
+            settings,
         }
     }
 }
@@ -281,6 +482,7 @@ pub struct VoxelPipelines {
 impl FromWorld for VoxelPipelines {
     fn from_world(world: &mut World) -> Self {
         let gpu_scene = world.resource::<VoxelGpuScene>();
+        let dims = *world.resource::<VoxelWorldDims>();
         let shader_prepass = world.load_asset("shaders/voxel_prepass.wgsl");
         let shader_draw = world.load_asset("shaders/draw.wgsl");
         let shader_draw_procedural = world.load_asset("shaders/draw_procedural.wgsl");
@@ -292,18 +494,21 @@ impl FromWorld for VoxelPipelines {
 
         let pipeline_cache = world.resource_mut::<PipelineCache>();
         let shader_defs = vec![
-            ShaderDefVal::Int("VOXEL_DIM".into(), VOXEL_DIM as i32),
-            ShaderDefVal::Int("VOXEL_TREE_DEPTH".into(), VOXEL_TREE_DEPTH as i32),
+            ShaderDefVal::Int("VOXEL_DIM".into(), dims.dim as i32),
+            ShaderDefVal::Int("VOXEL_TREE_DEPTH".into(), dims.depth as i32),
             ShaderDefVal::UInt("VOXEL_IDX_EMPTY".into(), VOXEL_IDX_EMPTY as u32),
-            // ShaderDefVal::Int("VOXEL_MASK_LEN".into(), VOXEL_MASK_LEN as i32),
+            ShaderDefVal::Int("VOXEL_MASK_LEN".into(), mask_len(dims.dim) as i32),
+            ShaderDefVal::Int("VOXEL_WORLD_ORIGIN_X".into(), dims.origin.x),
+            ShaderDefVal::Int("VOXEL_WORLD_ORIGIN_Y".into(), dims.origin.y),
+            ShaderDefVal::Int("VOXEL_WORLD_ORIGIN_Z".into(), dims.origin.z),
         ];
 
         let shader_defs_compute = [
             shader_defs.as_slice(),
             &[
-                ShaderDefVal::UInt("WG_X".into(), WORKGROUP_SIZE.x),
-                ShaderDefVal::UInt("WG_Y".into(), WORKGROUP_SIZE.y),
-                ShaderDefVal::UInt("WG_Z".into(), WORKGROUP_SIZE.z),
+                ShaderDefVal::UInt("WG_X".into(), dims.workgroup_size().x),
+                ShaderDefVal::UInt("WG_Y".into(), dims.workgroup_size().y),
+                ShaderDefVal::UInt("WG_Z".into(), dims.workgroup_size().z),
             ],
         ]
         .concat();
@@ -365,7 +570,7 @@ impl FromWorld for VoxelPipelines {
                 layout: vec![voxel_layout.clone()],
                 push_constant_ranges: vec![PushConstantRange {
                     stages: ShaderStages::COMPUTE,
-                    range: 0..(4 * 4 * 6), // min: vec3i, max: vec3i, world_min: vec3i, world_max: vec3i, wsize_children: vec3i, depth: u32
+                    range: 0..(4 * 4 * 7), // min: vec3i, max: vec3i, world_min: vec3i, world_max: vec3i, wsize_children: vec3i, depth: u32, import_offset: vec3i
                 }],
                 shader: shader_draw_procedural.clone(),
                 shader_defs: shader_defs_compute.clone(),
@@ -376,7 +581,7 @@ impl FromWorld for VoxelPipelines {
                 layout: vec![voxel_layout.clone()],
                 push_constant_ranges: vec![PushConstantRange {
                     stages: ShaderStages::COMPUTE,
-                    range: 0..(4 * 4 * 6), // min: vec3i, max: vec3i, world_min: vec3i, world_max: vec3i, wsize_children: vec3i, depth: u32
+                    range: 0..(4 * 4 * 7), // min: vec3i, max: vec3i, world_min: vec3i, world_max: vec3i, wsize_children: vec3i, depth: u32, import_offset: vec3i
                 }],
                 shader: shader_draw.clone(),
                 shader_defs: shader_defs_compute.clone(),
@@ -387,7 +592,7 @@ impl FromWorld for VoxelPipelines {
                 layout: vec![voxel_layout.clone(), voxel_import_layout.clone()],
                 push_constant_ranges: vec![PushConstantRange {
                     stages: ShaderStages::COMPUTE,
-                    range: 0..(4 * 4 * 6), // min: vec3i, max: vec3i, world_min: vec3i, world_max: vec3i, wsize_children: vec3i, depth: u32
+                    range: 0..(4 * 4 * 7), // min: vec3i, max: vec3i, world_min: vec3i, world_max: vec3i, wsize_children: vec3i, depth: u32, import_offset: vec3i
                 }],
                 shader: shader_draw_import.clone(),
                 shader_defs: shader_defs_compute.clone(),
@@ -491,6 +696,10 @@ pub fn prepare_voxel_view_bind_groups(
         return;
     };
 
+    let Some(materials) = gpu_scene.materials.binding() else {
+        return;
+    };
+
     let nodes_binding = BufferBinding {
         buffer: gpu_scene.nodes.buffer(),
         offset: 0,
@@ -507,7 +716,7 @@ pub fn prepare_voxel_view_bind_groups(
         let view_bind_group = device.create_bind_group(
             "voxel_view_bind_group",
             &gpu_scene.bind_group_layout_view,
-            &BindGroupEntries::sequential((view_uniforms.clone(),)),
+            &BindGroupEntries::sequential((view_uniforms.clone(), materials.clone())),
         );
 
         commands
@@ -516,33 +725,276 @@ pub fn prepare_voxel_view_bind_groups(
     }
 }
 
-pub fn render_world_send(
+/// Grows `nodes` and `leafs` when the last draw overflowed them or used more than 3/4,
+/// doubling the capacity up to `VoxelSettings::max_pool_bytes` and the device limits. Bind
+/// groups are recreated every frame and the import node redraws the world once
+/// `pools_generation` changes.
+pub fn prepare_voxel_pools(
     device: Res<RenderDevice>,
-    voxel_scene: Res<VoxelGpuScene>,
-    sender: Res<RenderWorldSender>,
+    queue: Res<RenderQueue>,
+    settings: Res<VoxelSettings>,
+    mut gpu_scene: ResMut<VoxelGpuScene>,
 ) {
-    let buffer_slice = voxel_scene.info_copy_dest.slice(..);
+    let last = gpu_scene.last_info;
+    let info = *gpu_scene.info.get();
+
+    // Readbacks lag a few frames behind, skip those that predate the last growth
+    if (last.nodes_cap, last.leafs_cap) != (info.nodes_cap, info.leafs_cap) {
+        return;
+    }
 
-    let (tx, rx) = crossbeam_channel::unbounded::<()>();
+    let needed = |len: u32, cap: u32, overflow: u32| {
+        (overflow != 0 || len > cap / 4 * 3).then(|| len.max(cap).saturating_mul(2))
+    };
+    let nodes_needed = needed(last.nodes_len, last.nodes_cap, last.nodes_overflow);
+    let leafs_needed = needed(last.leafs_len, last.leafs_cap, last.leafs_overflow);
+    if nodes_needed.is_none() && leafs_needed.is_none() {
+        return;
+    }
+
+    let max_bytes = VoxelSettings::max_binding_bytes(&device.limits()).min(settings.max_pool_bytes);
 
-    buffer_slice.map_async(MapMode::Read, move |r| match r {
-        Ok(_) => tx.send(()).unwrap(),
-        Err(err) => panic!("Failed to map buffer: {err}"),
+    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("voxel_pools_grow"),
     });
 
-    device.poll(Maintain::wait()).panic_on_timeout();
+    let gpu_scene = &mut *gpu_scene;
+    let generation = gpu_scene.pools_generation;
+    let mut has_grown = false;
+    for (pool, needed, overflow) in [
+        (&mut gpu_scene.nodes, nodes_needed, last.nodes_overflow),
+        (&mut gpu_scene.leafs, leafs_needed, last.leafs_overflow),
+    ] {
+        let Some(needed) = needed else {
+            continue;
+        };
+
+        let max_cap = (max_bytes / pool.item_size()).min(GpuIdx::MAX as u64) as GpuIdx;
+        let cap = needed.min(max_cap);
+        if cap <= pool.size() {
+            // The overflow stays until the pools grow, don't repeat it every readback
+            if overflow != 0 && gpu_scene.overflow_logged != Some(generation) {
+                error!(
+                    "Voxel pool of {} items is full and can't grow, {} bricks dropped, max bytes: {}",
+                    pool.size(),
+                    overflow,
+                    max_bytes
+                );
+                gpu_scene.overflow_logged = Some(generation);
+            }
+            continue;
+        }
+
+        info!("Growing voxel pool; cap: {} -> {}", pool.size(), cap);
+        pool.grow(cap, &device, &mut encoder);
+        has_grown = true;
+    }
+
+    if !has_grown {
+        return;
+    }
+
+    queue.submit([encoder.finish()]);
 
+    let nodes_cap = gpu_scene.nodes.size();
+    let leafs_cap = gpu_scene.leafs.size();
+    let info = gpu_scene.info.get_mut();
+    info.nodes_cap = nodes_cap;
+    info.leafs_cap = leafs_cap;
+    gpu_scene.info.write_buffer(&device, &queue);
+
+    gpu_scene.last_info = default();
+    gpu_scene.pools_generation = gpu_scene.pools_generation.wrapping_add(1);
+}
+
+pub fn prepare_voxel_materials(
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    materials: Res<VoxelMaterials>,
+    mut gpu_scene: ResMut<VoxelGpuScene>,
+) {
+    if !materials.is_changed() {
+        return;
+    }
+
+    gpu_scene.materials.set(materials.gpu_table());
+    gpu_scene.materials.write_buffer(&device, &queue);
+}
+
+/// Reads `info` back without waiting for the GPU and forwards it to the main world,
+/// values lag a few frames behind
+pub fn render_world_send(
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    mut voxel_scene: ResMut<VoxelGpuScene>,
+    sender: Res<RenderWorldSender>,
+) {
+    let voxel_scene = &mut *voxel_scene;
+
+    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("voxel_info_readback"),
+    });
+    if voxel_scene
+        .info_readback
+        .read(voxel_scene.info.buffer().unwrap(), &mut encoder)
     {
-        let buffer_view = buffer_slice.get_mapped_range();
-        let mut data = VoxelGpuSceneInfo { ..default() };
-        data.read_from(&mut Reader::new::<VoxelGpuSceneInfo>(&*buffer_view, 0).unwrap());
+        queue.submit([encoder.finish()]);
+        voxel_scene.info_readback.map();
+    }
+
+    // Runs the callbacks of finished readbacks
+    device.poll(Maintain::Poll);
+
+    while let Some(info) = voxel_scene.info_readback.try_recv() {
+        voxel_scene.last_info = info;
 
-        if let Err(err) = sender.send(data) {
+        if let Err(err) = sender.send(info) {
             error!("Failed to send data to the main world: {err}");
         }
     }
+}
+
+/// Bump `generation` to validate the CPU trees and a readback of the GPU scene,
+/// see `VoxelTree::validate`
+#[derive(Resource, ExtractResource, Clone, Copy, Default, Debug)]
+pub struct VoxelValidation {
+    pub generation: u32,
+}
+
+pub fn request_voxel_validation(
+    keys: Res<ButtonInput<KeyCode>>,
+    voxel_trees: Res<Assets<VoxelTree>>,
+    mut validation: ResMut<VoxelValidation>,
+) {
+    if !keys.just_pressed(KeyCode::F3) {
+        return;
+    }
 
-    voxel_scene.info_copy_dest.unmap();
+    for (id, tree) in voxel_trees.iter() {
+        log_validation(&format!("voxel tree {id}"), &tree.validate());
+    }
+
+    validation.generation += 1;
+}
+
+pub fn validate_voxel_gpu_scene(
+    validation: Res<VoxelValidation>,
+    mut last_generation: Local<u32>,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    voxel_scene: Res<VoxelGpuScene>,
+    dims: Res<VoxelWorldDims>,
+) {
+    if validation.generation == *last_generation {
+        return;
+    }
+    *last_generation = validation.generation;
+
+    let tree = voxel_scene.read_tree(&device, &queue, *dims);
+    let (res, orphans) = tree.validate_gpu();
+    if orphans > 0 {
+        info!("gpu voxel scene has {orphans} dropped slots");
+    }
+    log_validation("gpu voxel scene", &res);
+}
+
+impl VoxelGpuScene {
+    /// Blocking readback of the allocated part of `nodes`, `leafs` and the free lists,
+    /// meant for debugging only
+    pub fn read_tree(
+        &self,
+        device: &RenderDevice,
+        queue: &RenderQueue,
+        dims: VoxelWorldDims,
+    ) -> VoxelTree {
+        let bytes = read_buffer(
+            device,
+            queue,
+            self.info.buffer().unwrap(),
+            VoxelGpuSceneInfo::SHADER_SIZE.get(),
+        );
+        let mut info = VoxelGpuSceneInfo::default();
+        info.read_from(&mut Reader::new::<VoxelGpuSceneInfo>(&bytes, 0).unwrap());
+
+        let nodes_len = info.nodes_len.min(info.nodes_cap) as u64;
+        let leafs_len = info.leafs_len.min(info.leafs_cap) as u64;
+
+        let nodes = read_buffer(
+            device,
+            queue,
+            self.nodes.buffer(),
+            nodes_len * dims.node_size(),
+        );
+        let leafs = read_buffer(
+            device,
+            queue,
+            self.leafs.buffer(),
+            leafs_len * dims.leaf_size(),
+        );
+        let free_nodes = read_buffer(
+            device,
+            queue,
+            &self.free_nodes,
+            4 * info.nodes_free_count.min(info.nodes_cap) as u64,
+        );
+        let free_leafs = read_buffer(
+            device,
+            queue,
+            &self.free_leafs,
+            4 * info.leafs_free_count.min(info.leafs_cap) as u64,
+        );
+
+        let mut tree = VoxelTree::from_gpu_words(
+            dims.depth,
+            dims.dim,
+            &to_words(&nodes),
+            &to_words(&leafs),
+            &to_words(&free_nodes),
+            &to_words(&free_leafs),
+        );
+        tree.origin = dims.origin;
+        tree
+    }
+}
+
+/// Copies `size` bytes from the start of `src` and waits for them
+fn read_buffer(device: &RenderDevice, queue: &RenderQueue, src: &Buffer, size: u64) -> Vec<u8> {
+    if size == 0 {
+        return Vec::new();
+    }
+
+    let staging = device.create_buffer(&BufferDescriptor {
+        label: Some("voxel_readback_buffer"),
+        size,
+        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("voxel_readback"),
+    });
+    encoder.copy_buffer_to_buffer(src, 0, &staging, 0, size);
+    queue.submit([encoder.finish()]);
+
+    let buffer_slice = staging.slice(..);
+    buffer_slice.map_async(MapMode::Read, |r| {
+        if let Err(err) = r {
+            panic!("Failed to map buffer: {err}");
+        }
+    });
+
+    device.poll(Maintain::wait()).panic_on_timeout();
+
+    let data = buffer_slice.get_mapped_range().to_vec();
+    staging.unmap();
+    data
+}
+
+fn to_words(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .collect()
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
@@ -631,63 +1083,409 @@ impl ViewNode for VoxelWorldPepassNode {
     }
 }
 
-pub struct GpuVoxelTree {
-    pub nodes: BufferVec<VoxelNode>,
-    pub leafs: BufferVec<VoxelLeaf>,
-    pub bind_group: BindGroup,
-    pub world_min: UVec3,
-    pub world_max: UVec3,
+/// Bricks of a main world `VoxelTree` copied by `extract_voxel_trees`
+pub struct ExtractedVoxelTree {
+    pub depth: u8,
+    pub dim: u8,
+    pub origin: IVec3,
+    // The whole tree, otherwise the bricks in the cells edited since the last extraction
+    // and their ancestors
+    pub is_full: bool,
+    pub nodes_len: u32,
+    pub leafs_len: u32,
+    // Slots in the tree and their words, laid out as `ImportNode` and `ImportLeaf` from
+    // `draw_import.wgsl`
+    pub node_slots: Vec<u32>,
+    pub node_words: Vec<u32>,
+    pub leaf_slots: Vec<u32>,
+    pub leaf_words: Vec<u32>,
+    // `VoxelTree::calc_bbox`, only computed for whole trees
+    pub bbox: Option<(UVec3, UVec3)>,
+    pub edit_generation: u64,
+    // `VoxelTree::dirty` newer than the last extraction
+    pub dirty: Vec<(IVec3, i32, u64)>,
 }
 
-impl RenderAsset for GpuVoxelTree {
-    type SourceAsset = VoxelTree;
-    type Param = (
-        SRes<RenderDevice>,
-        SRes<RenderQueue>,
-        SResMut<VoxelGpuScene>,
-    );
+impl ExtractedVoxelTree {
+    /// The whole tree, or with `since` only the bricks under the cells edited after it
+    pub fn new(tree: &VoxelTree, since: Option<u64>) -> Self {
+        let mut res = Self {
+            depth: tree.depth,
+            dim: tree.dim,
+            origin: tree.origin,
+            is_full: since.is_none(),
+            nodes_len: tree.nodes.len() as u32,
+            leafs_len: tree.leafs.len() as u32,
+            node_slots: Vec::new(),
+            node_words: Vec::new(),
+            leaf_slots: Vec::new(),
+            leaf_words: Vec::new(),
+            bbox: None,
+            edit_generation: tree.edit_generation,
+            dirty: Vec::new(),
+        };
 
-    fn asset_usage(_source_asset: &Self::SourceAsset) -> RenderAssetUsages {
-        RenderAssetUsages::RENDER_WORLD
-    }
+        let Some(since) = since else {
+            for idx in 0..res.nodes_len {
+                res.push_node(tree, idx);
+            }
+            for idx in 0..res.leafs_len {
+                res.push_leaf(tree, idx);
+            }
+            res.bbox = tree.calc_bbox();
+            return res;
+        };
 
-    fn prepare_asset(
-        source_asset: Self::SourceAsset,
-        (device, queue, gpu_scene): &mut SystemParamItem<Self::Param>,
-    ) -> Result<Self, PrepareAssetError<Self::SourceAsset>> {
-        let mut nodes = BufferVec::new(BufferUsages::STORAGE);
-        let mut leafs = BufferVec::new(BufferUsages::STORAGE);
+        res.dirty = tree
+            .dirty
+            .iter()
+            .filter(|(_, &generation)| generation > since)
+            .map(|(&(min, size), &generation)| (min, size, generation))
+            .collect();
+
+        // The root is an ancestor of every cell
+        let mut nodes = BTreeSet::from([0]);
+        let mut leafs = BTreeSet::new();
+        for &(min, size, _) in &res.dirty {
+            let min = tree.origin + min;
+            for item in TreeWalk::new(tree, min, min + size) {
+                match item {
+                    WalkItem::Node { idx, .. } => {
+                        nodes.insert(idx);
+                    }
+                    WalkItem::Leaf { idx, .. } => {
+                        leafs.insert(idx);
+                    }
+                    WalkItem::Collapsed { .. } => {}
+                }
+            }
+        }
 
-        let bbox = source_asset.calc_bbox().unwrap();
+        // LODs of the ancestors
+        leafs.extend(
+            nodes
+                .iter()
+                .map(|&idx| tree.nodes[idx as usize].leaf)
+                .filter(|&idx| idx != VOXEL_IDX_EMPTY),
+        );
 
-        for node in &source_asset.nodes {
-            nodes.push(node.clone());
+        for idx in nodes {
+            res.push_node(tree, idx);
+        }
+        for idx in leafs {
+            res.push_leaf(tree, idx);
         }
 
-        for leaf in &source_asset.leafs {
-            leafs.push(leaf.clone());
+        res
+    }
+
+    fn push_node(&mut self, tree: &VoxelTree, idx: u32) {
+        let node = &tree.nodes[idx as usize];
+        self.node_slots.push(idx);
+        self.node_words.push(node.leaf);
+        self.node_words.extend(&node.mask);
+        self.node_words.extend(&node.indices);
+    }
+
+    fn push_leaf(&mut self, tree: &VoxelTree, idx: u32) {
+        let leaf = &tree.leafs[idx as usize];
+        self.leaf_slots.push(idx);
+        self.leaf_words.extend(&leaf.mask);
+        self.leaf_words
+            .extend(leaf.voxels.iter().map(|voxel| voxel.data));
+    }
+}
+
+/// Trees extracted this frame, consumed by `prepare_voxel_trees`
+#[derive(Resource, Default)]
+pub struct ExtractedVoxelTrees {
+    pub extracted: Vec<(AssetId<VoxelTree>, ExtractedVoxelTree)>,
+    pub removed: Vec<AssetId<VoxelTree>>,
+}
+
+/// Added and reloaded trees are copied whole. Trees modified only through the tracked
+/// API since their last extraction send the bricks under their new dirty cells instead,
+/// see `VoxelTree::dirty`.
+pub fn extract_voxel_trees(
+    mut events: Extract<EventReader<AssetEvent<VoxelTree>>>,
+    voxel_trees: Extract<Res<Assets<VoxelTree>>>,
+    // `VoxelTree::edit_generation` of every tree at its last extraction
+    mut extracted_generations: Local<HashMap<AssetId<VoxelTree>, u64>>,
+    mut extracted: ResMut<ExtractedVoxelTrees>,
+) {
+    let mut changed = HashSet::new();
+    for event in events.read() {
+        match event {
+            AssetEvent::Added { id } | AssetEvent::Modified { id } => {
+                changed.insert(*id);
+            }
+            AssetEvent::Unused { id } => {
+                changed.remove(id);
+                extracted_generations.remove(id);
+                extracted.removed.push(*id);
+            }
+            _ => {}
         }
+    }
 
-        nodes.write_buffer(device, queue);
-        leafs.write_buffer(device, queue);
+    for id in changed {
+        let Some(tree) = voxel_trees.get(id) else {
+            continue;
+        };
+
+        let since = extracted_generations
+            .get(&id)
+            .copied()
+            .filter(|&generation| tree.edit_generation > generation);
+        extracted
+            .extracted
+            .push((id, ExtractedVoxelTree::new(tree, since)));
+
+        extracted_generations.insert(id, tree.edit_generation);
+        tree.extracted_generation.set(tree.edit_generation);
+    }
+}
 
-        let nodes_binding = nodes.buffer().unwrap().as_entire_buffer_binding();
-        let leafs_binding = leafs.buffer().unwrap().as_entire_buffer_binding();
+/// Import buffers of every extracted tree, drawn into the GPU world by `VoxelDrawImportNode`
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct GpuVoxelTrees(pub HashMap<AssetId<VoxelTree>, GpuVoxelTree>);
 
-        let bind_group = device.create_bind_group(
-            "voxel_import_bind_group",
-            &gpu_scene.bind_group_layout_voxel_import,
-            &BindGroupEntries::sequential((nodes.binding().unwrap(), leafs.binding().unwrap())),
+pub struct GpuVoxelTree {
+    // `ImportNode` and `ImportLeaf` from `draw_import.wgsl` at the slots of the source
+    // tree. Kept across edits, which only write the bricks they touched.
+    pub nodes: GpuBufferAllocator,
+    pub leafs: GpuBufferAllocator,
+    pub bind_group: BindGroup,
+    pub depth: u8,
+    pub dim: u8,
+    // `VoxelTree::origin`, `bbox` is relative to it
+    pub origin: IVec3,
+    // Bounds of the voxels, `None` for an empty tree. Edits only grow it.
+    pub bbox: Option<(UVec3, UVec3)>,
+    // `VoxelGpuScene::trees_generation` after this tree was uploaded whole
+    pub prepare_generation: u32,
+    // `VoxelTree::edit_generation` of the last upload and the cells edited since the last
+    // sync of `VoxelDrawImportNode`
+    pub edit_generation: u64,
+    pub dirty: Vec<(IVec3, i32, u64)>,
+}
+
+impl GpuVoxelTree {
+    pub fn new(
+        tree: ExtractedVoxelTree,
+        prepare_generation: u32,
+        device: &RenderDevice,
+        queue: &RenderQueue,
+        layout: &BindGroupLayout,
+    ) -> Self {
+        assert!(tree.is_full);
+
+        let mut nodes = GpuBufferAllocator::new(
+            "voxel_import_nodes_buffer",
+            4 * (1 + mask_len(tree.dim) + voxel_count(tree.dim)) as u64,
+            tree.nodes_len.max(1).next_power_of_two(),
+            device,
+        );
+        let mut leafs = GpuBufferAllocator::new(
+            "voxel_import_leafs_buffer",
+            4 * (mask_len(tree.dim) + voxel_count(tree.dim)) as u64,
+            tree.leafs_len.max(1).next_power_of_two(),
+            device,
         );
 
-        info!("VoxelTree extracted");
-        Ok(Self {
+        // Slots are in order, a single write each
+        reserve_import_slots(&mut nodes, tree.nodes_len, device, queue);
+        reserve_import_slots(&mut leafs, tree.leafs_len, device, queue);
+        queue.write_buffer(nodes.buffer(), 0, &to_bytes(&tree.node_words));
+        queue.write_buffer(leafs.buffer(), 0, &to_bytes(&tree.leaf_words));
+
+        let bind_group = create_import_bind_group(&nodes, &leafs, device, layout);
+
+        Self {
             nodes,
             leafs,
             bind_group,
-            world_min: bbox.0,
-            world_max: bbox.1,
-        })
+            depth: tree.depth,
+            dim: tree.dim,
+            origin: tree.origin,
+            bbox: tree.bbox,
+            prepare_generation,
+            edit_generation: tree.edit_generation,
+            dirty: Vec::new(),
+        }
+    }
+
+    /// Writes the bricks of a partial extraction over their slots
+    pub fn patch(
+        &mut self,
+        tree: ExtractedVoxelTree,
+        device: &RenderDevice,
+        queue: &RenderQueue,
+        layout: &BindGroupLayout,
+    ) {
+        assert!(!tree.is_full);
+
+        let nodes_grown = reserve_import_slots(&mut self.nodes, tree.nodes_len, device, queue);
+        let leafs_grown = reserve_import_slots(&mut self.leafs, tree.leafs_len, device, queue);
+        if nodes_grown || leafs_grown {
+            self.bind_group = create_import_bind_group(&self.nodes, &self.leafs, device, layout);
+        }
+
+        let node_stride = self.nodes.item_size() as usize / 4;
+        for (&idx, words) in tree
+            .node_slots
+            .iter()
+            .zip(tree.node_words.chunks_exact(node_stride))
+        {
+            self.nodes.write(idx, &to_bytes(words), queue);
+        }
+
+        let leaf_stride = self.leafs.item_size() as usize / 4;
+        for (&idx, words) in tree
+            .leaf_slots
+            .iter()
+            .zip(tree.leaf_words.chunks_exact(leaf_stride))
+        {
+            self.leafs.write(idx, &to_bytes(words), queue);
+        }
+
+        // Voxels may have been added anywhere in the cells
+        for &(min, size, _) in &tree.dirty {
+            let min = min.as_uvec3();
+            let max = min + size as u32;
+            self.bbox = Some(match self.bbox {
+                Some((bbox_min, bbox_max)) => (bbox_min.min(min), bbox_max.max(max)),
+                None => (min, max),
+            });
+        }
+
+        self.edit_generation = tree.edit_generation;
+        self.dirty.extend(tree.dirty);
+    }
+
+    /// Regions in local coords edited after `since`. Small cells in the same batch of
+    /// `batch_size` are merged into their bounding box, so a stroke of edits costs a
+    /// single draw.
+    pub fn dirty_regions(&self, since: u64, batch_size: u32) -> Vec<(UVec3, UVec3)> {
+        let mut res = Vec::new();
+        let mut batches: HashMap<UVec3, (UVec3, UVec3)> = HashMap::new();
+
+        for &(min, size, generation) in &self.dirty {
+            if generation <= since {
+                continue;
+            }
+
+            let min = min.as_uvec3();
+            let max = min + size as u32;
+            if size as u32 >= batch_size {
+                res.push((min, max));
+                continue;
+            }
+
+            batches
+                .entry(min / batch_size)
+                .and_modify(|(bmin, bmax)| {
+                    *bmin = bmin.min(min);
+                    *bmax = bmax.max(max);
+                })
+                .or_insert((min, max));
+        }
+
+        res.extend(batches.into_values());
+        res
+    }
+}
+
+/// Hands out the slots below `len`, growing the buffer to the next power of two.
+/// Returns whether it grew, its bind group has to be recreated then.
+fn reserve_import_slots(
+    pool: &mut GpuBufferAllocator,
+    len: u32,
+    device: &RenderDevice,
+    queue: &RenderQueue,
+) -> bool {
+    let has_grown = len > pool.size();
+    if has_grown {
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("voxel_import_grow"),
+        });
+        pool.grow(len.next_power_of_two(), device, &mut encoder);
+        // Writes queued after this land in the new buffer
+        queue.submit([encoder.finish()]);
+    }
+
+    while pool.len() < len {
+        pool.alloc();
+    }
+
+    has_grown
+}
+
+fn create_import_bind_group(
+    nodes: &GpuBufferAllocator,
+    leafs: &GpuBufferAllocator,
+    device: &RenderDevice,
+    layout: &BindGroupLayout,
+) -> BindGroup {
+    device.create_bind_group(
+        "voxel_import_bind_group",
+        layout,
+        &BindGroupEntries::sequential((nodes.binding(), leafs.binding())),
+    )
+}
+
+fn to_bytes(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+/// Uploads the extracted trees. Whole trees get new import buffers and bump
+/// `trees_generation`, partial ones are patched in place and bump `edits_generation`.
+pub fn prepare_voxel_trees(
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    mut extracted: ResMut<ExtractedVoxelTrees>,
+    mut gpu_trees: ResMut<GpuVoxelTrees>,
+    mut gpu_scene: ResMut<VoxelGpuScene>,
+) {
+    let ExtractedVoxelTrees { extracted, removed } = std::mem::take(&mut *extracted);
+
+    for id in removed {
+        if gpu_trees.remove(&id).is_some() {
+            gpu_scene.trees_generation = gpu_scene.trees_generation.wrapping_add(1);
+        }
+    }
+
+    let layout = &gpu_scene.bind_group_layout_voxel_import;
+    let mut trees_changed = false;
+    let mut edits_changed = false;
+
+    for (id, tree) in extracted {
+        match (gpu_trees.get_mut(&id), tree.is_full) {
+            (Some(gpu_tree), false) => {
+                gpu_tree.patch(tree, &device, &queue, layout);
+                edits_changed = true;
+            }
+            (None, false) => {
+                warn!(
+                    "Skipping edits of voxel tree {:?} that was never uploaded",
+                    id
+                );
+            }
+            (_, true) => {
+                let generation = gpu_scene.trees_generation.wrapping_add(1);
+                let gpu_tree = GpuVoxelTree::new(tree, generation, &device, &queue, layout);
+                gpu_trees.insert(id, gpu_tree);
+                trees_changed = true;
+                info!("VoxelTree extracted");
+            }
+        }
+    }
+
+    if trees_changed {
+        gpu_scene.trees_generation = gpu_scene.trees_generation.wrapping_add(1);
+    }
+    if edits_changed {
+        gpu_scene.edits_generation = gpu_scene.edits_generation.wrapping_add(1);
     }
 }
 
@@ -788,6 +1586,8 @@ impl render_graph::Node for VoxelDrawNode {
         let pipeline_cache = world.resource::<PipelineCache>();
         let voxel_scene = world.resource::<VoxelGpuScene>();
         let voxel_bind_group = world.resource::<VoxelBindGroups>();
+        let dims = world.resource::<VoxelWorldDims>();
+        let workgroup_size = dims.workgroup_size();
 
         {
             let nodes_cap = voxel_scene.info.get().nodes_cap;
@@ -810,7 +1610,7 @@ impl render_graph::Node for VoxelDrawNode {
 
             pass.set_bind_group(0, &voxel_bind_group.0, &[]);
 
-            let wg = WORKGROUP_SIZE.x * WORKGROUP_SIZE.y * WORKGROUP_SIZE.z;
+            let wg = workgroup_size.element_product();
             let dispatch = (count + wg - 1) / wg;
             pass.dispatch_workgroups(dispatch, 1, 1);
 
@@ -822,9 +1622,10 @@ impl render_graph::Node for VoxelDrawNode {
 
         for z in 0..1 {
             for x in 0..1 {
-                let offset = UVec3::splat(1024) * UVec3::new(x, 0, z);
+                let batch_size = UVec3::splat(voxel_scene.settings.draw_batch_size(dims));
+                let offset = batch_size * UVec3::new(x, 0, z);
                 let world_min = UVec3::splat(0) + offset;
-                let world_max = UVec3::splat(1024) + offset;
+                let world_max = (batch_size + offset).min(UVec3::splat(dims.size()));
                 let mut dispatch_size_prev = UVec3::ZERO;
 
                 {
@@ -845,7 +1646,7 @@ impl render_graph::Node for VoxelDrawNode {
 
                     let min = world_min;
                     let max = world_max;
-                    let depth = VOXEL_TREE_DEPTH - 1;
+                    let depth = dims.depth - 1;
 
                     pass.set_push_constants(4 * 0, &(min.x as i32).to_ne_bytes());
                     pass.set_push_constants(4 * 1, &(min.y as i32).to_ne_bytes());
@@ -874,11 +1675,11 @@ impl render_graph::Node for VoxelDrawNode {
 
                     pass.set_push_constants(4 * 20, &(depth as u32).to_ne_bytes());
 
-                    let bound_min = min / WORKGROUP_SIZE * WORKGROUP_SIZE;
+                    let bound_min = min / workgroup_size * workgroup_size;
                     let bound_max =
-                        (max + WORKGROUP_SIZE - UVec3::splat(1)) / WORKGROUP_SIZE * WORKGROUP_SIZE;
+                        (max + workgroup_size - UVec3::splat(1)) / workgroup_size * workgroup_size;
 
-                    let dispatch_size = ((bound_max - bound_min) / WORKGROUP_SIZE).max(UVec3::ONE);
+                    let dispatch_size = ((bound_max - bound_min) / workgroup_size).max(UVec3::ONE);
 
                     info!(
                         "draw_leafs; depth: {}, min: {}, max: {}, dispatch: {}, dispatch_prev: {}",
@@ -905,8 +1706,8 @@ impl render_graph::Node for VoxelDrawNode {
 
                     pass.set_bind_group(0, &voxel_bind_group.0, &[]);
 
-                    for depth in (0..VOXEL_TREE_DEPTH - 1).rev() {
-                        let size = (VOXEL_DIM as u32).pow((VOXEL_TREE_DEPTH - 1 - depth) as u32);
+                    for depth in (0..dims.depth - 1).rev() {
+                        let size = (dims.dim as u32).pow((dims.depth - 1 - depth) as u32);
                         let min = world_min / size;
                         let max = (world_max - UVec3::ONE) / size + UVec3::ONE;
 
@@ -946,12 +1747,12 @@ impl render_graph::Node for VoxelDrawNode {
 
                         pass.set_push_constants(4 * 20, &(depth as u32).to_ne_bytes());
 
-                        let bound_min = min / WORKGROUP_SIZE * WORKGROUP_SIZE;
-                        let bound_max = (max + WORKGROUP_SIZE - UVec3::splat(1)) / WORKGROUP_SIZE
-                            * WORKGROUP_SIZE;
+                        let bound_min = min / workgroup_size * workgroup_size;
+                        let bound_max = (max + workgroup_size - UVec3::splat(1)) / workgroup_size
+                            * workgroup_size;
 
                         let dispatch_size =
-                            ((bound_max - bound_min) / WORKGROUP_SIZE).max(UVec3::ONE);
+                            ((bound_max - bound_min) / workgroup_size).max(UVec3::ONE);
 
                         info!(
                         "draw_nodes; depth: {}, min: {}, max: {}, dispatch: {}, dispatch_prev: {}",
@@ -962,19 +1763,6 @@ impl render_graph::Node for VoxelDrawNode {
                         dispatch_size_prev = dispatch_size;
                     }
                 }
-
-                {
-                    let src = voxel_scene.info.buffer().unwrap();
-                    let dst = &voxel_scene.info_copy_dest;
-
-                    render_context.command_encoder().copy_buffer_to_buffer(
-                        src,
-                        0,
-                        dst,
-                        0,
-                        src.size(),
-                    );
-                }
             }
         }
 
@@ -985,14 +1773,81 @@ impl render_graph::Node for VoxelDrawNode {
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
 pub struct VoxelDrawImportNodeLabel;
 
+/// What the next run of `VoxelDrawImportNode` draws
+pub enum VoxelImportPlan {
+    /// Clear the GPU world and draw every tree
+    Full,
+    /// Redraw the regions of the trees edited since the last sync, see `VoxelTree::dirty`
+    Regions(Vec<(AssetId<VoxelTree>, UVec3, UVec3)>),
+}
+
 pub struct VoxelDrawImportNode {
     state: VoxelDrawState,
+    trees_generation: u32,
+    edits_generation: u32,
+    pools_generation: u32,
+    plan: VoxelImportPlan,
+    // `prepare_generation` and `edit_generation` of every tree at the last run
+    synced: HashMap<AssetId<VoxelTree>, (u32, u64)>,
 }
 
 impl Default for VoxelDrawImportNode {
     fn default() -> Self {
         Self {
             state: VoxelDrawState::Loading,
+            trees_generation: 0,
+            edits_generation: 0,
+            pools_generation: 0,
+            plan: VoxelImportPlan::Full,
+            synced: HashMap::new(),
+        }
+    }
+}
+
+impl VoxelDrawImportNode {
+    /// Trees that were only edited through the tracked API are patched, any other change
+    /// (new tree, reload, untracked modification, removal) redraws everything. The dirty
+    /// cells of the trees are consumed.
+    fn next_plan(&mut self, world: &mut World) -> Option<VoxelImportPlan> {
+        let assets = world.resource::<GpuVoxelTrees>();
+        let voxel_scene = world.resource::<VoxelGpuScene>();
+        let dims = world.resource::<VoxelWorldDims>();
+
+        let mut regions = Vec::new();
+        let mut is_full = self.synced.keys().any(|id| !assets.contains_key(id));
+
+        for (&id, asset) in assets.iter() {
+            match self.synced.get(&id) {
+                Some(&(prepare_generation, _))
+                    if prepare_generation == asset.prepare_generation => {}
+                Some(&(_, edit_generation)) if asset.edit_generation > edit_generation => {
+                    let batch_size = voxel_scene.settings.draw_batch_size(dims);
+                    regions.extend(
+                        asset
+                            .dirty_regions(edit_generation, batch_size)
+                            .into_iter()
+                            .map(|(min, max)| (id, min, max)),
+                    );
+                }
+                _ => is_full = true,
+            }
+        }
+
+        self.synced = assets
+            .iter()
+            .map(|(&id, asset)| (id, (asset.prepare_generation, asset.edit_generation)))
+            .collect();
+
+        for asset in world.resource_mut::<GpuVoxelTrees>().values_mut() {
+            asset.dirty.clear();
+        }
+
+        if is_full {
+            Some(VoxelImportPlan::Full)
+        } else if !regions.is_empty() {
+            Some(VoxelImportPlan::Regions(regions))
+        } else {
+            None
         }
     }
 }
@@ -1001,6 +1856,9 @@ impl render_graph::Node for VoxelDrawImportNode {
     fn update(&mut self, world: &mut World) {
         let voxel_pipelines = world.resource::<VoxelPipelines>();
         let pipeline_cache = world.resource::<PipelineCache>();
+        let trees_generation = world.resource::<VoxelGpuScene>().trees_generation;
+        let edits_generation = world.resource::<VoxelGpuScene>().edits_generation;
+        let pools_generation = world.resource::<VoxelGpuScene>().pools_generation;
 
         match self.state {
             VoxelDrawState::Loading => {
@@ -1025,6 +1883,11 @@ impl render_graph::Node for VoxelDrawImportNode {
                 }
 
                 if ready == 3 {
+                    self.trees_generation = trees_generation;
+                    self.edits_generation = edits_generation;
+                    self.pools_generation = pools_generation;
+                    self.next_plan(world);
+                    self.plan = VoxelImportPlan::Full;
                     self.state = VoxelDrawState::Run;
                 }
             }
@@ -1054,6 +1917,24 @@ impl render_graph::Node for VoxelDrawImportNode {
 
                 if ready != 3 {
                     self.state = VoxelDrawState::Loading;
+                } else if self.pools_generation != pools_generation {
+                    // Draws that overflowed the pools were dropped
+                    self.trees_generation = trees_generation;
+                    self.edits_generation = edits_generation;
+                    self.pools_generation = pools_generation;
+                    self.next_plan(world);
+                    self.plan = VoxelImportPlan::Full;
+                    self.state = VoxelDrawState::Run;
+                } else if (self.trees_generation, self.edits_generation)
+                    != (trees_generation, edits_generation)
+                {
+                    // A tree was added, edited, reloaded or removed
+                    self.trees_generation = trees_generation;
+                    self.edits_generation = edits_generation;
+                    if let Some(plan) = self.next_plan(world) {
+                        self.plan = plan;
+                        self.state = VoxelDrawState::Run;
+                    }
                 }
             }
         }
@@ -1069,260 +1950,374 @@ impl render_graph::Node for VoxelDrawImportNode {
             return Ok(());
         };
 
-        let assets = world.resource::<RenderAssets<GpuVoxelTree>>();
+        let assets = world.resource::<GpuVoxelTrees>();
 
         let voxel_pipelines = world.resource::<VoxelPipelines>();
         let pipeline_cache = world.resource::<PipelineCache>();
         let voxel_scene = world.resource::<VoxelGpuScene>();
         let voxel_bind_group = world.resource::<VoxelBindGroups>();
+        let dims = world.resource::<VoxelWorldDims>();
+        let workgroup_size = dims.workgroup_size();
 
-        {
-            let nodes_cap = voxel_scene.info.get().nodes_cap;
-            let leafs_cap = voxel_scene.info.get().leafs_cap;
-            let count = nodes_cap + leafs_cap;
+        match &self.plan {
+            VoxelImportPlan::Full => {
+                // Bricks of the previous import can't be told apart, start from scratch
+                {
+                    let nodes_cap = voxel_scene.info.get().nodes_cap;
+                    let leafs_cap = voxel_scene.info.get().leafs_cap;
+                    let count = nodes_cap + leafs_cap;
 
-            let mut pass =
-                render_context
-                    .command_encoder()
-                    .begin_compute_pass(&ComputePassDescriptor {
-                        label: "voxel_clear_world".into(),
-                        ..default()
-                    });
+                    let mut pass = render_context.command_encoder().begin_compute_pass(
+                        &ComputePassDescriptor {
+                            label: "voxel_clear_world".into(),
+                            ..default()
+                        },
+                    );
 
-            pass.set_pipeline(
-                pipeline_cache
-                    .get_compute_pipeline(voxel_pipelines.clear_world)
-                    .unwrap(),
-            );
+                    pass.set_pipeline(
+                        pipeline_cache
+                            .get_compute_pipeline(voxel_pipelines.clear_world)
+                            .unwrap(),
+                    );
 
-            pass.set_bind_group(0, &voxel_bind_group.0, &[]);
+                    pass.set_bind_group(0, &voxel_bind_group.0, &[]);
 
-            let wg = WORKGROUP_SIZE.x * WORKGROUP_SIZE.y * WORKGROUP_SIZE.z;
-            let dispatch = (count + wg - 1) / wg;
-            pass.dispatch_workgroups(dispatch, 1, 1);
+                    let wg = workgroup_size.element_product();
+                    let dispatch = count.div_ceil(wg);
+                    pass.dispatch_workgroups(dispatch, 1, 1);
 
-            info!(
-                "Clear import; dispatch: {}, nodes_cap: {}, leafs_cap: {}",
-                dispatch, nodes_cap, leafs_cap
-            );
-        }
+                    info!(
+                        "Clear import; dispatch: {}, nodes_cap: {}, leafs_cap: {}",
+                        dispatch, nodes_cap, leafs_cap
+                    );
+                }
 
-        for (id, asset) in assets.iter() {
-            // let world_min = asset.world_min;
-            // let world_max = asset.world_max;
+                for (id, asset) in assets.iter() {
+                    let Some((min, max)) = asset.bbox else {
+                        continue;
+                    };
 
-            let offset = UVec3::splat(1024);
-            {
-                let b = U64Vec3::from(offset) / (VOXEL_DIM as u64);
-                assert_eq!(b.x * b.y * b.z, DRAW_MAX_DISPATCH);
+                    if (asset.depth, asset.dim) != (dims.depth, dims.dim) {
+                        warn!(
+                            "Skipping voxel tree {:?}; depth: {}, dim: {}, world dims: {:?}",
+                            id, asset.depth, asset.dim, dims
+                        );
+                        continue;
+                    }
+
+                    draw_import_region(render_context, world, asset, min, max);
+                }
             }
+            VoxelImportPlan::Regions(regions) => {
+                for &(id, min, max) in regions {
+                    let Some(asset) = assets.get(&id) else {
+                        continue;
+                    };
+
+                    if (asset.depth, asset.dim) == (dims.depth, dims.dim) {
+                        draw_import_region(render_context, world, asset, min, max);
+                    }
+                }
+            }
+        }
 
-            let asset_world_size = asset.world_max - asset.world_min;
-            let num_batchs = (asset_world_size + (offset - UVec3::ONE)) / offset;
+        Ok(())
+    }
+}
 
-            info!(
-                "draw_import; world_min: {}, world_max: {}",
-                asset.world_min, asset.world_max
-            );
+/// Draws `[region_min, region_max)` of the tree into the GPU world in batches of
+/// `draw_batch_size`, then updates the ancestor nodes and their LODs over the region.
+/// The region is in the tree's local coords, the part outside of the GPU world is dropped.
+fn draw_import_region(
+    render_context: &mut RenderContext,
+    world: &World,
+    asset: &GpuVoxelTree,
+    region_min: UVec3,
+    region_max: UVec3,
+) {
+    let voxel_pipelines = world.resource::<VoxelPipelines>();
+    let pipeline_cache = world.resource::<PipelineCache>();
+    let voxel_bind_group = world.resource::<VoxelBindGroups>();
+    let dims = world.resource::<VoxelWorldDims>();
+    let workgroup_size = dims.workgroup_size();
+
+    // Tree local coords to GPU world coords
+    let import_offset = asset.origin - dims.origin;
+    let gpu_min = (region_min.as_ivec3() + import_offset).max(IVec3::ZERO);
+    let gpu_max = (region_max.as_ivec3() + import_offset).min(IVec3::splat(dims.size() as i32));
+    if gpu_min.cmpge(gpu_max).any() {
+        warn!(
+            "draw_import; region {}..{} of a tree at {} is outside of the GPU world",
+            region_min, region_max, asset.origin
+        );
+        return;
+    }
+    let region_min = gpu_min.as_uvec3();
+    let region_max = gpu_max.as_uvec3();
 
-            for x in (0..num_batchs.x) {
-                for y in (0..num_batchs.y) {
-                    for z in (0..num_batchs.z) {
-                        let batch = UVec3::new(x, y, z);
-                        let world_min = asset.world_min + offset * batch;
-                        let world_max = asset.world_max.min(world_min + offset);
+    let settings = &world.resource::<VoxelGpuScene>().settings;
+    let offset = UVec3::splat(settings.draw_batch_size(dims));
+    {
+        let b = U64Vec3::from(offset) / (dims.dim as u64);
+        assert_eq!(b.x * b.y * b.z, settings.draw_max_dispatch());
+    }
 
-                        let mut dispatch_size_prev = UVec3::ZERO;
+    let region_size = region_max - region_min;
+    let num_batchs = (region_size + (offset - UVec3::ONE)) / offset;
 
-                        info!(
-                            "draw_import; batch: {}, min: {}, max: {}",
-                            batch, world_min, world_max
+    trace!(
+        "draw_import; region_min: {}, region_max: {}",
+        region_min, region_max
+    );
+
+    for x in (0..num_batchs.x) {
+        for y in (0..num_batchs.y) {
+            for z in (0..num_batchs.z) {
+                let batch = UVec3::new(x, y, z);
+                let world_min = region_min + offset * batch;
+                let world_max = region_max.min(world_min + offset);
+
+                let mut dispatch_size_prev = UVec3::ZERO;
+
+                trace!(
+                    "draw_import; batch: {}, min: {}, max: {}",
+                    batch, world_min, world_max
+                );
+
+                {
+                    let mut pass = render_context.command_encoder().begin_compute_pass(
+                        &ComputePassDescriptor {
+                            label: "voxel_draw_leafs".into(),
+                            ..default()
+                        },
+                    );
+
+                    pass.set_pipeline(
+                        pipeline_cache
+                            .get_compute_pipeline(voxel_pipelines.draw_import)
+                            .unwrap(),
+                    );
+
+                    pass.set_bind_group(0, &voxel_bind_group.0, &[]);
+                    pass.set_bind_group(1, &asset.bind_group, &[]);
+
+                    let min = world_min;
+                    let max = world_max;
+                    let depth = dims.depth - 1;
+
+                    pass.set_push_constants(4 * 0, &(min.x as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 1, &(min.y as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 2, &(min.z as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 3, &(0 as i32).to_ne_bytes());
+
+                    pass.set_push_constants(4 * 4, &(max.x as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 5, &(max.y as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 6, &(max.z as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 7, &(0 as i32).to_ne_bytes());
+
+                    pass.set_push_constants(4 * 8, &(world_min.x as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 9, &(world_min.y as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 10, &(world_min.z as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 11, &(0 as i32).to_ne_bytes());
+
+                    pass.set_push_constants(4 * 12, &(world_max.x as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 13, &(world_max.y as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 14, &(world_max.z as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 15, &(0 as i32).to_ne_bytes());
+
+                    pass.set_push_constants(4 * 16, &(dispatch_size_prev.x as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 17, &(dispatch_size_prev.y as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 18, &(dispatch_size_prev.z as i32).to_ne_bytes());
+                    pass.set_push_constants(4 * 19, &(0 as i32).to_ne_bytes());
+
+                    pass.set_push_constants(4 * 20, &(depth as u32).to_ne_bytes());
+
+                    // GPU world coords back to tree local coords
+                    pass.set_push_constants(4 * 24, &(-import_offset.x).to_ne_bytes());
+                    pass.set_push_constants(4 * 25, &(-import_offset.y).to_ne_bytes());
+                    pass.set_push_constants(4 * 26, &(-import_offset.z).to_ne_bytes());
+                    pass.set_push_constants(4 * 27, &0i32.to_ne_bytes());
+
+                    let bound_min = min / workgroup_size * workgroup_size;
+                    let bound_max =
+                        (max + workgroup_size - UVec3::splat(1)) / workgroup_size * workgroup_size;
+
+                    let dispatch_size = ((bound_max - bound_min) / workgroup_size).max(UVec3::ONE);
+
+                    trace!(
+                        "draw_import; depth: {}, min: {}, max: {}, dispatch: {}, dispatch_prev: {}",
+                        depth, min, max, dispatch_size, dispatch_size_prev,
+                    );
+
+                    pass.dispatch_workgroups(dispatch_size.x, dispatch_size.y, dispatch_size.z);
+
+                    dispatch_size_prev = dispatch_size;
+                }
+
+                {
+                    let mut pass = render_context.command_encoder().begin_compute_pass(
+                        &ComputePassDescriptor {
+                            label: "voxel_draw".into(),
+                            ..default()
+                        },
+                    );
+
+                    pass.set_pipeline(
+                        pipeline_cache
+                            .get_compute_pipeline(voxel_pipelines.draw_nodes)
+                            .unwrap(),
+                    );
+
+                    pass.set_bind_group(0, &voxel_bind_group.0, &[]);
+
+                    for depth in (0..dims.depth - 1).rev() {
+                        let size = (dims.dim as u32).pow((dims.depth - 1 - depth) as u32);
+                        let min = world_min / size;
+                        let max = (world_max - UVec3::ONE) / size + UVec3::ONE;
+
+                        pass.set_push_constants(4 * 0, &(min.x as i32).to_ne_bytes());
+                        pass.set_push_constants(4 * 1, &(min.y as i32).to_ne_bytes());
+                        pass.set_push_constants(4 * 2, &(min.z as i32).to_ne_bytes());
+                        pass.set_push_constants(4 * 3, &(0 as i32).to_ne_bytes());
+
+                        pass.set_push_constants(4 * 4, &(max.x as i32).to_ne_bytes());
+                        pass.set_push_constants(4 * 5, &(max.y as i32).to_ne_bytes());
+                        pass.set_push_constants(4 * 6, &(max.z as i32).to_ne_bytes());
+                        pass.set_push_constants(4 * 7, &(0 as i32).to_ne_bytes());
+
+                        pass.set_push_constants(4 * 8, &(world_min.x as i32).to_ne_bytes());
+                        pass.set_push_constants(4 * 9, &(world_min.y as i32).to_ne_bytes());
+                        pass.set_push_constants(4 * 10, &(world_min.z as i32).to_ne_bytes());
+                        pass.set_push_constants(4 * 11, &(0 as i32).to_ne_bytes());
+
+                        pass.set_push_constants(4 * 12, &(world_max.x as i32).to_ne_bytes());
+                        pass.set_push_constants(4 * 13, &(world_max.y as i32).to_ne_bytes());
+                        pass.set_push_constants(4 * 14, &(world_max.z as i32).to_ne_bytes());
+                        pass.set_push_constants(4 * 15, &(0 as i32).to_ne_bytes());
+
+                        pass.set_push_constants(
+                            4 * 16,
+                            &(dispatch_size_prev.x as i32).to_ne_bytes(),
                         );
+                        pass.set_push_constants(
+                            4 * 17,
+                            &(dispatch_size_prev.y as i32).to_ne_bytes(),
+                        );
+                        pass.set_push_constants(
+                            4 * 18,
+                            &(dispatch_size_prev.z as i32).to_ne_bytes(),
+                        );
+                        pass.set_push_constants(4 * 19, &(0 as i32).to_ne_bytes());
+
+                        pass.set_push_constants(4 * 20, &(depth as u32).to_ne_bytes());
+
+                        let bound_min = min / workgroup_size * workgroup_size;
+                        let bound_max = (max + workgroup_size - UVec3::splat(1)) / workgroup_size
+                            * workgroup_size;
+
+                        let dispatch_size =
+                            ((bound_max - bound_min) / workgroup_size).max(UVec3::ONE);
+
+                        trace!(
+                            "draw_nodes_import; depth: {}, min: {}, max: {}, dispatch: {}, dispatch_prev: {}",
+                            depth, min, max, dispatch_size, dispatch_size_prev,
+                        );
+                        pass.dispatch_workgroups(dispatch_size.x, dispatch_size.y, dispatch_size.z);
 
-                        {
-                            let mut pass = render_context.command_encoder().begin_compute_pass(
-                                &ComputePassDescriptor {
-                                    label: "voxel_draw_leafs".into(),
-                                    ..default()
-                                },
-                            );
-
-                            pass.set_pipeline(
-                                pipeline_cache
-                                    .get_compute_pipeline(voxel_pipelines.draw_import)
-                                    .unwrap(),
-                            );
-
-                            pass.set_bind_group(0, &voxel_bind_group.0, &[]);
-                            pass.set_bind_group(1, &asset.bind_group, &[]);
-
-                            let min = world_min;
-                            let max = world_max;
-                            let depth = VOXEL_TREE_DEPTH - 1;
-
-                            pass.set_push_constants(4 * 0, &(min.x as i32).to_ne_bytes());
-                            pass.set_push_constants(4 * 1, &(min.y as i32).to_ne_bytes());
-                            pass.set_push_constants(4 * 2, &(min.z as i32).to_ne_bytes());
-                            pass.set_push_constants(4 * 3, &(0 as i32).to_ne_bytes());
-
-                            pass.set_push_constants(4 * 4, &(max.x as i32).to_ne_bytes());
-                            pass.set_push_constants(4 * 5, &(max.y as i32).to_ne_bytes());
-                            pass.set_push_constants(4 * 6, &(max.z as i32).to_ne_bytes());
-                            pass.set_push_constants(4 * 7, &(0 as i32).to_ne_bytes());
-
-                            pass.set_push_constants(4 * 8, &(world_min.x as i32).to_ne_bytes());
-                            pass.set_push_constants(4 * 9, &(world_min.y as i32).to_ne_bytes());
-                            pass.set_push_constants(4 * 10, &(world_min.z as i32).to_ne_bytes());
-                            pass.set_push_constants(4 * 11, &(0 as i32).to_ne_bytes());
-
-                            pass.set_push_constants(4 * 12, &(world_max.x as i32).to_ne_bytes());
-                            pass.set_push_constants(4 * 13, &(world_max.y as i32).to_ne_bytes());
-                            pass.set_push_constants(4 * 14, &(world_max.z as i32).to_ne_bytes());
-                            pass.set_push_constants(4 * 15, &(0 as i32).to_ne_bytes());
-
-                            pass.set_push_constants(
-                                4 * 16,
-                                &(dispatch_size_prev.x as i32).to_ne_bytes(),
-                            );
-                            pass.set_push_constants(
-                                4 * 17,
-                                &(dispatch_size_prev.y as i32).to_ne_bytes(),
-                            );
-                            pass.set_push_constants(
-                                4 * 18,
-                                &(dispatch_size_prev.z as i32).to_ne_bytes(),
-                            );
-                            pass.set_push_constants(4 * 19, &(0 as i32).to_ne_bytes());
-
-                            pass.set_push_constants(4 * 20, &(depth as u32).to_ne_bytes());
-
-                            let bound_min = min / WORKGROUP_SIZE * WORKGROUP_SIZE;
-                            let bound_max = (max + WORKGROUP_SIZE - UVec3::splat(1))
-                                / WORKGROUP_SIZE
-                                * WORKGROUP_SIZE;
-
-                            let dispatch_size =
-                                ((bound_max - bound_min) / WORKGROUP_SIZE).max(UVec3::ONE);
-
-                            info!(
-                                "draw_import; depth: {}, min: {}, max: {}, dispatch: {}, dispatch_prev: {}",
-                                depth, min, max, dispatch_size, dispatch_size_prev,
-                            );
-
-                            pass.dispatch_workgroups(
-                                dispatch_size.x,
-                                dispatch_size.y,
-                                dispatch_size.z,
-                            );
-
-                            dispatch_size_prev = dispatch_size;
-                        }
-
-                        {
-                            let mut pass = render_context.command_encoder().begin_compute_pass(
-                                &ComputePassDescriptor {
-                                    label: "voxel_draw".into(),
-                                    ..default()
-                                },
-                            );
-
-                            pass.set_pipeline(
-                                pipeline_cache
-                                    .get_compute_pipeline(voxel_pipelines.draw_nodes)
-                                    .unwrap(),
-                            );
-
-                            pass.set_bind_group(0, &voxel_bind_group.0, &[]);
-
-                            for depth in (0..VOXEL_TREE_DEPTH - 1).rev() {
-                                let size =
-                                    (VOXEL_DIM as u32).pow((VOXEL_TREE_DEPTH - 1 - depth) as u32);
-                                let min = world_min / size;
-                                let max = (world_max - UVec3::ONE) / size + UVec3::ONE;
-
-                                pass.set_push_constants(4 * 0, &(min.x as i32).to_ne_bytes());
-                                pass.set_push_constants(4 * 1, &(min.y as i32).to_ne_bytes());
-                                pass.set_push_constants(4 * 2, &(min.z as i32).to_ne_bytes());
-                                pass.set_push_constants(4 * 3, &(0 as i32).to_ne_bytes());
-
-                                pass.set_push_constants(4 * 4, &(max.x as i32).to_ne_bytes());
-                                pass.set_push_constants(4 * 5, &(max.y as i32).to_ne_bytes());
-                                pass.set_push_constants(4 * 6, &(max.z as i32).to_ne_bytes());
-                                pass.set_push_constants(4 * 7, &(0 as i32).to_ne_bytes());
-
-                                pass.set_push_constants(4 * 8, &(world_min.x as i32).to_ne_bytes());
-                                pass.set_push_constants(4 * 9, &(world_min.y as i32).to_ne_bytes());
-                                pass.set_push_constants(
-                                    4 * 10,
-                                    &(world_min.z as i32).to_ne_bytes(),
-                                );
-                                pass.set_push_constants(4 * 11, &(0 as i32).to_ne_bytes());
-
-                                pass.set_push_constants(
-                                    4 * 12,
-                                    &(world_max.x as i32).to_ne_bytes(),
-                                );
-                                pass.set_push_constants(
-                                    4 * 13,
-                                    &(world_max.y as i32).to_ne_bytes(),
-                                );
-                                pass.set_push_constants(
-                                    4 * 14,
-                                    &(world_max.z as i32).to_ne_bytes(),
-                                );
-                                pass.set_push_constants(4 * 15, &(0 as i32).to_ne_bytes());
-
-                                pass.set_push_constants(
-                                    4 * 16,
-                                    &(dispatch_size_prev.x as i32).to_ne_bytes(),
-                                );
-                                pass.set_push_constants(
-                                    4 * 17,
-                                    &(dispatch_size_prev.y as i32).to_ne_bytes(),
-                                );
-                                pass.set_push_constants(
-                                    4 * 18,
-                                    &(dispatch_size_prev.z as i32).to_ne_bytes(),
-                                );
-                                pass.set_push_constants(4 * 19, &(0 as i32).to_ne_bytes());
-
-                                pass.set_push_constants(4 * 20, &(depth as u32).to_ne_bytes());
-
-                                let bound_min = min / WORKGROUP_SIZE * WORKGROUP_SIZE;
-                                let bound_max = (max + WORKGROUP_SIZE - UVec3::splat(1))
-                                    / WORKGROUP_SIZE
-                                    * WORKGROUP_SIZE;
-
-                                let dispatch_size =
-                                    ((bound_max - bound_min) / WORKGROUP_SIZE).max(UVec3::ONE);
-
-                                info!(
-                                    "draw_nodes_import; depth: {}, min: {}, max: {}, dispatch: {}, dispatch_prev: {}",
-                                    depth, min, max, dispatch_size, dispatch_size_prev,
-                                );
-                                pass.dispatch_workgroups(
-                                    dispatch_size.x,
-                                    dispatch_size.y,
-                                    dispatch_size.z,
-                                );
-
-                                dispatch_size_prev = dispatch_size;
-                            }
-                        }
+                        dispatch_size_prev = dispatch_size;
                     }
                 }
             }
+        }
+    }
+}
 
-            {
-                let src = voxel_scene.info.buffer().unwrap();
-                let dst = &voxel_scene.info_copy_dest;
-
-                render_context
-                    .command_encoder()
-                    .copy_buffer_to_buffer(src, 0, dst, 0, src.size());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn walk_slots(tree: &VoxelTree, min: IVec3, max: IVec3) -> (Vec<u32>, Vec<u32>) {
+        let mut nodes = Vec::new();
+        let mut leafs = Vec::new();
+        for item in TreeWalk::new(tree, min, max) {
+            match item {
+                WalkItem::Node { idx, .. } => nodes.push(idx),
+                WalkItem::Leaf { idx, .. } => leafs.push(idx),
+                WalkItem::Collapsed { .. } => {}
             }
         }
+        (nodes, leafs)
+    }
 
-        Ok(())
+    #[test]
+    fn edits_extract_only_their_bricks_and_ancestors() {
+        let mut tree = VoxelTree::new(3, 2);
+        tree.track_dirty = true;
+        let voxel = Voxel::new(IVec3::new(255, 0, 0), 1);
+        tree.set_voxel(IVec3::new(0, 0, 0), voxel).unwrap();
+        tree.set_voxel(IVec3::new(7, 7, 7), voxel).unwrap();
+
+        let full = ExtractedVoxelTree::new(&tree, None);
+        assert!(full.is_full);
+        assert_eq!(full.node_slots.len(), tree.nodes.len());
+        assert_eq!(full.leaf_slots.len(), tree.leafs.len());
+        assert_eq!(full.bbox, Some((UVec3::ZERO, UVec3::splat(8))));
+
+        let since = tree.edit_generation;
+        tree.extracted_generation.set(since);
+        tree.set_voxel(IVec3::new(7, 7, 6), voxel).unwrap();
+        // The cells extracted above are dropped by the edit
+        assert!(tree.dirty.values().all(|&generation| generation > since));
+
+        let patch = ExtractedVoxelTree::new(&tree, Some(since));
+        assert!(!patch.is_full);
+
+        let (edited_nodes, edited_leafs) = walk_slots(&tree, IVec3::splat(6), IVec3::splat(8));
+        let (_, other_leafs) = walk_slots(&tree, IVec3::ZERO, IVec3::splat(2));
+
+        let mut nodes = vec![0];
+        nodes.extend(edited_nodes);
+        assert_eq!(patch.node_slots, nodes);
+        assert!(patch.leaf_slots.contains(&edited_leafs[0]));
+        assert!(!patch.leaf_slots.contains(&other_leafs[0]));
+
+        let node_stride = 1 + mask_len(2) + voxel_count(2);
+        let leaf_stride = mask_len(2) + voxel_count(2);
+        assert_eq!(patch.node_words.len(), patch.node_slots.len() * node_stride);
+        assert_eq!(patch.leaf_words.len(), patch.leaf_slots.len() * leaf_stride);
+    }
+
+    #[test]
+    fn untracked_edits_are_not_recorded() {
+        let mut tree = VoxelTree::new(3, 2);
+        let voxel = Voxel::new(IVec3::new(255, 0, 0), 1);
+        tree.set_voxel(IVec3::ZERO, voxel).unwrap();
+        tree.fill_box(IVec3::ZERO, IVec3::splat(4), voxel);
+
+        assert!(tree.dirty.is_empty());
+        assert_eq!(tree.edit_generation, 0);
+    }
+
+    #[test]
+    fn dedup_extracts_whole_tree() {
+        let mut tree = VoxelTree::new(3, 2);
+        tree.track_dirty = true;
+        let voxel = Voxel::new(IVec3::new(255, 0, 0), 1);
+        tree.set_voxel(IVec3::ZERO, voxel).unwrap();
+        tree.set_voxel(IVec3::splat(4), voxel).unwrap();
+
+        let since = tree.edit_generation;
+        tree.extracted_generation.set(since);
+        tree.dedup();
+
+        let patch = ExtractedVoxelTree::new(&tree, Some(since));
+        assert_eq!(patch.dirty, vec![(IVec3::ZERO, 8, since + 1)]);
+
+        let (nodes, leafs) = walk_slots(&tree, IVec3::ZERO, IVec3::splat(8));
+        let mut nodes = nodes.into_iter().collect::<BTreeSet<_>>();
+        nodes.insert(0);
+        let leafs = leafs.into_iter().collect::<BTreeSet<_>>();
+        assert_eq!(patch.node_slots, nodes.into_iter().collect::<Vec<_>>());
+        assert_eq!(patch.leaf_slots, leafs.into_iter().collect::<Vec<_>>());
     }
 }
@@ -0,0 +1,20 @@
+use bevy::prelude::*;
+
+// CPU versions of the primitives from `sdf.wgsl`
+// https://iquilezles.org/articles/distfunctions/
+
+pub fn sdf_sphere(p: Vec3, r: f32) -> f32 {
+    p.length() - r
+}
+
+pub fn sdf_round_box(p: Vec3, b: Vec3, r: f32) -> f32 {
+    let q = p.abs() - b + r;
+    q.max(Vec3::ZERO).length() + q.max_element().min(0.) - r
+}
+
+pub fn sdf_capsule(p: Vec3, a: Vec3, b: Vec3, r: f32) -> f32 {
+    let pa = p - a;
+    let ba = b - a;
+    let h = (pa.dot(ba) / ba.length_squared().max(f32::EPSILON)).clamp(0., 1.);
+    (pa - ba * h).length() - r
+}
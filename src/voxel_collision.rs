@@ -0,0 +1,171 @@
+use bevy::prelude::*;
+
+use crate::{voxel_iter::*, voxel_tree::*};
+
+/// Gap kept between a swept box and the voxels it stops at, so the box doesn't end up
+/// touching them and the next sweep starts outside
+pub const VOXEL_COLLISION_SKIN: f32 = 1e-3;
+
+/// Result of `VoxelTree::sweep_aabb`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SweepResult {
+    /// Movement that doesn't overlap any voxel, the requested one with blocked axes shortened
+    pub delta: Vec3,
+    /// Axes along which the box was stopped
+    pub hit: BVec3,
+}
+
+impl VoxelTree {
+    /// Whether the voxel at `pos` in world coords is occupied, voxels of collapsed bricks included
+    pub fn is_solid(&self, pos: IVec3) -> bool {
+        self.get_voxel(pos).is_some()
+    }
+
+    /// Whether `pos` in world coords is inside an occupied voxel, `VOXEL_SIZE` is 1
+    pub fn point_overlaps(&self, pos: Vec3) -> bool {
+        self.is_solid(pos.floor().as_ivec3())
+    }
+
+    /// Whether any occupied voxel intersects the open box `(min, max)`, so boxes resting
+    /// on or sliding along voxel faces don't overlap them
+    pub fn aabb_overlaps(&self, min: Vec3, max: Vec3) -> bool {
+        let min = min.floor().as_ivec3();
+        let max = max.ceil().as_ivec3();
+        if min.cmpge(max).any() {
+            return false;
+        }
+
+        TreeWalk::new(self, min, max).any(|item| match item {
+            WalkItem::Node { .. } => false,
+            WalkItem::Leaf { idx, origin } => mask_iter(&self.leafs[idx as usize].mask).any(|i| {
+                let pos = origin + idx_to_pos(i as i32, self.dim);
+                pos.cmpge(min).all() && pos.cmplt(max).all()
+            }),
+            // The walk only yields bricks intersecting the region
+            WalkItem::Collapsed { .. } => true,
+        })
+    }
+
+    /// Moves the box `[min, max]` by `delta` one axis at a time, vertical first. A blocked
+    /// axis stops at the voxel face while the others keep moving, so the box slides along
+    /// walls and floors. Voxels the box already overlaps are ignored.
+    pub fn sweep_aabb(&self, min: Vec3, max: Vec3, delta: Vec3) -> SweepResult {
+        let mut min = min;
+        let mut max = max;
+        let mut res = Vec3::ZERO;
+        let mut hit = [false; 3];
+
+        for axis in [1, 0, 2] {
+            let dist = self.sweep_axis(min, max, axis, delta[axis]);
+            min[axis] += dist;
+            max[axis] += dist;
+            res[axis] = dist;
+            hit[axis] = dist.abs() < delta[axis].abs();
+        }
+
+        SweepResult {
+            delta: res,
+            hit: BVec3::from(hit),
+        }
+    }
+
+    /// Distance the box can move along `axis`, checking the voxel layers entered by its
+    /// leading face in order
+    fn sweep_axis(&self, min: Vec3, max: Vec3, axis: usize, dist: f32) -> f32 {
+        let layer_overlaps = |layer: i32| {
+            let mut layer_min = min;
+            let mut layer_max = max;
+            layer_min[axis] = layer as f32;
+            layer_max[axis] = (layer + 1) as f32;
+            self.aabb_overlaps(layer_min, layer_max)
+        };
+
+        if dist > 0. {
+            let face = max[axis];
+            for layer in face.ceil() as i32..(face + dist).ceil() as i32 {
+                if layer_overlaps(layer) {
+                    return (layer as f32 - face - VOXEL_COLLISION_SKIN).clamp(0., dist);
+                }
+            }
+        } else if dist < 0. {
+            let face = min[axis];
+            for layer in ((face + dist).floor() as i32..face.floor() as i32).rev() {
+                if layer_overlaps(layer) {
+                    return ((layer + 1) as f32 - face + VOXEL_COLLISION_SKIN).clamp(dist, 0.);
+                }
+            }
+        }
+
+        dist
+    }
+
+    /// Whether there is an occupied voxel at most `max_dist` below the box `[min, max]`
+    pub fn is_on_ground(&self, min: Vec3, max: Vec3, max_dist: f32) -> bool {
+        self.aabb_overlaps(
+            Vec3::new(min.x, min.y - max_dist, min.z),
+            Vec3::new(max.x, min.y, max.z),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> VoxelTree {
+        let mut tree = VoxelTree::new_centered(3, 2);
+        tree.set_voxel(IVec3::ZERO, Voxel::from_color(IVec3::splat(255)))
+            .unwrap();
+        tree
+    }
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).abs().max_element() < 1e-5, "{a} != {b}");
+    }
+
+    #[test]
+    fn sweep_stops_at_voxel() {
+        let tree = tree();
+        let min = Vec3::new(-2., 0.2, 0.2);
+        let max = Vec3::new(-1.5, 0.8, 0.8);
+
+        let res = tree.sweep_aabb(min, max, Vec3::new(3., 0., 0.));
+        assert_near(res.delta, Vec3::new(1.5 - VOXEL_COLLISION_SKIN, 0., 0.));
+        assert_eq!(res.hit, BVec3::new(true, false, false));
+        assert!(!tree.aabb_overlaps(min + res.delta, max + res.delta));
+    }
+
+    #[test]
+    fn sweep_slides_along_voxel() {
+        let tree = tree();
+        let min = Vec3::new(-2., 0.2, 0.2);
+        let max = Vec3::new(-1.5, 0.8, 0.8);
+
+        let res = tree.sweep_aabb(min, max, Vec3::new(3., 0., 0.5));
+        assert_near(res.delta, Vec3::new(1.5 - VOXEL_COLLISION_SKIN, 0., 0.5));
+        assert_eq!(res.hit, BVec3::new(true, false, false));
+    }
+
+    #[test]
+    fn sweep_lands_on_voxel() {
+        let tree = tree();
+        let min = Vec3::new(0.2, 2., 0.2);
+        let max = Vec3::new(0.8, 3., 0.8);
+
+        let res = tree.sweep_aabb(min, max, Vec3::new(0., -5., 0.));
+        assert_near(res.delta, Vec3::new(0., -1. + VOXEL_COLLISION_SKIN, 0.));
+        assert_eq!(res.hit, BVec3::new(false, true, false));
+        assert!(tree.is_on_ground(min + res.delta, max + res.delta, 0.01));
+    }
+
+    #[test]
+    fn sweep_passes_beside_voxel() {
+        let tree = tree();
+        let min = Vec3::new(-2., 1., 0.2);
+        let max = Vec3::new(-1.5, 2., 0.8);
+
+        let res = tree.sweep_aabb(min, max, Vec3::new(4., 0., 0.));
+        assert_near(res.delta, Vec3::new(4., 0., 0.));
+        assert_eq!(res.hit, BVec3::FALSE);
+    }
+}
@@ -0,0 +1,345 @@
+use bevy::prelude::*;
+
+use crate::{math::IMat4, voxel_iter::*, voxel_tree::*};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsgOp {
+    /// Voxels of the other tree overwrite voxels of this one
+    Union,
+    /// Voxels covered by the other tree are removed
+    Subtract,
+    /// Only voxels covered by the other tree are kept, with their own values
+    Intersect,
+}
+
+/// Content of a brick sized cell of a tree
+#[derive(Clone, Copy, Debug)]
+enum Brick {
+    Empty,
+    // Collapsed uniform brick, see `VoxelTree::is_collapsed`
+    Uniform(Voxel),
+    Leaf(u32),
+}
+
+impl VoxelTree {
+    pub fn union(&mut self, other: &VoxelTree, tr: &IMat4) -> Result<(), VoxelError> {
+        self.csg(other, tr, CsgOp::Union)
+    }
+
+    pub fn subtract(&mut self, other: &VoxelTree, tr: &IMat4) -> Result<(), VoxelError> {
+        self.csg(other, tr, CsgOp::Subtract)
+    }
+
+    pub fn intersect(&mut self, other: &VoxelTree, tr: &IMat4) -> Result<(), VoxelError> {
+        self.csg(other, tr, CsgOp::Intersect)
+    }
+
+    /// Combines `other` placed at `tr` into `self`. `tr` maps world positions of `other`
+    /// to world positions of `self`.
+    ///
+    /// If `tr` is a translation by whole bricks and both trees have the same `dim`,
+    /// leafs are combined mask by mask and collapsed bricks as regions. Otherwise `other`
+    /// is resampled into the layout of `self` first.
+    ///
+    /// Voxels of `other` that land outside of `self` are skipped, for `CsgOp::Union`
+    /// the first of them is returned as an error.
+    pub fn csg(&mut self, other: &VoxelTree, tr: &IMat4, op: CsgOp) -> Result<(), VoxelError> {
+        assert_ne!(self.depth, 0);
+        assert_ne!(other.depth, 0);
+
+        if let Some(offset) = self.brick_offset(other, tr) {
+            return self.csg_aligned(other, offset, op);
+        }
+
+        let mut moved = VoxelTree::new(self.depth, self.dim);
+        moved.origin = self.origin;
+        let res = other.transform_to(&mut moved, tr);
+
+        self.csg_aligned(&moved, IVec3::ZERO, op)?;
+
+        // Voxels that don't fit only matter if they would be added
+        match op {
+            CsgOp::Union => res,
+            CsgOp::Subtract | CsgOp::Intersect => Ok(()),
+        }
+    }
+
+    /// Writes every voxel of `self` to `tr * pos` of `dst`. Voxels that land outside
+    /// of `dst` are skipped and the first of them is returned as an error.
+    pub fn transform_to(&self, dst: &mut VoxelTree, tr: &IMat4) -> Result<(), VoxelError> {
+        let mut res = Ok(());
+        for (pos, voxel) in self.iter_voxels() {
+            let pos = (*tr * pos.extend(1)).xyz();
+            if let Err(err) = dst.set_voxel(pos, voxel) {
+                res = res.and(Err(err));
+            }
+        }
+
+        res
+    }
+
+    /// Translation of `tr` if it maps bricks of `other` exactly onto bricks of `self`
+    fn brick_offset(&self, other: &VoxelTree, tr: &IMat4) -> Option<IVec3> {
+        let is_translation = tr.x_axis == IVec4::X
+            && tr.y_axis == IVec4::Y
+            && tr.z_axis == IVec4::Z
+            && tr.w_axis.w == 1;
+        if !is_translation || self.dim != other.dim {
+            return None;
+        }
+
+        let offset = tr.w_axis.xyz();
+        let misalign =
+            (other.origin + offset - self.origin).rem_euclid(IVec3::splat(self.dim as i32));
+
+        (misalign == IVec3::ZERO).then_some(offset)
+    }
+
+    /// `offset` maps world positions of `other` to `self` and keeps bricks aligned
+    fn csg_aligned(
+        &mut self,
+        other: &VoxelTree,
+        offset: IVec3,
+        op: CsgOp,
+    ) -> Result<(), VoxelError> {
+        match op {
+            CsgOp::Union => self.union_aligned(other, offset),
+            CsgOp::Subtract => {
+                self.subtract_aligned(other, offset);
+                Ok(())
+            }
+            CsgOp::Intersect => {
+                self.intersect_aligned(other, offset);
+                Ok(())
+            }
+        }
+    }
+
+    fn union_aligned(&mut self, other: &VoxelTree, offset: IVec3) -> Result<(), VoxelError> {
+        let mut res = Ok(());
+
+        for item in TreeWalk::new(other, other.min(), other.max()) {
+            match item {
+                WalkItem::Node { .. } => {}
+                WalkItem::Leaf { idx, origin } => {
+                    // Aligned bricks are either completely inside of the tree or outside
+                    let local = match self.to_local_checked(origin + offset) {
+                        Ok(local) => local,
+                        Err(err) => {
+                            res = res.and(Err(err));
+                            continue;
+                        }
+                    };
+
+                    let src = &other.leafs[idx as usize];
+                    self.update_leaf(local, |leaf| {
+                        for i in mask_iter(&src.mask) {
+                            leaf.set(i, src.voxels[i]);
+                        }
+                    });
+                }
+                WalkItem::Collapsed {
+                    origin,
+                    size,
+                    voxel,
+                } => {
+                    let min = origin + offset;
+                    let max = min + size;
+                    for pos in [min, max - 1] {
+                        if let Err(err) = self.to_local_checked(pos) {
+                            res = res.and(Err(err));
+                        }
+                    }
+
+                    self.fill_with(min, max, |_| Some(voxel));
+                }
+            }
+        }
+
+        res
+    }
+
+    fn subtract_aligned(&mut self, other: &VoxelTree, offset: IVec3) {
+        for item in TreeWalk::new(other, other.min(), other.max()) {
+            match item {
+                WalkItem::Node { .. } => {}
+                WalkItem::Leaf { idx, origin } => {
+                    let pos = origin + offset;
+                    if matches!(self.brick_at(pos), Brick::Empty) {
+                        continue;
+                    }
+
+                    let src = &other.leafs[idx as usize];
+                    self.update_leaf(pos - self.origin, |leaf| {
+                        for i in mask_iter(&src.mask) {
+                            leaf.set(i, Voxel::empty());
+                        }
+                    });
+                }
+                WalkItem::Collapsed { origin, size, .. } => {
+                    self.clear_region(origin + offset, origin + offset + size);
+                }
+            }
+        }
+    }
+
+    fn intersect_aligned(&mut self, other: &VoxelTree, offset: IVec3) {
+        // Bricks get released while walking, so the walk can't borrow `self`
+        let items: Vec<_> = TreeWalk::new(self, self.min(), self.max()).collect();
+
+        for item in items {
+            match item {
+                WalkItem::Node { .. } => {}
+                WalkItem::Leaf { origin, .. } => match other.brick_at(origin - offset) {
+                    Brick::Empty => self.clear_region(origin, origin + self.dim as i32),
+                    Brick::Uniform(_) => {}
+                    Brick::Leaf(src_idx) => {
+                        let src = &other.leafs[src_idx as usize];
+                        self.update_leaf(origin - self.origin, |leaf| {
+                            for i in 0..leaf.voxels.len() {
+                                if !get_mask(&src.mask, i as i32) {
+                                    leaf.set(i, Voxel::empty());
+                                }
+                            }
+                        });
+                    }
+                },
+                WalkItem::Collapsed {
+                    origin,
+                    size,
+                    voxel,
+                } => {
+                    self.clear_region(origin, origin + size);
+
+                    let src_min = origin - offset;
+                    for (pos, _) in other.iter_voxels_in(src_min, src_min + size) {
+                        self.set_voxel(pos + offset, voxel)
+                            .expect("Position is inside of the collapsed brick");
+                    }
+                }
+            }
+        }
+    }
+
+    /// `pos` is the world position of a brick's min corner
+    fn brick_at(&self, pos: IVec3) -> Brick {
+        let Ok(pos) = self.to_local_checked(pos) else {
+            return Brick::Empty;
+        };
+
+        let mut node_idx = 0;
+        for depth in (1..self.depth).rev() {
+            let local_pos = pos / (self.dim as i32).pow(depth as u32) % (self.dim as i32);
+            let idx = pos_to_idx(local_pos, self.dim) as usize;
+
+            let node = &self.nodes[node_idx as usize];
+            if !get_mask(&node.mask, idx as i32) {
+                let voxel = self.lod_voxel(node_idx, idx);
+                return if voxel.is_empty() {
+                    Brick::Empty
+                } else {
+                    Brick::Uniform(voxel)
+                };
+            }
+
+            if depth == 1 {
+                return Brick::Leaf(node.indices[idx]);
+            }
+
+            node_idx = node.indices[idx];
+        }
+
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn red() -> Voxel {
+        Voxel::from_color(IVec3::new(255, 0, 0))
+    }
+
+    fn blue() -> Voxel {
+        Voxel::from_color(IVec3::new(0, 0, 255))
+    }
+
+    fn contains(min: IVec3, max: IVec3, pos: IVec3) -> bool {
+        pos.cmpge(min).all() && pos.cmplt(max).all()
+    }
+
+    // Red `[0, 4)` in `self`, a blue 4^3 box of `other` placed at `offset`
+    fn check(offset: IVec3, op: CsgOp) {
+        let mut tree = VoxelTree::new(3, 2);
+        tree.fill_box(IVec3::ZERO, IVec3::splat(4), red());
+
+        let mut other = VoxelTree::new(3, 2);
+        other.fill_box(IVec3::ZERO, IVec3::splat(4), blue());
+
+        tree.csg(&other, &IMat4::from_translation(offset), op)
+            .unwrap();
+
+        for x in 0..8 {
+            for y in 0..8 {
+                for z in 0..8 {
+                    let pos = IVec3::new(x, y, z);
+                    let in_self = contains(IVec3::ZERO, IVec3::splat(4), pos);
+                    let in_other = contains(offset, offset + 4, pos);
+
+                    let expected = match op {
+                        CsgOp::Union if in_other => Some(blue()),
+                        CsgOp::Union | CsgOp::Subtract if in_self && !in_other => Some(red()),
+                        CsgOp::Intersect if in_self && in_other => Some(red()),
+                        _ => None,
+                    };
+                    assert_eq!(tree.get_voxel(pos), expected, "{op:?} at {pos}");
+                }
+            }
+        }
+
+        assert!(tree.validate().is_ok());
+    }
+
+    #[test]
+    fn union_aligned() {
+        check(IVec3::splat(2), CsgOp::Union);
+    }
+
+    #[test]
+    fn union_unaligned() {
+        check(IVec3::new(1, 2, 3), CsgOp::Union);
+    }
+
+    #[test]
+    fn subtract_aligned() {
+        check(IVec3::splat(2), CsgOp::Subtract);
+    }
+
+    #[test]
+    fn subtract_unaligned() {
+        check(IVec3::new(1, 2, 3), CsgOp::Subtract);
+    }
+
+    #[test]
+    fn intersect_aligned() {
+        check(IVec3::splat(2), CsgOp::Intersect);
+    }
+
+    #[test]
+    fn intersect_unaligned() {
+        check(IVec3::new(1, 2, 3), CsgOp::Intersect);
+    }
+
+    #[test]
+    fn union_reports_voxels_outside() {
+        let mut tree = VoxelTree::new(3, 2);
+        let mut other = VoxelTree::new(3, 2);
+        other.fill_box(IVec3::ZERO, IVec3::splat(4), blue());
+
+        let res = tree.union(&other, &IMat4::from_translation(IVec3::splat(6)));
+
+        assert!(res.is_err());
+        assert_eq!(tree.get_voxel(IVec3::splat(7)), Some(blue()));
+    }
+}
@@ -0,0 +1,203 @@
+use std::{collections::HashMap, fmt};
+
+use crate::voxel_tree::*;
+
+/// Live bricks before and after `VoxelTree::dedup`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DedupStats {
+    pub leafs_before: usize,
+    pub leafs_after: usize,
+    pub nodes_before: usize,
+    pub nodes_after: usize,
+    /// Size of the released bricks in the GPU layout
+    pub bytes_saved: usize,
+}
+
+impl fmt::Display for DedupStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "leafs: {} -> {}, nodes: {} -> {}, saved: {:.2} MiB",
+            self.leafs_before,
+            self.leafs_after,
+            self.nodes_before,
+            self.nodes_after,
+            self.bytes_saved as f64 / (1024. * 1024.)
+        )
+    }
+}
+
+/// Canonical brick for each content and the brick every visited index was merged into
+#[derive(Default)]
+struct Dedup {
+    leafs: HashMap<Vec<u32>, u32>,
+    // Keyed by the depth, the LOD brick and the children. Without the depth a node of
+    // leafs could be merged with a node of nodes whose indices happen to match.
+    nodes: HashMap<(u8, u32, Vec<u32>), u32>,
+    leaf_remap: HashMap<u32, u32>,
+    node_remap: HashMap<u32, u32>,
+}
+
+impl VoxelTree {
+    /// Merges identical leafs (LOD bricks included) and then identical nodes bottom-up,
+    /// so they are shared between parents and the tree becomes a DAG. Writers copy
+    /// shared bricks before modifying them, see `unshare_child`. Every slot may change,
+    /// so the whole tree is marked dirty.
+    pub fn dedup(&mut self) -> DedupStats {
+        assert_ne!(self.depth, 0);
+
+        let leafs_before = self.leafs.len() - self.free_leafs.len();
+        let nodes_before = self.nodes.len() - self.free_nodes.len();
+
+        self.mark_all_dirty();
+
+        let mut dedup = Dedup::default();
+        self.dedup_node(0, 0, &mut dedup);
+
+        self.update_refs();
+        self.release_unreferenced();
+
+        let leafs_after = self.leafs.len() - self.free_leafs.len();
+        let nodes_after = self.nodes.len() - self.free_nodes.len();
+
+        let leaf_size = (mask_len(self.dim) + voxel_count(self.dim)) * 4;
+        let node_size = (1 + mask_len(self.dim) + voxel_count(self.dim)) * 4;
+
+        DedupStats {
+            leafs_before,
+            leafs_after,
+            nodes_before,
+            nodes_after,
+            bytes_saved: (leafs_before - leafs_after) * leaf_size
+                + (nodes_before - nodes_after) * node_size,
+        }
+    }
+
+    /// Returns the canonical node, the root is never merged
+    fn dedup_node(&mut self, node_idx: u32, depth: u8, dedup: &mut Dedup) -> u32 {
+        if let Some(&idx) = dedup.node_remap.get(&node_idx) {
+            return idx;
+        }
+
+        let lod_idx = self.nodes[node_idx as usize].leaf;
+        if lod_idx != VOXEL_IDX_EMPTY {
+            self.nodes[node_idx as usize].leaf = self.dedup_leaf(lod_idx, dedup);
+        }
+
+        let mask = self.nodes[node_idx as usize].mask.clone();
+        for i in mask_iter(&mask) {
+            let child_idx = self.nodes[node_idx as usize].indices[i];
+            let child_idx = if depth == self.depth - 2 {
+                self.dedup_leaf(child_idx, dedup)
+            } else {
+                self.dedup_node(child_idx, depth + 1, dedup)
+            };
+            self.nodes[node_idx as usize].indices[i] = child_idx;
+        }
+
+        let res = if node_idx == 0 {
+            0
+        } else {
+            let node = &self.nodes[node_idx as usize];
+            *dedup
+                .nodes
+                .entry((depth, node.leaf, node.indices.clone()))
+                .or_insert(node_idx)
+        };
+
+        dedup.node_remap.insert(node_idx, res);
+        res
+    }
+
+    fn dedup_leaf(&self, leaf_idx: u32, dedup: &mut Dedup) -> u32 {
+        if let Some(&idx) = dedup.leaf_remap.get(&leaf_idx) {
+            return idx;
+        }
+
+        let key = self.leafs[leaf_idx as usize]
+            .voxels
+            .iter()
+            .map(|voxel| voxel.data)
+            .collect();
+        let res = *dedup.leafs.entry(key).or_insert(leaf_idx);
+
+        dedup.leaf_remap.insert(leaf_idx, res);
+        res
+    }
+
+    /// Rebuilds the free lists from `leaf_refs` and `node_refs`
+    fn release_unreferenced(&mut self) {
+        self.free_leafs.clear();
+        for (idx, &refs) in self.leaf_refs.iter().enumerate() {
+            if refs == 0 {
+                self.leafs[idx].clear();
+                self.free_leafs.push(idx as u32);
+            }
+        }
+
+        self.free_nodes.clear();
+        for (idx, &refs) in self.node_refs.iter().enumerate() {
+            if refs == 0 {
+                self.nodes[idx].clear();
+                self.free_nodes.push(idx as u32);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::prelude::*;
+
+    fn voxels(tree: &VoxelTree) -> Vec<Option<Voxel>> {
+        let size = tree.size();
+        let mut res = Vec::new();
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    res.push(tree.get_voxel(tree.origin + IVec3::new(x, y, z)));
+                }
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn dedup_keeps_content() {
+        let mut tree = VoxelTree::new(4, 2);
+        // Child indices of a depth 1 and a depth 2 node end up equal
+        for (i, pos) in [
+            IVec3::new(3, 14, 11),
+            IVec3::new(14, 9, 0),
+            IVec3::new(8, 9, 12),
+            IVec3::new(3, 11, 0),
+            IVec3::new(15, 1, 4),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let voxel = Voxel::new(IVec3::splat(50 * i as i32), 1);
+            tree.set_voxel(pos, voxel).unwrap();
+        }
+
+        let before = voxels(&tree);
+        tree.dedup();
+        assert_eq!(voxels(&tree), before);
+        assert!(tree.validate().is_ok());
+    }
+
+    #[test]
+    fn dedup_shares_identical_bricks() {
+        let mut tree = VoxelTree::new(3, 2);
+        let voxel = Voxel::new(IVec3::new(255, 0, 0), 1);
+        tree.set_voxel(IVec3::new(0, 0, 0), voxel).unwrap();
+        tree.set_voxel(IVec3::new(4, 0, 0), voxel).unwrap();
+
+        let before = voxels(&tree);
+        let stats = tree.dedup();
+        assert_eq!(voxels(&tree), before);
+        assert_eq!(stats.leafs_after, 1);
+        assert_eq!(stats.nodes_after, 2);
+    }
+}
@@ -0,0 +1,290 @@
+use bevy::prelude::*;
+
+use crate::{sdf::*, voxel_tree::*};
+
+/// Arguments of `fill_region` passed down through `fill_node` and `fill_leaf`
+struct FillRegion<'a, C, F> {
+    min: IVec3,
+    max: IVec3,
+    cull: C,
+    f: &'a mut F,
+}
+
+impl VoxelTree {
+    /// Calls `f` for every voxel in `[min, max)` and writes the returned voxel,
+    /// `None` leaves the voxel untouched, `Some(Voxel::empty())` removes it.
+    /// The tree is walked once per brick instead of once per voxel.
+    pub fn fill_with(&mut self, min: IVec3, max: IVec3, mut f: impl FnMut(IVec3) -> Option<Voxel>) {
+        self.fill_region(min, max, |_, _| true, &mut f);
+    }
+
+    pub fn fill_box(&mut self, min: IVec3, max: IVec3, voxel: Voxel) {
+        self.fill_with(min, max, |_| Some(voxel));
+    }
+
+    pub fn fill_sphere(&mut self, center: Vec3, radius: f32, voxel: Voxel) {
+        self.fill_sdf(
+            center - radius,
+            center + radius,
+            |p| sdf_sphere(p - center, radius),
+            voxel,
+        );
+    }
+
+    /// `half_size` is the half extent of the box including the rounding `radius`
+    pub fn fill_round_box(&mut self, center: Vec3, half_size: Vec3, radius: f32, voxel: Voxel) {
+        self.fill_sdf(
+            center - half_size,
+            center + half_size,
+            |p| sdf_round_box(p - center, half_size, radius),
+            voxel,
+        );
+    }
+
+    /// Fills a capsule of `radius` around the segment `a`-`b`
+    pub fn fill_line(&mut self, a: Vec3, b: Vec3, radius: f32, voxel: Voxel) {
+        let radius = radius.max(0.5);
+        self.fill_sdf(
+            a.min(b) - radius,
+            a.max(b) + radius,
+            |p| sdf_capsule(p, a, b, radius),
+            voxel,
+        );
+    }
+
+    /// Fills the voxels in `[min, max)` whose center is in the half-space
+    /// `dot(p, normalize(normal)) + h <= 0`
+    pub fn fill_plane(&mut self, min: IVec3, max: IVec3, normal: Vec3, h: f32, voxel: Voxel) {
+        assert_ne!(normal, Vec3::ZERO, "Plane normal can't be zero");
+
+        let normal = normal.normalize();
+        self.fill_sdf_region(min, max, |p| p.dot(normal) + h, voxel);
+    }
+
+    /// Fills every voxel in `[floor(min), ceil(max) + 1)` whose center has `sdf(center) <= 0`.
+    /// Whole nodes are skipped when the distance at their center is larger than their half diagonal,
+    /// so `sdf` has to be a proper distance bound (like the functions from `sdf.rs`).
+    pub fn fill_sdf(&mut self, min: Vec3, max: Vec3, sdf: impl Fn(Vec3) -> f32, voxel: Voxel) {
+        let min = min.floor().as_ivec3();
+        let max = max.ceil().as_ivec3() + IVec3::ONE;
+
+        self.fill_sdf_region(min, max, sdf, voxel);
+    }
+
+    /// `fill_sdf` over the voxels in `[min, max)`
+    fn fill_sdf_region(&mut self, min: IVec3, max: IVec3, sdf: impl Fn(Vec3) -> f32, voxel: Voxel) {
+        self.fill_region(
+            min,
+            max,
+            |cell_min, cell_size| {
+                let half = cell_size as f32 * 0.5;
+                let center = cell_min.as_vec3() + half;
+                sdf(center) <= half * 3f32.sqrt()
+            },
+            &mut |pos| (sdf(pos.as_vec3() + 0.5) <= 0.).then_some(voxel),
+        );
+    }
+
+    /// `cull` gets the min corner and size of a node's cell and returns `false`
+    /// if nothing inside of it has to be written
+    fn fill_region(
+        &mut self,
+        min: IVec3,
+        max: IVec3,
+        cull: impl Fn(IVec3, i32) -> bool,
+        f: &mut impl FnMut(IVec3) -> Option<Voxel>,
+    ) {
+        assert_ne!(self.depth, 0);
+
+        let min = min.max(self.min());
+        let max = max.min(self.max());
+        if min.cmpge(max).any() {
+            return;
+        }
+
+        self.mark_dirty(min - self.origin, max - self.origin);
+
+        let child_size = (self.dim as i32).pow(self.depth as u32 - 1);
+        let mut region = FillRegion { min, max, cull, f };
+        self.fill_node(0, self.origin, child_size, 0, &mut region);
+    }
+
+    /// Returns `true` if the node has no children left
+    fn fill_node<C, F>(
+        &mut self,
+        node_idx: u32,
+        offset: IVec3,
+        child_size: i32,
+        depth: u8,
+        region: &mut FillRegion<C, F>,
+    ) -> bool
+    where
+        C: Fn(IVec3, i32) -> bool,
+        F: FnMut(IVec3) -> Option<Voxel>,
+    {
+        for idx in 0..self.voxel_count() {
+            let child_min = offset + idx_to_pos(idx as i32, self.dim) * child_size;
+            let child_max = child_min + child_size;
+
+            if child_max.cmple(region.min).any() || child_min.cmpge(region.max).any() {
+                continue;
+            }
+
+            if !(region.cull)(child_min, child_size) {
+                continue;
+            }
+
+            if self.is_collapsed(node_idx, idx) {
+                self.expand_lod(node_idx, idx, depth == self.depth - 2);
+            }
+
+            let child_idx = if get_mask(&self.nodes[node_idx as usize].mask, idx as i32) {
+                self.unshare_child(node_idx, idx, depth)
+            } else {
+                VOXEL_IDX_EMPTY
+            };
+
+            let child_idx = if depth == self.depth - 2 {
+                self.fill_leaf(child_idx, child_min, region)
+            } else {
+                let child_idx = if child_idx == VOXEL_IDX_EMPTY {
+                    self.alloc_node()
+                } else {
+                    child_idx
+                };
+
+                let is_empty = self.fill_node(
+                    child_idx,
+                    child_min,
+                    child_size / (self.dim as i32),
+                    depth + 1,
+                    region,
+                );
+
+                if is_empty {
+                    self.free_node(child_idx);
+                    VOXEL_IDX_EMPTY
+                } else {
+                    child_idx
+                }
+            };
+
+            self.set_child(node_idx, idx, child_idx);
+        }
+
+        self.is_node_empty(node_idx)
+    }
+
+    /// Returns the index of the leaf after writing, `VOXEL_IDX_EMPTY` if it is empty.
+    /// The leaf is only allocated once a non-empty voxel is written into it.
+    fn fill_leaf<C, F>(
+        &mut self,
+        leaf_idx: u32,
+        offset: IVec3,
+        region: &mut FillRegion<C, F>,
+    ) -> u32
+    where
+        F: FnMut(IVec3) -> Option<Voxel>,
+    {
+        let lmin = (region.min - offset).max(IVec3::ZERO);
+        let lmax = (region.max - offset).min(IVec3::splat(self.dim as i32));
+
+        let mut leaf_idx = leaf_idx;
+
+        for x in lmin.x..lmax.x {
+            for y in lmin.y..lmax.y {
+                for z in lmin.z..lmax.z {
+                    let pos = IVec3::new(x, y, z);
+
+                    let Some(voxel) = (region.f)(offset + pos) else {
+                        continue;
+                    };
+
+                    if leaf_idx == VOXEL_IDX_EMPTY {
+                        if voxel.is_empty() {
+                            continue;
+                        }
+
+                        leaf_idx = self.alloc_leaf();
+                    }
+
+                    self.leafs[leaf_idx as usize].set(pos_to_idx(pos, self.dim) as usize, voxel);
+                }
+            }
+        }
+
+        if leaf_idx != VOXEL_IDX_EMPTY && self.leafs[leaf_idx as usize].is_empty() {
+            self.free_leaf(leaf_idx);
+            return VOXEL_IDX_EMPTY;
+        }
+
+        leaf_idx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn red() -> Voxel {
+        Voxel::from_color(IVec3::new(255, 0, 0))
+    }
+
+    /// Compares every voxel of the tree with `expected`
+    fn check(tree: &VoxelTree, expected: impl Fn(IVec3) -> bool) {
+        let size = tree.size();
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    let pos = tree.origin + IVec3::new(x, y, z);
+                    let voxel = expected(pos).then_some(red());
+                    assert_eq!(tree.get_voxel(pos), voxel, "at {pos}");
+                }
+            }
+        }
+
+        assert!(tree.validate().is_ok());
+    }
+
+    #[test]
+    fn fill_box_and_clear() {
+        let mut tree = VoxelTree::new(3, 2);
+        tree.fill_box(IVec3::new(1, 0, 2), IVec3::new(8, 5, 4), red());
+        tree.fill_box(IVec3::new(2, 2, 2), IVec3::new(4, 4, 4), Voxel::empty());
+
+        check(&tree, |p| {
+            let filled = p.cmpge(IVec3::new(1, 0, 2)).all() && p.cmplt(IVec3::new(8, 5, 4)).all();
+            let cleared = p.cmpge(IVec3::splat(2)).all() && p.cmplt(IVec3::splat(4)).all();
+            filled && !cleared
+        });
+    }
+
+    #[test]
+    fn fill_box_is_clipped() {
+        let mut tree = VoxelTree::new_centered(3, 2);
+        tree.fill_box(IVec3::splat(-100), IVec3::splat(100), red());
+
+        check(&tree, |_| true);
+    }
+
+    #[test]
+    fn fill_sphere() {
+        let center = Vec3::new(8., 7.5, 8.25);
+        let mut tree = VoxelTree::new(4, 2);
+        tree.fill_sphere(center, 5.5, red());
+
+        check(&tree, |p| (p.as_vec3() + 0.5).distance(center) <= 5.5);
+    }
+
+    #[test]
+    fn fill_plane() {
+        let normal = Vec3::new(1., 2., 0.);
+        let mut tree = VoxelTree::new(3, 2);
+        tree.fill_plane(IVec3::ZERO, IVec3::new(8, 8, 5), normal, -6., red());
+
+        check(&tree, |p| {
+            let below = (p.as_vec3() + 0.5).dot(normal.normalize()) - 6. <= 0.;
+            below && p.z < 5
+        });
+    }
+}
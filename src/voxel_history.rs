@@ -0,0 +1,329 @@
+use std::collections::{HashMap, VecDeque};
+
+use bevy::prelude::*;
+
+use crate::voxel_tree::*;
+
+/// Default for `VoxelHistory::max_bytes`
+pub const VOXEL_HISTORY_MAX_BYTES: usize = 256 * 1024 * 1024;
+
+/// Voxels of a brick in `VoxelLeaf` order, `None` if the brick is empty
+pub type BrickVoxels = Option<Vec<Voxel>>;
+
+struct BrickEdit {
+    before: BrickVoxels,
+    after: BrickVoxels,
+}
+
+/// Bricks touched by a group of operations, by brick min in world coords
+#[derive(Default)]
+struct Transaction {
+    bricks: HashMap<IVec3, BrickEdit>,
+}
+
+impl Transaction {
+    fn bytes(&self) -> usize {
+        let size = |voxels: &BrickVoxels| {
+            voxels
+                .as_ref()
+                .map_or(0, |v| v.len() * std::mem::size_of::<Voxel>())
+        };
+        self.bricks
+            .values()
+            .map(|edit| size(&edit.before) + size(&edit.after))
+            .sum()
+    }
+}
+
+/// Edit journal of a `VoxelTree`. Operations run through `record` store the bricks the
+/// tree marks dirty before and after, `undo` and `redo` write them back. Operations between `begin`
+/// and `commit` are undone as one.
+pub struct VoxelHistory {
+    /// Oldest transactions are dropped once the stored bricks take more than that
+    pub max_bytes: usize,
+    undo: VecDeque<Transaction>,
+    redo: Vec<Transaction>,
+    pending: Option<Transaction>,
+    bytes: usize,
+}
+
+impl Default for VoxelHistory {
+    fn default() -> Self {
+        Self::new(VOXEL_HISTORY_MAX_BYTES)
+    }
+}
+
+impl VoxelHistory {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            pending: None,
+            bytes: 0,
+        }
+    }
+
+    /// Starts a transaction, operations are grouped until `commit`
+    pub fn begin(&mut self) {
+        assert!(self.pending.is_none(), "Transaction is already open");
+        self.pending = Some(Transaction::default());
+    }
+
+    pub fn commit(&mut self) {
+        let transaction = self.pending.take().expect("No open transaction");
+        self.push(transaction);
+    }
+
+    /// Runs `f` on `tree` and journals every brick it modifies, as reported by
+    /// `VoxelTree::mark_dirty`. Clears the redo history.
+    pub fn record<R>(&mut self, tree: &mut VoxelTree, f: impl FnOnce(&mut VoxelTree) -> R) -> R {
+        assert!(tree.journal.is_none(), "Tree is already being recorded");
+
+        let is_single = self.pending.is_none();
+
+        tree.journal = Some(HashMap::new());
+        let res = f(tree);
+        let journal = tree.journal.take().unwrap_or_default();
+
+        let transaction = self.pending.get_or_insert_with(Transaction::default);
+        for (pos, before) in journal {
+            let after = read_brick(tree, pos);
+            // Bricks touched again in the same transaction keep their first state
+            transaction
+                .bricks
+                .entry(pos)
+                .or_insert(BrickEdit {
+                    before,
+                    after: None,
+                })
+                .after = after;
+        }
+
+        self.clear_redo();
+
+        if is_single {
+            self.commit();
+        }
+
+        res
+    }
+
+    /// Reverts the last transaction, returns `false` if there is none
+    pub fn undo(&mut self, tree: &mut VoxelTree) -> bool {
+        assert!(self.pending.is_none(), "Can't undo inside a transaction");
+
+        let Some(transaction) = self.undo.pop_back() else {
+            return false;
+        };
+
+        for (&pos, edit) in &transaction.bricks {
+            write_brick(tree, pos, &edit.before);
+        }
+
+        self.redo.push(transaction);
+        true
+    }
+
+    /// Reapplies the last undone transaction, returns `false` if there is none
+    pub fn redo(&mut self, tree: &mut VoxelTree) -> bool {
+        assert!(self.pending.is_none(), "Can't redo inside a transaction");
+
+        let Some(transaction) = self.redo.pop() else {
+            return false;
+        };
+
+        for (&pos, edit) in &transaction.bricks {
+            write_brick(tree, pos, &edit.after);
+        }
+
+        self.undo.push_back(transaction);
+        true
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Size of the stored bricks
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.pending = None;
+        self.bytes = 0;
+    }
+
+    fn clear_redo(&mut self) {
+        for transaction in self.redo.drain(..) {
+            self.bytes -= transaction.bytes();
+        }
+    }
+
+    /// Drops the oldest transactions until the history fits into `max_bytes`,
+    /// a transaction larger than that can't be undone
+    fn push(&mut self, mut transaction: Transaction) {
+        transaction
+            .bricks
+            .retain(|_, edit| edit.before != edit.after);
+        if transaction.bricks.is_empty() {
+            return;
+        }
+
+        self.bytes += transaction.bytes();
+        self.undo.push_back(transaction);
+
+        while self.bytes > self.max_bytes {
+            let Some(oldest) = self.undo.pop_front() else {
+                break;
+            };
+            self.bytes -= oldest.bytes();
+        }
+    }
+}
+
+impl VoxelTree {
+    /// Saves the bricks intersecting `[min, max)` in local coords into `journal`,
+    /// unless they already are
+    pub fn journal_bricks(&mut self, min: IVec3, max: IVec3) {
+        let Some(mut journal) = self.journal.take() else {
+            return;
+        };
+
+        let min = min.max(IVec3::ZERO);
+        let max = max.min(IVec3::splat(self.size()));
+
+        let dim = self.dim as i32;
+        let bmin = min / dim;
+        let bmax = (max + dim - 1) / dim;
+        for x in bmin.x..bmax.x {
+            for y in bmin.y..bmax.y {
+                for z in bmin.z..bmax.z {
+                    let pos = self.origin + IVec3::new(x, y, z) * dim;
+                    journal.entry(pos).or_insert_with(|| read_brick(self, pos));
+                }
+            }
+        }
+
+        self.journal = Some(journal);
+    }
+}
+
+/// Only allocates once a voxel is found, most bricks of a large region are empty
+fn read_brick(tree: &VoxelTree, pos: IVec3) -> BrickVoxels {
+    let mut voxels: BrickVoxels = None;
+    for (voxel_pos, voxel) in tree.iter_voxels_in(pos, pos + tree.dim as i32) {
+        let voxels = voxels.get_or_insert_with(|| vec![Voxel::empty(); tree.voxel_count()]);
+        voxels[pos_to_idx(voxel_pos - pos, tree.dim) as usize] = voxel;
+    }
+
+    voxels
+}
+
+fn write_brick(tree: &mut VoxelTree, pos: IVec3, voxels: &BrickVoxels) {
+    let local = pos - tree.origin;
+    match voxels {
+        Some(voxels) => tree.update_leaf(local, |leaf| {
+            for (idx, &voxel) in voxels.iter().enumerate() {
+                leaf.set(idx, voxel);
+            }
+        }),
+        None => tree.clear_region(pos, pos + tree.dim as i32),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voxel(r: i32) -> Voxel {
+        Voxel::from_color(IVec3::new(r, 0, 0))
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut tree = VoxelTree::new(3, 2);
+        let mut history = VoxelHistory::default();
+        tree.set_voxel(IVec3::ONE, voxel(10)).unwrap();
+
+        history.record(&mut tree, |tree| {
+            tree.fill_sphere(Vec3::splat(4.), 3., voxel(20));
+        });
+        let filled = tree.get_voxel(IVec3::splat(4));
+        assert_eq!(filled, Some(voxel(20)));
+
+        assert!(history.undo(&mut tree));
+        assert_eq!(tree.get_voxel(IVec3::splat(4)), None);
+        assert_eq!(tree.get_voxel(IVec3::ONE), Some(voxel(10)));
+        assert!(!history.can_undo());
+
+        assert!(history.redo(&mut tree));
+        assert_eq!(tree.get_voxel(IVec3::splat(4)), filled);
+        assert!(!history.can_redo());
+        assert!(tree.validate().is_ok());
+    }
+
+    #[test]
+    fn record_clears_redo() {
+        let mut tree = VoxelTree::new(3, 2);
+        let mut history = VoxelHistory::default();
+
+        history.record(&mut tree, |tree| tree.set_voxel(IVec3::ZERO, voxel(10)));
+        history.undo(&mut tree);
+        history.record(&mut tree, |tree| tree.set_voxel(IVec3::ONE, voxel(20)));
+
+        assert!(!history.can_redo());
+        assert!(history.undo(&mut tree));
+        assert!(!history.can_undo());
+        assert_eq!(tree.iter_voxels().count(), 0);
+    }
+
+    #[test]
+    fn transaction_is_undone_at_once() {
+        let mut tree = VoxelTree::new(3, 2);
+        let mut history = VoxelHistory::default();
+
+        history.begin();
+        history.record(&mut tree, |tree| tree.set_voxel(IVec3::ZERO, voxel(10)));
+        history.record(&mut tree, |tree| tree.set_voxel(IVec3::ZERO, voxel(20)));
+        history.record(&mut tree, |tree| tree.set_voxel(IVec3::splat(7), voxel(30)));
+        history.commit();
+
+        assert!(history.undo(&mut tree));
+        assert!(!history.can_undo());
+        assert_eq!(tree.iter_voxels().count(), 0);
+
+        assert!(history.redo(&mut tree));
+        assert_eq!(tree.get_voxel(IVec3::ZERO), Some(voxel(20)));
+        assert_eq!(tree.get_voxel(IVec3::splat(7)), Some(voxel(30)));
+    }
+
+    #[test]
+    fn oldest_transactions_are_evicted() {
+        let mut tree = VoxelTree::new(3, 2);
+        // Each edit below stores a single brick after, nothing before
+        let brick_bytes = tree.voxel_count() * std::mem::size_of::<Voxel>();
+        let mut history = VoxelHistory::new(2 * brick_bytes);
+
+        for (i, pos) in [IVec3::ZERO, IVec3::splat(2), IVec3::splat(4)]
+            .into_iter()
+            .enumerate()
+        {
+            history.record(&mut tree, |tree| tree.set_voxel(pos, voxel(i as i32 + 1)));
+        }
+        assert_eq!(history.bytes(), 2 * brick_bytes);
+
+        assert!(history.undo(&mut tree));
+        assert!(history.undo(&mut tree));
+        assert!(!history.undo(&mut tree));
+        assert_eq!(tree.get_voxel(IVec3::ZERO), Some(voxel(1)));
+        assert_eq!(tree.iter_voxels().count(), 1);
+    }
+}
@@ -0,0 +1,496 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+};
+
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+};
+
+use crate::{voxel_tree::*, voxel_validate::TreeError};
+
+// Binary layout, all values are little endian:
+//
+// magic: [u8; 4]
+// version: u32
+// depth: u8
+// voxel_dim: u8
+// flags: u16
+// nodes_len: u32
+// leafs_len: u32
+// free_nodes_len: u32
+// free_leafs_len: u32
+// origin: [i32; 3]
+//
+// Followed by a stream of u32 words: `nodes[].leaf` and `nodes[].indices`, `leafs[].voxels`,
+// `free_nodes`, `free_leafs`.
+// With `VOXEL_TREE_FLAG_RLE` the stream is stored as `(run_len, value)` pairs.
+
+pub const VOXEL_TREE_MAGIC: [u8; 4] = *b"VXTR";
+pub const VOXEL_TREE_VERSION: u32 = 1;
+
+pub const VOXEL_TREE_FLAG_RLE: u16 = 1 << 0;
+
+#[derive(Debug)]
+pub enum VoxelTreeFormatError {
+    Io(io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    UnsupportedVoxelDim(u8),
+    Corrupted(&'static str),
+    /// The structure failed `VoxelTree::validate`
+    InvalidTree(Vec<TreeError>),
+}
+
+impl fmt::Display for VoxelTreeFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "io error: {err}"),
+            Self::InvalidMagic => write!(f, "not a voxel tree file"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported version: {v}"),
+            Self::UnsupportedVoxelDim(dim) => write!(f, "unsupported voxel dim: {dim}"),
+            Self::Corrupted(what) => write!(f, "corrupted file: {what}"),
+            Self::InvalidTree(errors) => {
+                write!(
+                    f,
+                    "invalid tree, {} errors, first: {}",
+                    errors.len(),
+                    errors[0]
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for VoxelTreeFormatError {}
+
+impl From<io::Error> for VoxelTreeFormatError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+struct WordWriter<W: Write> {
+    writer: W,
+    rle: bool,
+    run_value: u32,
+    run_len: u32,
+}
+
+impl<W: Write> WordWriter<W> {
+    fn new(writer: W, rle: bool) -> Self {
+        Self {
+            writer,
+            rle,
+            run_value: 0,
+            run_len: 0,
+        }
+    }
+
+    fn write(&mut self, word: u32) -> io::Result<()> {
+        if !self.rle {
+            return self.writer.write_all(&word.to_le_bytes());
+        }
+
+        if self.run_len > 0 && (self.run_value != word || self.run_len == u32::MAX) {
+            self.flush_run()?;
+        }
+
+        self.run_value = word;
+        self.run_len += 1;
+
+        Ok(())
+    }
+
+    fn flush_run(&mut self) -> io::Result<()> {
+        if self.run_len > 0 {
+            self.writer.write_all(&self.run_len.to_le_bytes())?;
+            self.writer.write_all(&self.run_value.to_le_bytes())?;
+            self.run_len = 0;
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.flush_run()?;
+        self.writer.flush()
+    }
+}
+
+struct WordReader<R: Read> {
+    reader: R,
+    rle: bool,
+    run_value: u32,
+    run_len: u32,
+    // Words declared by the header that no run has covered yet
+    remaining: u64,
+}
+
+impl<R: Read> WordReader<R> {
+    fn new(reader: R, rle: bool, len: u64) -> Self {
+        Self {
+            reader,
+            rle,
+            run_value: 0,
+            run_len: 0,
+            remaining: len,
+        }
+    }
+
+    fn read(&mut self) -> Result<u32, VoxelTreeFormatError> {
+        if !self.rle {
+            return Ok(read_u32(&mut self.reader)?);
+        }
+
+        if self.run_len == 0 {
+            self.run_len = read_u32(&mut self.reader)?;
+            self.run_value = read_u32(&mut self.reader)?;
+
+            if self.run_len == 0 {
+                return Err(VoxelTreeFormatError::Corrupted("zero length run"));
+            }
+
+            if self.run_len as u64 > self.remaining {
+                return Err(VoxelTreeFormatError::Corrupted("run past the end"));
+            }
+            self.remaining -= self.run_len as u64;
+        }
+
+        self.run_len -= 1;
+        Ok(self.run_value)
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0u8; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+impl VoxelTree {
+    pub fn save(&self, mut writer: impl Write, compress: bool) -> io::Result<()> {
+        let flags = if compress { VOXEL_TREE_FLAG_RLE } else { 0 };
+
+        writer.write_all(&VOXEL_TREE_MAGIC)?;
+        writer.write_all(&VOXEL_TREE_VERSION.to_le_bytes())?;
+        writer.write_all(&[self.depth, self.dim])?;
+        writer.write_all(&flags.to_le_bytes())?;
+        writer.write_all(&(self.nodes.len() as u32).to_le_bytes())?;
+        writer.write_all(&(self.leafs.len() as u32).to_le_bytes())?;
+        writer.write_all(&(self.free_nodes.len() as u32).to_le_bytes())?;
+        writer.write_all(&(self.free_leafs.len() as u32).to_le_bytes())?;
+        for v in self.origin.to_array() {
+            writer.write_all(&v.to_le_bytes())?;
+        }
+
+        let mut words = WordWriter::new(writer, compress);
+
+        for node in &self.nodes {
+            words.write(node.leaf)?;
+            for &idx in &node.indices {
+                words.write(idx)?;
+            }
+        }
+
+        for leaf in &self.leafs {
+            for voxel in &leaf.voxels {
+                words.write(voxel.data)?;
+            }
+        }
+
+        for &idx in self.free_nodes.iter().chain(&self.free_leafs) {
+            words.write(idx)?;
+        }
+
+        words.finish()
+    }
+
+    pub fn save_to_file(&self, path: impl AsRef<Path>, compress: bool) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.save(BufWriter::new(file), compress)
+    }
+
+    /// Trees that don't pass `validate` are rejected, indices from the file aren't trusted
+    pub fn load(mut reader: impl Read) -> Result<Self, VoxelTreeFormatError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != VOXEL_TREE_MAGIC {
+            return Err(VoxelTreeFormatError::InvalidMagic);
+        }
+
+        let version = read_u32(&mut reader)?;
+        if version != VOXEL_TREE_VERSION {
+            return Err(VoxelTreeFormatError::UnsupportedVersion(version));
+        }
+
+        let depth = read_u8(&mut reader)?;
+        let voxel_dim = read_u8(&mut reader)?;
+        if voxel_dim < 2 {
+            return Err(VoxelTreeFormatError::UnsupportedVoxelDim(voxel_dim));
+        }
+
+        if !is_valid_tree_dims(depth, voxel_dim) {
+            return Err(VoxelTreeFormatError::Corrupted("depth"));
+        }
+
+        let flags = read_u16(&mut reader)?;
+        let nodes_len = read_u32(&mut reader)?;
+        let leafs_len = read_u32(&mut reader)?;
+        let free_nodes_len = read_u32(&mut reader)?;
+        let free_leafs_len = read_u32(&mut reader)?;
+
+        let mut origin = IVec3::ZERO;
+        for axis in 0..3 {
+            origin[axis] = read_u32(&mut reader)? as i32;
+        }
+
+        let size = (voxel_dim as i32).pow(depth as u32);
+        if origin
+            .to_array()
+            .iter()
+            .any(|v| v.checked_add(size).is_none())
+        {
+            return Err(VoxelTreeFormatError::Corrupted("origin"));
+        }
+
+        if nodes_len == 0 {
+            return Err(VoxelTreeFormatError::Corrupted("no root node"));
+        }
+
+        // Lengths are checked before anything is allocated for them
+        let (max_nodes, max_leafs) = max_bricks(depth, voxel_dim);
+        if nodes_len as u64 > max_nodes || free_nodes_len > nodes_len {
+            return Err(VoxelTreeFormatError::Corrupted("nodes length"));
+        }
+        if leafs_len as u64 > max_leafs || free_leafs_len > leafs_len {
+            return Err(VoxelTreeFormatError::Corrupted("leafs length"));
+        }
+
+        let count = voxel_count(voxel_dim) as u64;
+        let words_len = nodes_len as u64 * (1 + count)
+            + leafs_len as u64 * count
+            + free_nodes_len as u64
+            + free_leafs_len as u64;
+
+        let mut stream = Vec::new();
+        reader.read_to_end(&mut stream)?;
+
+        let rle = flags & VOXEL_TREE_FLAG_RLE != 0;
+        if !rle && stream.len() as u64 != words_len * 4 {
+            return Err(VoxelTreeFormatError::Corrupted("length"));
+        }
+
+        let mut words = WordReader::new(stream.as_slice(), rle, words_len);
+
+        let mut tree = VoxelTree {
+            depth,
+            dim: voxel_dim,
+            origin,
+            ..default()
+        };
+
+        for _ in 0..nodes_len {
+            let mut node = VoxelNode::new(voxel_dim);
+            node.leaf = words.read()?;
+            for idx in node.indices.iter_mut() {
+                *idx = words.read()?;
+            }
+            node.update_mask();
+            tree.nodes.push(node);
+        }
+
+        for _ in 0..leafs_len {
+            let mut leaf = VoxelLeaf::new(voxel_dim);
+            for voxel in leaf.voxels.iter_mut() {
+                voxel.data = words.read()?;
+            }
+            leaf.update_mask();
+            tree.leafs.push(leaf);
+        }
+
+        for _ in 0..free_nodes_len {
+            tree.free_nodes.push(words.read()?);
+        }
+
+        for _ in 0..free_leafs_len {
+            tree.free_leafs.push(words.read()?);
+        }
+
+        tree.update_refs();
+        tree.validate().map_err(VoxelTreeFormatError::InvalidTree)?;
+
+        Ok(tree)
+    }
+}
+
+/// Most nodes and leafs a tree of `depth` and `dim` can hold, LOD bricks included
+fn max_bricks(depth: u8, dim: u8) -> (u64, u64) {
+    let count = voxel_count(dim) as u64;
+
+    let mut nodes = 0u64;
+    let mut level = 1u64;
+    for _ in 0..depth - 1 {
+        nodes = nodes.saturating_add(level);
+        level = level.saturating_mul(count);
+    }
+
+    (nodes, level.saturating_add(nodes))
+}
+
+/// Loads `*.voxtree` files written by [`VoxelTree::save`]
+#[derive(Default)]
+pub struct VoxelTreeLoader;
+
+impl AssetLoader for VoxelTreeLoader {
+    type Asset = VoxelTree;
+    type Settings = ();
+    type Error = VoxelTreeFormatError;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+
+        VoxelTree::load(bytes.as_slice())
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["voxtree"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Header bytes before the word stream, see the layout above
+    const HEADER_LEN: usize = 40;
+
+    fn saved_tree() -> Vec<u8> {
+        let mut tree = VoxelTree::new(2, 2);
+        tree.set_voxel(IVec3::ZERO, Voxel::from_color(IVec3::splat(255)))
+            .unwrap();
+
+        let mut bytes = Vec::new();
+        tree.save(&mut bytes, false).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn load_roundtrip() {
+        let tree = VoxelTree::load(saved_tree().as_slice()).unwrap();
+
+        assert_eq!(
+            tree.get_voxel(IVec3::ZERO),
+            Some(Voxel::from_color(IVec3::splat(255)))
+        );
+        assert_eq!(tree.get_voxel(IVec3::ONE), None);
+    }
+
+    fn header(version: u32, flags: u16, nodes_len: u32, leafs_len: u32) -> Vec<u8> {
+        let mut bytes = VOXEL_TREE_MAGIC.to_vec();
+        bytes.extend(version.to_le_bytes());
+        bytes.extend([2, 2]);
+        bytes.extend(flags.to_le_bytes());
+        for len in [nodes_len, leafs_len, 0, 0, 0, 0, 0] {
+            bytes.extend(len.to_le_bytes());
+        }
+        assert_eq!(bytes.len(), HEADER_LEN);
+        bytes
+    }
+
+    #[test]
+    fn load_rejects_other_versions() {
+        for version in [0u32, 2, 3] {
+            let mut bytes = saved_tree();
+            bytes[4..8].copy_from_slice(&version.to_le_bytes());
+
+            let res = VoxelTree::load(bytes.as_slice());
+            assert!(
+                matches!(res, Err(VoxelTreeFormatError::UnsupportedVersion(v)) if v == version),
+                "{res:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn load_rejects_lengths_the_tree_cant_hold() {
+        // A depth 2 tree has one node and at most 8 leafs plus the root LOD
+        let mut bytes = header(VOXEL_TREE_VERSION, VOXEL_TREE_FLAG_RLE, u32::MAX, 0);
+        bytes.extend(u32::MAX.to_le_bytes());
+        bytes.extend(0u32.to_le_bytes());
+
+        let res = VoxelTree::load(bytes.as_slice());
+        assert!(
+            matches!(res, Err(VoxelTreeFormatError::Corrupted("nodes length"))),
+            "{res:?}"
+        );
+    }
+
+    #[test]
+    fn load_rejects_runs_past_the_end() {
+        let mut bytes = header(VOXEL_TREE_VERSION, VOXEL_TREE_FLAG_RLE, 1, 9);
+        bytes.extend(u32::MAX.to_le_bytes());
+        bytes.extend(0u32.to_le_bytes());
+
+        let res = VoxelTree::load(bytes.as_slice());
+        assert!(
+            matches!(
+                res,
+                Err(VoxelTreeFormatError::Corrupted("run past the end"))
+            ),
+            "{res:?}"
+        );
+    }
+
+    #[test]
+    fn load_rejects_truncated_stream() {
+        let mut bytes = saved_tree();
+        bytes.truncate(bytes.len() - 4);
+
+        let res = VoxelTree::load(bytes.as_slice());
+        assert!(
+            matches!(res, Err(VoxelTreeFormatError::Corrupted("length"))),
+            "{res:?}"
+        );
+    }
+
+    #[test]
+    fn load_rejects_child_out_of_range() {
+        let mut bytes = saved_tree();
+        // `nodes[0].indices[0]`, after `nodes[0].leaf`
+        let offset = HEADER_LEN + 4;
+        bytes[offset..offset + 4].copy_from_slice(&7u32.to_le_bytes());
+
+        let res = VoxelTree::load(bytes.as_slice());
+
+        let Err(VoxelTreeFormatError::InvalidTree(errors)) = res else {
+            panic!("Expected an invalid tree, got {res:?}");
+        };
+        assert!(errors.contains(&TreeError::ChildOutOfRange {
+            node: 0,
+            slot: 0,
+            child: 7,
+        }));
+    }
+}
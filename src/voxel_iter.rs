@@ -0,0 +1,284 @@
+use bevy::prelude::*;
+
+use crate::voxel_tree::*;
+
+/// Brick reached by `TreeWalk`, positions are world voxels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WalkItem {
+    Node {
+        idx: u32,
+        origin: IVec3,
+        depth: u8,
+    },
+    Leaf {
+        idx: u32,
+        origin: IVec3,
+    },
+    // Slot without a child but with a LOD voxel, see `VoxelTree::is_collapsed`
+    Collapsed {
+        origin: IVec3,
+        size: i32,
+        voxel: Voxel,
+    },
+}
+
+/// Depth-first walk over the bricks intersecting `[min, max)`
+pub struct TreeWalk<'a> {
+    tree: &'a VoxelTree,
+    min: IVec3,
+    max: IVec3,
+    // (node_idx, depth, origin, next slot)
+    stack: Vec<(u32, u8, IVec3, usize)>,
+}
+
+impl<'a> TreeWalk<'a> {
+    pub fn new(tree: &'a VoxelTree, min: IVec3, max: IVec3) -> Self {
+        assert_ne!(tree.depth, 0);
+
+        let min = min.max(tree.min());
+        let max = max.min(tree.max());
+
+        let mut stack = Vec::with_capacity(tree.depth as usize);
+        if min.cmplt(max).all() {
+            stack.push((0, 0, tree.origin, 0));
+        }
+
+        Self {
+            tree,
+            min,
+            max,
+            stack,
+        }
+    }
+}
+
+impl<'a> Iterator for TreeWalk<'a> {
+    type Item = WalkItem;
+
+    fn next(&mut self) -> Option<WalkItem> {
+        loop {
+            let (node_idx, depth, origin, slot) = self.stack.last_mut()?;
+            if *slot == self.tree.voxel_count() {
+                self.stack.pop();
+                continue;
+            }
+
+            let idx = *slot;
+            *slot += 1;
+
+            let (node_idx, depth, origin) = (*node_idx, *depth, *origin);
+            let child_size = (self.tree.dim as i32).pow((self.tree.depth - 1 - depth) as u32);
+            let child_min = origin + idx_to_pos(idx as i32, self.tree.dim) * child_size;
+            let child_max = child_min + child_size;
+
+            if child_max.cmple(self.min).any() || child_min.cmpge(self.max).any() {
+                continue;
+            }
+
+            let node = &self.tree.nodes[node_idx as usize];
+            if !get_mask(&node.mask, idx as i32) {
+                let voxel = self.tree.lod_voxel(node_idx, idx);
+                if voxel.is_empty() {
+                    continue;
+                }
+
+                return Some(WalkItem::Collapsed {
+                    origin: child_min,
+                    size: child_size,
+                    voxel,
+                });
+            }
+
+            let child_idx = node.indices[idx];
+            if depth == self.tree.depth - 2 {
+                return Some(WalkItem::Leaf {
+                    idx: child_idx,
+                    origin: child_min,
+                });
+            }
+
+            self.stack.push((child_idx, depth + 1, child_min, 0));
+            return Some(WalkItem::Node {
+                idx: child_idx,
+                origin: child_min,
+                depth: depth + 1,
+            });
+        }
+    }
+}
+
+impl VoxelTree {
+    /// Every non-empty voxel with its position, voxels of collapsed bricks included
+    pub fn iter_voxels(&self) -> impl Iterator<Item = (IVec3, Voxel)> + '_ {
+        self.iter_voxels_in(self.min(), self.max())
+    }
+
+    /// Every non-empty voxel in `[min, max)` with its position
+    pub fn iter_voxels_in(
+        &self,
+        min: IVec3,
+        max: IVec3,
+    ) -> impl Iterator<Item = (IVec3, Voxel)> + '_ {
+        TreeWalk::new(self, min, max).flat_map(
+            move |item| -> Box<dyn Iterator<Item = (IVec3, Voxel)> + '_> {
+                match item {
+                    WalkItem::Node { .. } => Box::new(std::iter::empty()),
+                    WalkItem::Leaf { idx, origin } => {
+                        let leaf = &self.leafs[idx as usize];
+                        Box::new(
+                            mask_iter(&leaf.mask)
+                                .map(move |i| {
+                                    (origin + idx_to_pos(i as i32, self.dim), leaf.voxels[i])
+                                })
+                                .filter(move |(pos, _)| {
+                                    pos.cmpge(min).all() && pos.cmplt(max).all()
+                                }),
+                        )
+                    }
+                    WalkItem::Collapsed {
+                        origin,
+                        size,
+                        voxel,
+                    } => {
+                        let lmin = origin.max(min);
+                        let lmax = (origin + size).min(max);
+                        Box::new((lmin.x..lmax.x).flat_map(move |x| {
+                            (lmin.y..lmax.y).flat_map(move |y| {
+                                (lmin.z..lmax.z).map(move |z| (IVec3::new(x, y, z), voxel))
+                            })
+                        }))
+                    }
+                }
+            },
+        )
+    }
+
+    /// Every allocated leaf with the position of its min corner.
+    /// Collapsed bricks have no leaf and are skipped.
+    pub fn iter_leaves(&self) -> impl Iterator<Item = (IVec3, &VoxelLeaf)> + '_ {
+        self.iter_leaves_in(self.min(), self.max())
+    }
+
+    /// Every allocated leaf intersecting `[min, max)` with the position of its min corner
+    pub fn iter_leaves_in(
+        &self,
+        min: IVec3,
+        max: IVec3,
+    ) -> impl Iterator<Item = (IVec3, &VoxelLeaf)> + '_ {
+        TreeWalk::new(self, min, max).filter_map(|item| match item {
+            WalkItem::Leaf { idx, origin } => Some((origin, &self.leafs[idx as usize])),
+            _ => None,
+        })
+    }
+
+    /// Every node with the position of its min corner and its depth, the root included.
+    /// A node at `depth` spans `self.dim^(self.depth - depth)` voxels along each axis.
+    pub fn iter_nodes(&self) -> impl Iterator<Item = (IVec3, u8, &VoxelNode)> + '_ {
+        self.iter_nodes_in(self.min(), self.max())
+    }
+
+    /// Every node intersecting `[min, max)`, see `iter_nodes`
+    pub fn iter_nodes_in(
+        &self,
+        min: IVec3,
+        max: IVec3,
+    ) -> impl Iterator<Item = (IVec3, u8, &VoxelNode)> + '_ {
+        let walk = TreeWalk::new(self, min, max);
+        let root = (!walk.stack.is_empty()).then(|| (self.origin, 0, &self.nodes[0]));
+
+        root.into_iter().chain(walk.filter_map(|item| match item {
+            WalkItem::Node { idx, origin, depth } => {
+                Some((origin, depth, &self.nodes[idx as usize]))
+            }
+            _ => None,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn red() -> Voxel {
+        Voxel::from_color(IVec3::new(255, 0, 0))
+    }
+
+    fn leaf_origins(tree: &VoxelTree, min: IVec3, max: IVec3) -> Vec<IVec3> {
+        TreeWalk::new(tree, min, max)
+            .filter_map(|item| match item {
+                WalkItem::Leaf { origin, .. } => Some(origin),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn walk_is_clipped_to_region() {
+        let mut tree = VoxelTree::new_centered(3, 2);
+        for pos in [IVec3::splat(-4), IVec3::ZERO, IVec3::splat(3)] {
+            tree.set_voxel(pos, red()).unwrap();
+        }
+
+        assert_eq!(
+            leaf_origins(&tree, tree.min(), tree.max()).len(),
+            3,
+            "every leaf of the tree"
+        );
+        assert_eq!(
+            leaf_origins(&tree, IVec3::splat(-1), IVec3::splat(1)),
+            vec![IVec3::ZERO]
+        );
+
+        let nodes: Vec<_> = TreeWalk::new(&tree, IVec3::ZERO, IVec3::splat(2))
+            .filter_map(|item| match item {
+                WalkItem::Node { origin, depth, .. } => Some((origin, depth)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(nodes, vec![(IVec3::ZERO, 1)]);
+
+        // The leaf at zero intersects the region, its voxel doesn't
+        let voxels: Vec<_> = tree
+            .iter_voxels_in(IVec3::ONE, IVec3::splat(100))
+            .map(|(pos, _)| pos)
+            .collect();
+        assert_eq!(voxels, vec![IVec3::splat(3)]);
+    }
+
+    #[test]
+    fn walk_of_empty_region_is_empty() {
+        let mut tree = VoxelTree::new(3, 2);
+        tree.set_voxel(IVec3::ONE, red()).unwrap();
+
+        assert_eq!(TreeWalk::new(&tree, IVec3::ONE, IVec3::ONE).count(), 0);
+        assert_eq!(
+            TreeWalk::new(&tree, IVec3::splat(8), IVec3::splat(16)).count(),
+            0
+        );
+        assert_eq!(tree.iter_nodes_in(IVec3::splat(-8), IVec3::ZERO).count(), 0);
+    }
+
+    #[test]
+    fn walk_yields_collapsed_bricks() {
+        let mut tree = VoxelTree::new(3, 2);
+        tree.fill_box(IVec3::ZERO, IVec3::splat(4), red());
+        tree.build_lods();
+
+        let items: Vec<_> = TreeWalk::new(&tree, tree.min(), tree.max()).collect();
+        assert_eq!(
+            items,
+            vec![WalkItem::Collapsed {
+                origin: IVec3::ZERO,
+                size: 4,
+                voxel: red(),
+            }]
+        );
+        assert_eq!(tree.iter_leaves().count(), 0);
+
+        // Voxels of collapsed bricks are clipped to the region too
+        let voxels: Vec<_> = tree
+            .iter_voxels_in(IVec3::splat(3), IVec3::splat(8))
+            .collect();
+        assert_eq!(voxels, vec![(IVec3::splat(3), red())]);
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::voxel_tree::*;
+
+/// Child of a node after LOD generation, the same as `DrawResult` in `voxel_write.wgsl`
+#[derive(Clone, Copy, Debug)]
+struct LodResult {
+    // To `leafs` or `nodes`, `VOXEL_IDX_EMPTY` if the brick is empty or collapsed
+    idx: u32,
+    // Mean color and majority material of the brick
+    value: Voxel,
+}
+
+impl LodResult {
+    fn empty() -> Self {
+        Self {
+            idx: VOXEL_IDX_EMPTY,
+            value: Voxel::empty(),
+        }
+    }
+}
+
+// Results of the bricks already built by one `build_lods`
+#[derive(Default)]
+struct LodCache {
+    nodes: HashMap<u32, LodResult>,
+    leafs: HashMap<u32, LodResult>,
+}
+
+impl VoxelTree {
+    /// Rebuilds the LOD brick of every node bottom-up, the same way as `draw_end` and `draw_nodes`
+    /// from `draw.wgsl`:
+    /// - the LOD voxel of a child has the mean color and the most common material of its
+    ///   occupied cells, the lowest material wins ties
+    /// - empty bricks are released
+    /// - bricks where every cell holds the same voxel and nothing is subdivided are released
+    ///   and only kept as the LOD voxel of their parent
+    ///
+    /// The root node always stays and always gets a LOD brick. Bricks shared by `dedup`
+    /// are built once, the other parents reuse the result and drop their reference if
+    /// the brick was released. The whole tree is marked dirty.
+    pub fn build_lods(&mut self) {
+        assert_ne!(self.depth, 0);
+
+        self.mark_all_dirty();
+        self.build_lod_node(0, 0, &mut LodCache::default());
+    }
+
+    fn build_lod_node(&mut self, node_idx: u32, depth: u8, cache: &mut LodCache) -> LodResult {
+        if let Some(&res) = cache.nodes.get(&node_idx) {
+            if res.idx == VOXEL_IDX_EMPTY {
+                self.free_node(node_idx);
+            }
+            return res;
+        }
+
+        let mut values = vec![Voxel::empty(); self.voxel_count()];
+
+        for (idx, value) in values.iter_mut().enumerate() {
+            if !get_mask(&self.nodes[node_idx as usize].mask, idx as i32) {
+                // Collapsed bricks keep their LOD voxel
+                *value = self.lod_voxel(node_idx, idx);
+                continue;
+            }
+
+            let child_idx = self.nodes[node_idx as usize].indices[idx];
+            let res = if depth == self.depth - 2 {
+                self.build_lod_leaf(child_idx, cache)
+            } else {
+                self.build_lod_node(child_idx, depth + 1, cache)
+            };
+
+            self.nodes[node_idx as usize].set(idx, res.idx);
+            *value = res.value;
+        }
+
+        let res = self.finish_lod_node(node_idx, values);
+        cache.nodes.insert(node_idx, res);
+        res
+    }
+
+    fn finish_lod_node(&mut self, node_idx: u32, values: Vec<Voxel>) -> LodResult {
+        let stats = LodStats::new(&values);
+        let is_divided = !self.nodes[node_idx as usize].is_empty();
+
+        if node_idx != 0 {
+            if stats.num_occupied == 0 {
+                self.free_node(node_idx);
+                return LodResult::empty();
+            }
+
+            if stats.is_uniform && !is_divided {
+                self.free_node(node_idx);
+                return LodResult {
+                    idx: VOXEL_IDX_EMPTY,
+                    value: stats.mean,
+                };
+            }
+        }
+
+        let mut lod_idx = self.nodes[node_idx as usize].leaf;
+        if lod_idx != VOXEL_IDX_EMPTY && self.leafs[lod_idx as usize].voxels != values {
+            lod_idx = self.unshare_lod(node_idx);
+        }
+        if lod_idx == VOXEL_IDX_EMPTY {
+            lod_idx = self.alloc_leaf();
+            self.nodes[node_idx as usize].leaf = lod_idx;
+        }
+
+        let lod = &mut self.leafs[lod_idx as usize];
+        lod.voxels = values;
+        lod.update_mask();
+
+        LodResult {
+            idx: node_idx,
+            value: stats.mean,
+        }
+    }
+
+    fn build_lod_leaf(&mut self, leaf_idx: u32, cache: &mut LodCache) -> LodResult {
+        if let Some(&res) = cache.leafs.get(&leaf_idx) {
+            if res.idx == VOXEL_IDX_EMPTY {
+                self.free_leaf(leaf_idx);
+            }
+            return res;
+        }
+
+        let res = self.finish_lod_leaf(leaf_idx);
+        cache.leafs.insert(leaf_idx, res);
+        res
+    }
+
+    fn finish_lod_leaf(&mut self, leaf_idx: u32) -> LodResult {
+        let stats = LodStats::new(&self.leafs[leaf_idx as usize].voxels);
+
+        if stats.num_occupied == 0 {
+            self.free_leaf(leaf_idx);
+            return LodResult::empty();
+        }
+
+        if stats.is_uniform {
+            self.free_leaf(leaf_idx);
+            return LodResult {
+                idx: VOXEL_IDX_EMPTY,
+                value: stats.mean,
+            };
+        }
+
+        LodResult {
+            idx: leaf_idx,
+            value: stats.mean,
+        }
+    }
+}
+
+struct LodStats {
+    num_occupied: u32,
+    // Every cell holds the same value, compared with the payload like `num_different`
+    is_uniform: bool,
+    mean: Voxel,
+}
+
+impl LodStats {
+    fn new(voxels: &[Voxel]) -> Self {
+        let mut num_occupied = 0;
+        let mut sum = UVec3::ZERO;
+        let mut material_counts = [0u32; 256];
+
+        for voxel in voxels {
+            if !voxel.is_empty() {
+                num_occupied += 1;
+                sum += unpack_color(voxel.data);
+                material_counts[voxel.material() as usize] += 1;
+            }
+        }
+
+        let is_uniform = voxels.iter().all(|v| *v == voxels[0]);
+
+        let mean = if num_occupied > 0 {
+            let mean = sum.as_vec3() / num_occupied as f32 / 255.;
+            // Same tie-break as the `atomicMax` in `draw.wgsl`
+            let material = (0..=u8::MAX)
+                .max_by_key(|&m| (material_counts[m as usize], std::cmp::Reverse(m)))
+                .unwrap();
+            Voxel {
+                data: pack_color(mean),
+            }
+            .with_material(material)
+        } else {
+            Voxel::empty()
+        };
+
+        Self {
+            num_occupied,
+            is_uniform,
+            mean,
+        }
+    }
+}
+
+// `vec3u(unpack4x8unorm(data).xyz * 255.f)`
+fn unpack_color(data: u32) -> UVec3 {
+    let channel = |shift: u32| ((((data >> shift) & 0xff) as f32 / 255.) * 255.) as u32;
+    UVec3::new(channel(0), channel(8), channel(16))
+}
+
+// `pack4x8unorm(vec4f(color, 0.))`, the payload byte is left zero for the material
+fn pack_color(color: Vec3) -> u32 {
+    let channel = |v: f32| (0.5 + v.clamp(0., 1.) * 255.).floor() as u32;
+    channel(color.x) | (channel(color.y) << 8) | (channel(color.z) << 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: IVec3 = IVec3::new(255, 0, 0);
+    const BLUE: IVec3 = IVec3::new(0, 0, 255);
+
+    fn fill(tree: &mut VoxelTree, min: IVec3, max: IVec3, voxel: Voxel) {
+        for x in min.x..max.x {
+            for y in min.y..max.y {
+                for z in min.z..max.z {
+                    tree.set_voxel(IVec3::new(x, y, z), voxel).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shared_bricks_keep_content() {
+        let mut tree = VoxelTree::new(3, 2);
+        let red = Voxel::new(RED, 1);
+        fill(&mut tree, IVec3::ZERO, IVec3::splat(4), red);
+        fill(&mut tree, IVec3::new(4, 0, 0), IVec3::new(8, 4, 4), red);
+
+        tree.dedup();
+        tree.build_lods();
+
+        for x in 0..8 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    assert_eq!(tree.get_voxel(IVec3::new(x, y, z)), Some(red));
+                }
+            }
+        }
+        assert!(tree.validate().is_ok());
+    }
+
+    #[test]
+    fn lod_voxel_is_mean_color_and_majority_material() {
+        let mut tree = VoxelTree::new(2, 2);
+        let red = Voxel::new(RED, 1);
+        let blue = Voxel::new(BLUE, 2);
+        tree.set_voxel(IVec3::new(0, 0, 0), red).unwrap();
+        tree.set_voxel(IVec3::new(0, 0, 1), red).unwrap();
+        tree.set_voxel(IVec3::new(0, 1, 0), blue).unwrap();
+
+        tree.build_lods();
+
+        let lod = tree.lod_voxel(0, 0);
+        assert_eq!(lod, Voxel::new(IVec3::new(170, 0, 85), 1));
+        // The brick isn't uniform and keeps its leaf
+        assert!(get_mask(&tree.nodes[0].mask, 0));
+        assert!(!tree.is_collapsed(0, 0));
+    }
+
+    #[test]
+    fn lod_material_ties_go_to_the_lowest() {
+        let mut tree = VoxelTree::new(2, 2);
+        let a = Voxel::new(RED, 3);
+        let b = Voxel::new(RED, 2);
+        tree.set_voxel(IVec3::new(0, 0, 0), a).unwrap();
+        tree.set_voxel(IVec3::new(0, 0, 1), b).unwrap();
+
+        tree.build_lods();
+
+        assert_eq!(tree.lod_voxel(0, 0), b);
+    }
+
+    #[test]
+    fn uniform_leaf_collapses() {
+        let mut tree = VoxelTree::new(2, 2);
+        let voxel = Voxel::new(BLUE, 1);
+        fill(&mut tree, IVec3::ZERO, IVec3::splat(2), voxel);
+        // A second brick that stays
+        tree.set_voxel(IVec3::new(2, 2, 2), voxel).unwrap();
+
+        tree.build_lods();
+
+        assert!(tree.is_collapsed(0, 0));
+        assert_eq!(tree.nodes[0].indices[0], VOXEL_IDX_EMPTY);
+        // The other brick and the root LOD, the collapsed leaf was released
+        assert_eq!(tree.leafs.len() - tree.free_leafs.len(), 2);
+        assert_eq!(tree.lod_voxel(0, 0), voxel);
+
+        let idx = pos_to_idx(IVec3::ONE, tree.dim) as usize;
+        assert!(get_mask(&tree.nodes[0].mask, idx as i32));
+        assert!(!tree.is_collapsed(0, idx));
+
+        for pos in [IVec3::ZERO, IVec3::new(1, 0, 1), IVec3::ONE] {
+            assert_eq!(tree.get_voxel(pos), Some(voxel));
+        }
+        assert_eq!(tree.get_voxel(IVec3::new(2, 0, 0)), None);
+    }
+
+    #[test]
+    fn uniform_node_collapses_into_the_root() {
+        let mut tree = VoxelTree::new(3, 2);
+        let voxel = Voxel::new(RED, 2);
+        fill(&mut tree, IVec3::ZERO, IVec3::splat(4), voxel);
+
+        tree.build_lods();
+
+        // Every leaf collapses first, then the node holding them
+        assert!(tree.is_collapsed(0, 0));
+        assert!(tree.nodes[0].is_empty());
+        assert_eq!(tree.lod_voxel(0, 0), voxel);
+        assert_eq!(tree.get_voxel(IVec3::new(3, 1, 2)), Some(voxel));
+        assert_eq!(tree.get_voxel(IVec3::new(4, 0, 0)), None);
+    }
+
+    #[test]
+    fn empty_tree_keeps_an_empty_root_lod() {
+        let mut tree = VoxelTree::new(2, 2);
+        tree.build_lods();
+
+        assert_ne!(tree.nodes[0].leaf, VOXEL_IDX_EMPTY);
+        assert!(tree.is_node_empty(0));
+    }
+}
@@ -0,0 +1,76 @@
+use bevy::{
+    prelude::*,
+    render::{extract_resource::ExtractResource, render_resource::ShaderType},
+};
+
+/// Number of materials addressable by the 8-bit payload of `Voxel`. Material 255 is reserved,
+/// a white voxel with it would pack to `VOXEL_IDX_EMPTY`.
+pub const VOXEL_MATERIALS_LEN: usize = 255;
+
+pub const VOXEL_MATERIAL_DEFAULT: u8 = 0;
+pub const VOXEL_MATERIAL_STONE: u8 = 1;
+pub const VOXEL_MATERIAL_METAL: u8 = 2;
+pub const VOXEL_MATERIAL_GLOWING: u8 = 3;
+
+/// Applied to the `PbrInput` in `voxel_prepass.wgsl`, the base color comes from the voxel
+#[derive(Clone, Copy, Debug, PartialEq, ShaderType, Reflect)]
+pub struct VoxelMaterial {
+    pub perceptual_roughness: f32,
+    pub metallic: f32,
+    /// Emitted light is the voxel color multiplied by it
+    pub emissive: f32,
+    pub reflectance: f32,
+}
+
+impl Default for VoxelMaterial {
+    fn default() -> Self {
+        Self {
+            perceptual_roughness: 0.9,
+            metallic: 0.,
+            emissive: 0.,
+            reflectance: 0.5,
+        }
+    }
+}
+
+/// Material table indexed by `Voxel::material`, missing entries fall back to the default one
+#[derive(Resource, ExtractResource, Clone, Debug, Reflect)]
+pub struct VoxelMaterials {
+    pub materials: Vec<VoxelMaterial>,
+}
+
+impl Default for VoxelMaterials {
+    fn default() -> Self {
+        let mut materials = vec![VoxelMaterial::default(); 4];
+        materials[VOXEL_MATERIAL_STONE as usize] = VoxelMaterial {
+            perceptual_roughness: 1.,
+            reflectance: 0.3,
+            ..default()
+        };
+        materials[VOXEL_MATERIAL_METAL as usize] = VoxelMaterial {
+            perceptual_roughness: 0.25,
+            metallic: 1.,
+            ..default()
+        };
+        materials[VOXEL_MATERIAL_GLOWING as usize] = VoxelMaterial {
+            emissive: 1000.,
+            ..default()
+        };
+
+        Self { materials }
+    }
+}
+
+impl VoxelMaterials {
+    /// 256 entries including the reserved one, so any payload is a valid index on the GPU
+    pub fn gpu_table(&self) -> Vec<VoxelMaterial> {
+        assert!(
+            self.materials.len() <= VOXEL_MATERIALS_LEN,
+            "At most {VOXEL_MATERIALS_LEN} voxel materials are supported"
+        );
+
+        let mut res = self.materials.clone();
+        res.resize(VOXEL_MATERIALS_LEN + 1, VoxelMaterial::default());
+        res
+    }
+}
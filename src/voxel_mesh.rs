@@ -0,0 +1,303 @@
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+    },
+};
+
+use crate::{voxel_iter::*, voxel_tree::*};
+
+#[derive(Default)]
+struct MeshBuilder {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    colors: Vec<[f32; 4]>,
+    indices: Vec<u32>,
+}
+
+impl MeshBuilder {
+    /// Face rectangle `[i, i + w) x [j, j + h)` on the `u` and `v` axes of `axis`,
+    /// on the `sign` side of the voxels at `slice`
+    fn quad(
+        &mut self,
+        brick_min: IVec3,
+        axis: usize,
+        sign: i32,
+        slice: i32,
+        rect: (i32, i32, i32, i32),
+        voxel: Voxel,
+    ) {
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let (i, j, w, h) = rect;
+
+        let base = self.positions.len() as u32;
+        for (du, dv) in [(0, 0), (w, 0), (w, h), (0, h)] {
+            let mut pos = brick_min;
+            pos[axis] += slice + (sign > 0) as i32;
+            pos[u] += i + du;
+            pos[v] += j + dv;
+            self.positions.push(pos.as_vec3().to_array());
+        }
+
+        let mut normal = Vec3::ZERO;
+        normal[axis] = sign as f32;
+        self.normals.extend([normal.to_array(); 4]);
+
+        self.colors.extend([voxel.color().extend(1.).to_array(); 4]);
+
+        // `u x v` points along `axis`, flip the winding for the negative side
+        if sign > 0 {
+            self.indices
+                .extend([base, base + 1, base + 2, base, base + 2, base + 3]);
+        } else {
+            self.indices
+                .extend([base, base + 2, base + 1, base, base + 3, base + 2]);
+        }
+    }
+
+    fn build(self) -> Mesh {
+        Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, self.positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, self.normals)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, self.colors)
+        .with_inserted_indices(Indices::U32(self.indices))
+    }
+}
+
+impl VoxelTree {
+    /// Greedy meshed surface of the whole tree, see `to_mesh_in`
+    pub fn to_mesh(&self) -> Mesh {
+        self.to_mesh_in(self.min(), self.max())
+    }
+
+    /// Greedy meshed surface of the voxels in `[min, max)`, in world coords with
+    /// `VOXEL_SIZE` of 1. Faces get flat normals and the voxel color as vertex color.
+    ///
+    /// Every brick is meshed on its own, faces between bricks are culled against the
+    /// neighbor brick. Voxels outside of the region count as empty, so the mesh is
+    /// closed at the region border.
+    pub fn to_mesh_in(&self, min: IVec3, max: IVec3) -> Mesh {
+        let min = min.max(self.min());
+        let max = max.min(self.max());
+
+        let dim = self.dim as i32;
+        let mut builder = MeshBuilder::default();
+
+        for item in TreeWalk::new(self, min, max) {
+            match item {
+                WalkItem::Node { .. } => {}
+                WalkItem::Leaf { idx, origin } => {
+                    let leaf = &self.leafs[idx as usize];
+                    self.mesh_brick(
+                        origin,
+                        min,
+                        max,
+                        &|pos| leaf.voxels[pos_to_idx(pos, self.dim) as usize],
+                        &mut builder,
+                    );
+                }
+                WalkItem::Collapsed {
+                    origin,
+                    size,
+                    voxel,
+                } => {
+                    // Bricks of the clipped region, only the ones on its border can have faces
+                    let bmin = (min - origin).max(IVec3::ZERO) / dim;
+                    let bmax = ((max - origin + dim - 1) / dim).min(IVec3::splat(size / dim));
+                    let last = bmax - 1;
+
+                    for x in bmin.x..bmax.x {
+                        for y in bmin.y..bmax.y {
+                            let is_border =
+                                x == bmin.x || x == last.x || y == bmin.y || y == last.y;
+                            let step = if is_border {
+                                1
+                            } else {
+                                (last.z - bmin.z).max(1) as usize
+                            };
+
+                            for z in (bmin.z..bmax.z).step_by(step) {
+                                self.mesh_brick(
+                                    origin + IVec3::new(x, y, z) * dim,
+                                    min,
+                                    max,
+                                    &|_| voxel,
+                                    &mut builder,
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        builder.build()
+    }
+
+    /// `inner` returns the voxels of the brick by local position, neighbors are queried
+    /// from the tree
+    fn mesh_brick(
+        &self,
+        brick_min: IVec3,
+        min: IVec3,
+        max: IVec3,
+        inner: &dyn Fn(IVec3) -> Voxel,
+        builder: &mut MeshBuilder,
+    ) {
+        let dim = self.dim as i32;
+
+        // The brick with a border of one voxel taken from the neighbors
+        let padded = dim + 2;
+        let padded_idx = |pos: IVec3| {
+            let pos = pos + 1;
+            (pos.x + pos.y * padded + pos.z * padded * padded) as usize
+        };
+
+        let mut voxels = vec![Voxel::empty(); (padded * padded * padded) as usize];
+        for x in -1..=dim {
+            for y in -1..=dim {
+                for z in -1..=dim {
+                    let pos = IVec3::new(x, y, z);
+                    let world_pos = brick_min + pos;
+                    if world_pos.cmplt(min).any() || world_pos.cmpge(max).any() {
+                        continue;
+                    }
+
+                    let is_inner =
+                        pos.cmpge(IVec3::ZERO).all() && pos.cmplt(IVec3::splat(dim)).all();
+                    voxels[padded_idx(pos)] = if is_inner {
+                        inner(pos)
+                    } else {
+                        self.get_voxel(world_pos).unwrap_or(Voxel::empty())
+                    };
+                }
+            }
+        }
+
+        let mut mask = vec![Voxel::empty(); (dim * dim) as usize];
+
+        for axis in 0..3 {
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+
+            for sign in [1, -1] {
+                for slice in 0..dim {
+                    // Visible faces of the slice
+                    for j in 0..dim {
+                        for i in 0..dim {
+                            let mut pos = IVec3::ZERO;
+                            pos[axis] = slice;
+                            pos[u] = i;
+                            pos[v] = j;
+
+                            let mut neighbor = pos;
+                            neighbor[axis] += sign;
+
+                            let voxel = voxels[padded_idx(pos)];
+                            mask[(i + j * dim) as usize] =
+                                if voxels[padded_idx(neighbor)].is_empty() {
+                                    voxel
+                                } else {
+                                    Voxel::empty()
+                                };
+                        }
+                    }
+
+                    // Merge faces with the same voxel into rectangles
+                    for j in 0..dim {
+                        let mut i = 0;
+                        while i < dim {
+                            let voxel = mask[(i + j * dim) as usize];
+                            if voxel.is_empty() {
+                                i += 1;
+                                continue;
+                            }
+
+                            let mut w = 1;
+                            while i + w < dim && mask[(i + w + j * dim) as usize] == voxel {
+                                w += 1;
+                            }
+
+                            let mut h = 1;
+                            while j + h < dim
+                                && (i..i + w).all(|k| mask[(k + (j + h) * dim) as usize] == voxel)
+                            {
+                                h += 1;
+                            }
+
+                            for jj in j..j + h {
+                                for ii in i..i + w {
+                                    mask[(ii + jj * dim) as usize] = Voxel::empty();
+                                }
+                            }
+
+                            builder.quad(brick_min, axis, sign, slice, (i, j, w, h), voxel);
+                            i += w;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::render::mesh::VertexAttributeValues;
+
+    fn red() -> Voxel {
+        Voxel::from_color(IVec3::new(255, 0, 0))
+    }
+
+    fn quad_count(mesh: &Mesh) -> usize {
+        mesh.indices().map_or(0, |indices| indices.len()) / 6
+    }
+
+    #[test]
+    fn single_voxel_has_six_quads() {
+        let mut tree = VoxelTree::new_centered(3, 2);
+        tree.set_voxel(IVec3::ZERO, red()).unwrap();
+
+        let mesh = tree.to_mesh();
+        assert_eq!(quad_count(&mesh), 6);
+        assert_eq!(mesh.count_vertices(), 24);
+    }
+
+    #[test]
+    fn faces_between_bricks_are_culled() {
+        let mut tree = VoxelTree::new(3, 2);
+        // Neighbors across the brick border at x = 2
+        tree.set_voxel(IVec3::new(1, 0, 0), red()).unwrap();
+        tree.set_voxel(IVec3::new(2, 0, 0), red()).unwrap();
+
+        let mesh = tree.to_mesh();
+        // Every brick is meshed on its own, so the long sides aren't merged
+        assert_eq!(quad_count(&mesh), 10);
+
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            panic!("Mesh has no positions");
+        };
+        assert!(positions
+            .chunks_exact(4)
+            .all(|quad| quad.iter().any(|p| p[0] != 2.)));
+    }
+
+    #[test]
+    fn mesh_is_clipped_to_region() {
+        let mut tree = VoxelTree::new(3, 2);
+        tree.fill_box(IVec3::ZERO, IVec3::splat(4), red());
+        tree.build_lods();
+
+        // One quad per brick side on the surface of the collapsed 4^3 box
+        assert_eq!(quad_count(&tree.to_mesh()), 24);
+        // The region border closes the mesh of a single brick
+        let mesh = tree.to_mesh_in(IVec3::ZERO, IVec3::splat(2));
+        assert_eq!(quad_count(&mesh), 6);
+    }
+}
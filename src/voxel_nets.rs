@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+    },
+};
+
+use crate::{voxel_iter::*, voxel_tree::*};
+
+/// Surface nets over the occupancy of `tree` in `[min, max)`. Samples are voxel centers,
+/// every cell between 8 samples crossed by the surface gets one vertex.
+struct SurfaceNets<'a> {
+    tree: &'a VoxelTree,
+    min: IVec3,
+    max: IVec3,
+    // Vertex of a cell by the position of its min sample
+    cells: HashMap<IVec3, u32>,
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    colors: Vec<[f32; 4]>,
+    indices: Vec<u32>,
+}
+
+impl<'a> SurfaceNets<'a> {
+    fn sample(&self, pos: IVec3) -> Option<Voxel> {
+        if pos.cmplt(self.min).any() || pos.cmpge(self.max).any() {
+            return None;
+        }
+
+        self.tree.get_voxel(pos)
+    }
+
+    /// Vertex at the mean of the crossed cell edges, colored by the mean of the occupied samples
+    fn cell_vertex(&mut self, cell: IVec3) -> u32 {
+        if let Some(&idx) = self.cells.get(&cell) {
+            return idx;
+        }
+
+        let corners: [Option<Voxel>; 8] = std::array::from_fn(|i| {
+            self.sample(cell + IVec3::new(i as i32 & 1, (i as i32 >> 1) & 1, i as i32 >> 2))
+        });
+        let corner_pos =
+            |i: usize| Vec3::new((i & 1) as f32, ((i >> 1) & 1) as f32, (i >> 2) as f32);
+
+        let mut sum = Vec3::ZERO;
+        let mut num_crossed = 0;
+        for a in 0..8 {
+            for bit in [1, 2, 4] {
+                let b = a | bit;
+                if a & bit != 0 || corners[a].is_some() == corners[b].is_some() {
+                    continue;
+                }
+
+                sum += (corner_pos(a) + corner_pos(b)) * 0.5;
+                num_crossed += 1;
+            }
+        }
+
+        let mut color = Vec3::ZERO;
+        let mut num_occupied = 0;
+        for voxel in corners.iter().flatten() {
+            color += voxel.color();
+            num_occupied += 1;
+        }
+
+        // Samples are at voxel centers
+        let pos = cell.as_vec3() + 0.5 + sum / num_crossed.max(1) as f32;
+        let color = color / num_occupied.max(1) as f32;
+
+        let idx = self.positions.len() as u32;
+        self.positions.push(pos);
+        self.normals.push(Vec3::ZERO);
+        self.colors.push(color.extend(1.).to_array());
+        self.cells.insert(cell, idx);
+        idx
+    }
+
+    /// Emits a quad for every side of the occupied `pos` facing an empty sample
+    fn add_voxel(&mut self, pos: IVec3) {
+        for axis in 0..3 {
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+
+            for sign in [1, -1] {
+                let mut neighbor = pos;
+                neighbor[axis] += sign;
+                if self.sample(neighbor).is_some() {
+                    continue;
+                }
+
+                // The 4 cells around the edge between `pos` and `neighbor`
+                let mut cell = pos;
+                cell[axis] = pos[axis].min(neighbor[axis]);
+                let quad = [(-1, -1), (0, -1), (0, 0), (-1, 0)].map(|(du, dv)| {
+                    let mut cell = cell;
+                    cell[u] += du;
+                    cell[v] += dv;
+                    self.cell_vertex(cell)
+                });
+
+                // Counter-clockwise seen from the empty side
+                let quad = if sign > 0 {
+                    quad
+                } else {
+                    [quad[0], quad[3], quad[2], quad[1]]
+                };
+
+                let p = quad.map(|i| self.positions[i as usize]);
+                let normal = (p[2] - p[0]).cross(p[3] - p[1]);
+                for i in quad {
+                    self.normals[i as usize] += normal;
+                }
+
+                self.indices
+                    .extend([quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+            }
+        }
+    }
+
+    /// Only the shell of a uniform region can touch empty samples
+    fn add_region(&mut self, min: IVec3, max: IVec3) {
+        let min = min.max(self.min);
+        let max = max.min(self.max);
+        if min.cmpge(max).any() {
+            return;
+        }
+
+        let last = max - 1;
+        for x in min.x..max.x {
+            for y in min.y..max.y {
+                let is_border = x == min.x || x == last.x || y == min.y || y == last.y;
+                let step = if is_border {
+                    1
+                } else {
+                    (last.z - min.z).max(1) as usize
+                };
+
+                for z in (min.z..max.z).step_by(step) {
+                    self.add_voxel(IVec3::new(x, y, z));
+                }
+            }
+        }
+    }
+
+    fn build(self) -> Mesh {
+        let normals: Vec<_> = self
+            .normals
+            .iter()
+            .map(|n| n.normalize_or_zero().to_array())
+            .collect();
+        let positions: Vec<_> = self.positions.iter().map(|p| p.to_array()).collect();
+
+        Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, self.colors)
+        .with_inserted_indices(Indices::U32(self.indices))
+    }
+}
+
+impl VoxelTree {
+    /// Smooth surface of the whole tree, see `to_smooth_mesh_in`
+    pub fn to_smooth_mesh(&self) -> Mesh {
+        self.to_smooth_mesh_in(self.min(), self.max())
+    }
+
+    /// Surface nets mesh of the voxels in `[min, max)` in world coords, with smooth normals
+    /// and colors interpolated from the neighboring voxels. Vertices are shared between
+    /// bricks and voxels outside of the region count as empty, so the mesh is watertight.
+    pub fn to_smooth_mesh_in(&self, min: IVec3, max: IVec3) -> Mesh {
+        assert_ne!(self.depth, 0);
+
+        let mut nets = SurfaceNets {
+            tree: self,
+            min: min.max(self.min()),
+            max: max.min(self.max()),
+            cells: HashMap::new(),
+            positions: Vec::new(),
+            normals: Vec::new(),
+            colors: Vec::new(),
+            indices: Vec::new(),
+        };
+
+        for item in TreeWalk::new(self, nets.min, nets.max) {
+            match item {
+                WalkItem::Node { .. } => {}
+                WalkItem::Leaf { idx, origin } => {
+                    let leaf = &self.leafs[idx as usize];
+                    for i in mask_iter(&leaf.mask) {
+                        let pos = origin + idx_to_pos(i as i32, self.dim);
+                        if pos.cmpge(nets.min).all() && pos.cmplt(nets.max).all() {
+                            nets.add_voxel(pos);
+                        }
+                    }
+                }
+                WalkItem::Collapsed { origin, size, .. } => {
+                    nets.add_region(origin, origin + size);
+                }
+            }
+        }
+
+        nets.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn red() -> Voxel {
+        Voxel::from_color(IVec3::new(255, 0, 0))
+    }
+
+    /// Every edge of a closed mesh is walked once in each direction
+    fn assert_closed(mesh: &Mesh) {
+        let Some(Indices::U32(indices)) = mesh.indices() else {
+            panic!("Mesh has no u32 indices");
+        };
+        assert!(!indices.is_empty());
+
+        let mut edges = HashMap::new();
+        for tri in indices.chunks_exact(3) {
+            for (a, b) in [(tri[0], tri[1]), (tri[1], tri[2]), (tri[2], tri[0])] {
+                *edges.entry((a, b)).or_insert(0) += 1;
+            }
+        }
+
+        for (&(a, b), &count) in &edges {
+            assert_eq!(edges.get(&(b, a)), Some(&count), "open edge {a} - {b}");
+        }
+    }
+
+    #[test]
+    fn single_voxel_is_closed() {
+        let mut tree = VoxelTree::new_centered(3, 2);
+        tree.set_voxel(IVec3::ZERO, red()).unwrap();
+
+        let mesh = tree.to_smooth_mesh();
+        assert_closed(&mesh);
+        // One vertex per cell around the voxel
+        assert_eq!(mesh.count_vertices(), 8);
+    }
+
+    #[test]
+    fn sphere_is_closed() {
+        let mut tree = VoxelTree::new(4, 2);
+        tree.fill_sphere(Vec3::splat(8.), 5., red());
+
+        assert_closed(&tree.to_smooth_mesh());
+    }
+
+    #[test]
+    fn region_border_closes_mesh() {
+        let mut tree = VoxelTree::new(3, 2);
+        tree.fill_box(IVec3::ZERO, IVec3::splat(4), red());
+        tree.build_lods();
+
+        assert_closed(&tree.to_smooth_mesh_in(IVec3::ONE, IVec3::splat(3)));
+    }
+}
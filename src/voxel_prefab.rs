@@ -0,0 +1,208 @@
+use bevy::prelude::*;
+
+use crate::{
+    import::{is_valid_rot, rot_to_mat},
+    math::IMat4,
+    voxel_tree::*,
+};
+
+/// How `VoxelTree::paste` combines the prefab with voxels already in the tree
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Reflect)]
+pub enum PasteMode {
+    /// The whole extent of the prefab replaces the tree, empty voxels included
+    Overwrite,
+    /// Only empty voxels of the tree are written
+    KeepExisting,
+    /// Non-empty voxels of the prefab are written, the rest of the tree stays
+    #[default]
+    SkipEmpty,
+}
+
+impl VoxelTree {
+    /// Copies the voxels in `[min, max)` into a standalone tree with the same `dim`.
+    /// The new tree's `origin` is `min`, so voxels keep their world positions.
+    pub fn extract(&self, min: IVec3, max: IVec3) -> VoxelTree {
+        let min = min.max(self.min());
+        let max = max.min(self.max()).max(min);
+
+        let extent = (max - min).max_element();
+        let mut depth = 2;
+        while (self.dim as i32).pow(depth as u32) < extent {
+            depth += 1;
+        }
+
+        let mut res = VoxelTree::new(depth, self.dim);
+        res.origin = min;
+
+        for (pos, voxel) in self.iter_voxels_in(min, max) {
+            res.set_voxel(pos, voxel)
+                .expect("Extracted region fits into the tree");
+        }
+
+        res
+    }
+
+    /// Stamps `prefab` rotated by `rot` (encoded as in `.vox` files, see `rot_to_mat`)
+    /// so its `origin` lands at `offset`. The rotation keeps the prefab's content
+    /// in the positive octant of `offset`.
+    ///
+    /// Voxels that land outside of the tree are skipped and the first of them is
+    /// returned as an error. Invalid rotations are rejected before anything is written.
+    pub fn paste(
+        &mut self,
+        prefab: &VoxelTree,
+        offset: IVec3,
+        rot: u8,
+        mode: PasteMode,
+    ) -> Result<(), VoxelError> {
+        if !is_valid_rot(rot) {
+            return Err(VoxelError::InvalidRotation(rot));
+        }
+
+        let Some((_, bbox_max)) = prefab.calc_bbox() else {
+            return Ok(());
+        };
+
+        // Rotate the box `[0, extent)` relative to the prefab's origin in place
+        let extent = bbox_max.as_ivec3();
+        let rot = rot_to_mat(rot);
+        let rotated = rot.mul_vec4(extent.extend(0)).xyz();
+        let shift = (rot.mul_vec4((1 - extent).extend(0)).xyz() + rotated.abs() - 1) / 2;
+
+        let tr =
+            IMat4::from_translation(offset + shift) * rot * IMat4::from_translation(-prefab.origin);
+
+        match mode {
+            PasteMode::Overwrite => {
+                self.clear_region(offset, offset + rotated.abs());
+                self.union(prefab, &tr)
+            }
+            PasteMode::SkipEmpty => self.union(prefab, &tr),
+            PasteMode::KeepExisting => {
+                let mut res = Ok(());
+                for (pos, voxel) in prefab.iter_voxels() {
+                    let pos = (tr * pos.extend(1)).xyz();
+                    if self.get_voxel(pos).is_some() {
+                        continue;
+                    }
+
+                    if let Err(err) = self.set_voxel(pos, voxel) {
+                        res = res.and(Err(err));
+                    }
+                }
+
+                res
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDENTITY: u8 = 0b0000100;
+    // x' = -y, y' = x
+    const ROT_Z: u8 = 0b0010001;
+
+    fn voxel(r: i32) -> Voxel {
+        Voxel::new(IVec3::new(r, 0, 0), 1)
+    }
+
+    // A at (0, 0, 0), B at (1, 0, 0) and C at (0, 1, 0), `(1, 1, 0)` stays empty
+    fn prefab() -> VoxelTree {
+        let mut tree = VoxelTree::new(3, 2);
+        tree.set_voxel(IVec3::new(0, 0, 0), voxel(10)).unwrap();
+        tree.set_voxel(IVec3::new(1, 0, 0), voxel(20)).unwrap();
+        tree.set_voxel(IVec3::new(0, 1, 0), voxel(30)).unwrap();
+        tree.extract(IVec3::ZERO, IVec3::splat(2))
+    }
+
+    fn target() -> VoxelTree {
+        let mut tree = VoxelTree::new(3, 2);
+        tree.set_voxel(IVec3::new(4, 4, 4), voxel(40)).unwrap();
+        tree.set_voxel(IVec3::new(5, 5, 4), voxel(50)).unwrap();
+        tree
+    }
+
+    fn count(tree: &VoxelTree) -> usize {
+        tree.iter_voxels().count()
+    }
+
+    #[test]
+    fn extract_keeps_world_positions() {
+        let prefab = prefab();
+
+        assert_eq!(prefab.origin, IVec3::ZERO);
+        assert_eq!(prefab.get_voxel(IVec3::new(1, 0, 0)), Some(voxel(20)));
+        assert_eq!(count(&prefab), 3);
+    }
+
+    #[test]
+    fn paste_skip_empty() {
+        let mut tree = target();
+        let offset = IVec3::splat(4);
+        tree.paste(&prefab(), offset, IDENTITY, PasteMode::SkipEmpty)
+            .unwrap();
+
+        assert_eq!(tree.get_voxel(offset), Some(voxel(10)));
+        assert_eq!(tree.get_voxel(offset + IVec3::X), Some(voxel(20)));
+        assert_eq!(tree.get_voxel(offset + IVec3::Y), Some(voxel(30)));
+        assert_eq!(tree.get_voxel(IVec3::new(5, 5, 4)), Some(voxel(50)));
+        assert_eq!(count(&tree), 4);
+    }
+
+    #[test]
+    fn paste_overwrite() {
+        let mut tree = target();
+        let offset = IVec3::splat(4);
+        tree.paste(&prefab(), offset, IDENTITY, PasteMode::Overwrite)
+            .unwrap();
+
+        assert_eq!(tree.get_voxel(offset), Some(voxel(10)));
+        // Empty voxels of the prefab clear the tree
+        assert_eq!(tree.get_voxel(IVec3::new(5, 5, 4)), None);
+        assert_eq!(count(&tree), 3);
+    }
+
+    #[test]
+    fn paste_keep_existing() {
+        let mut tree = target();
+        let offset = IVec3::splat(4);
+        tree.paste(&prefab(), offset, IDENTITY, PasteMode::KeepExisting)
+            .unwrap();
+
+        assert_eq!(tree.get_voxel(offset), Some(voxel(40)));
+        assert_eq!(tree.get_voxel(offset + IVec3::X), Some(voxel(20)));
+        assert_eq!(tree.get_voxel(offset + IVec3::Y), Some(voxel(30)));
+        assert_eq!(count(&tree), 4);
+    }
+
+    #[test]
+    fn paste_rotated() {
+        let mut tree = VoxelTree::new(3, 2);
+        let offset = IVec3::splat(4);
+        tree.paste(&prefab(), offset, ROT_Z, PasteMode::SkipEmpty)
+            .unwrap();
+
+        // Rotated into the positive octant of `offset`
+        assert_eq!(tree.get_voxel(offset + IVec3::X), Some(voxel(10)));
+        assert_eq!(
+            tree.get_voxel(offset + IVec3::new(1, 1, 0)),
+            Some(voxel(20))
+        );
+        assert_eq!(tree.get_voxel(offset), Some(voxel(30)));
+        assert_eq!(count(&tree), 3);
+    }
+
+    #[test]
+    fn paste_rejects_invalid_rotations() {
+        for rot in [0b0001111, 0b0000000, 0b0000011] {
+            let mut tree = VoxelTree::new(3, 2);
+            let res = tree.paste(&prefab(), IVec3::ZERO, rot, PasteMode::SkipEmpty);
+
+            assert_eq!(res, Err(VoxelError::InvalidRotation(rot)));
+            assert_eq!(count(&tree), 0);
+        }
+    }
+}
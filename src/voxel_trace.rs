@@ -0,0 +1,292 @@
+use bevy::prelude::*;
+
+use crate::voxel_tree::*;
+
+/// CPU counterpart of `RayMarchResult` from `common.wgsl`
+#[derive(Clone, Copy, Debug)]
+pub struct RayMarchResult {
+    /// Face normal, `-step` along the last crossed axis.
+    /// Zero if the ray starts inside an occupied cell
+    pub normal: Vec3,
+    /// Point where the ray enters the hit cell
+    pub position: Vec3,
+    /// Min corner of the hit cell
+    pub voxel_pos: IVec3,
+    /// Size of the hit cell, greater than `VOXEL_SIZE` if the hit was resolved by a LOD
+    pub voxel_size: i32,
+    pub voxel: Voxel,
+    pub distance: f32,
+}
+
+struct Ray {
+    org: Vec3,
+    dir: Vec3,
+    step: IVec3,
+    inv_dir: Vec3,
+}
+
+impl VoxelTree {
+    /// Hierarchical DDA over the tree, the same traversal as `trace()` in `voxel_read.wgsl`.
+    ///
+    /// Positions are world voxels (`VOXEL_SIZE` is 1), `dir` doesn't have to be normalized.
+    /// Empty slots of a node fall back to the node's LOD voxel if there is one.
+    pub fn trace(&self, pos: Vec3, dir: Vec3) -> Option<RayMarchResult> {
+        assert_ne!(self.depth, 0);
+
+        let dir = dir.normalize_or_zero();
+        if dir == Vec3::ZERO {
+            return None;
+        }
+
+        let step = IVec3::new(
+            (dir.x > 0.) as i32 - (dir.x < 0.) as i32,
+            (dir.y > 0.) as i32 - (dir.y < 0.) as i32,
+            (dir.z > 0.) as i32 - (dir.z < 0.) as i32,
+        );
+
+        let ray = Ray {
+            org: pos,
+            dir,
+            step,
+            inv_dir: 1. / dir,
+        };
+
+        let min = self.min().as_vec3();
+        let max = self.max().as_vec3();
+        let (t_enter, normal) = ray_bbox(&ray, min, max)?;
+
+        self.trace_brick(&ray, 0, 0, min, t_enter, normal)
+    }
+
+    /// `idx` points to `nodes` or to `leafs` if `depth == self.depth - 1`
+    fn trace_brick(
+        &self,
+        ray: &Ray,
+        idx: u32,
+        depth: u8,
+        min: Vec3,
+        t_enter: f32,
+        normal: Vec3,
+    ) -> Option<RayMarchResult> {
+        let is_leaf = depth == self.depth - 1;
+        let cell_size = (self.dim as i32).pow((self.depth - 1 - depth) as u32);
+        let cell_size_f = cell_size as f32;
+
+        let entry = ray.org + ray.dir * t_enter;
+        let mut ipos = ((entry - min) / cell_size_f)
+            .floor()
+            .as_ivec3()
+            .clamp(IVec3::ZERO, IVec3::splat(self.dim as i32 - 1));
+
+        let delta = (cell_size_f * ray.inv_dir).abs();
+        let mut tmax = Vec3::ZERO;
+        for axis in 0..3 {
+            tmax[axis] = match ray.step[axis] {
+                1 => {
+                    (min[axis] + (ipos[axis] + 1) as f32 * cell_size_f - ray.org[axis])
+                        * ray.inv_dir[axis]
+                }
+                -1 => {
+                    (min[axis] + ipos[axis] as f32 * cell_size_f - ray.org[axis])
+                        * ray.inv_dir[axis]
+                }
+                _ => f32::INFINITY,
+            };
+        }
+
+        let mut t = t_enter;
+        let mut normal = normal;
+
+        loop {
+            let slot = pos_to_idx(ipos, self.dim) as usize;
+            let cell_min = min + ipos.as_vec3() * cell_size_f;
+
+            let voxel = if is_leaf {
+                let leaf = &self.leafs[idx as usize];
+                if get_mask(&leaf.mask, slot as i32) {
+                    leaf.voxels[slot]
+                } else {
+                    Voxel::empty()
+                }
+            } else {
+                let node = &self.nodes[idx as usize];
+                if get_mask(&node.mask, slot as i32) {
+                    let child_idx = node.indices[slot];
+                    let hit = self.trace_brick(ray, child_idx, depth + 1, cell_min, t, normal);
+                    if hit.is_some() {
+                        return hit;
+                    }
+
+                    Voxel::empty()
+                } else {
+                    self.lod_voxel(idx, slot)
+                }
+            };
+
+            if !voxel.is_empty() {
+                return Some(RayMarchResult {
+                    normal,
+                    position: ray.org + ray.dir * t,
+                    voxel_pos: cell_min.as_ivec3(),
+                    voxel_size: cell_size,
+                    voxel,
+                    distance: t,
+                });
+            }
+
+            let axis = if tmax.x <= tmax.y && tmax.x <= tmax.z {
+                0
+            } else if tmax.y <= tmax.z {
+                1
+            } else {
+                2
+            };
+
+            t = tmax[axis];
+            tmax[axis] += delta[axis];
+            ipos[axis] += ray.step[axis];
+
+            normal = Vec3::ZERO;
+            normal[axis] = -ray.step[axis] as f32;
+
+            if ipos[axis] < 0 || ipos[axis] >= self.dim as i32 {
+                return None;
+            }
+        }
+    }
+}
+
+/// Returns the distance to the box along the ray and the normal of the entered face,
+/// zero distance and normal if `org` is already inside
+fn ray_bbox(ray: &Ray, lb: Vec3, rt: Vec3) -> Option<(f32, Vec3)> {
+    if ray.org.cmpge(lb).all() && ray.org.cmplt(rt).all() {
+        return Some((0., Vec3::ZERO));
+    }
+
+    let t1 = (lb - ray.org) * ray.inv_dir;
+    let t2 = (rt - ray.org) * ray.inv_dir;
+
+    let tmin = t1.min(t2);
+    let tmax = t1.max(t2);
+
+    let t_enter = tmin.max_element();
+    let t_exit = tmax.min_element();
+
+    if t_exit < 0. || t_enter > t_exit {
+        return None;
+    }
+
+    let axis = if tmin.x >= tmin.y && tmin.x >= tmin.z {
+        0
+    } else if tmin.y >= tmin.z {
+        1
+    } else {
+        2
+    };
+
+    let mut normal = Vec3::ZERO;
+    normal[axis] = -ray.step[axis] as f32;
+
+    Some((t_enter, normal))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPS: f32 = 1e-4;
+
+    fn tree_with(voxels: &[IVec3]) -> VoxelTree {
+        let mut tree = VoxelTree::new(2, 4);
+        for &pos in voxels {
+            tree.set_voxel(pos, Voxel::from_color(IVec3::splat(255)))
+                .unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn axis_aligned_ray_hits_the_first_voxel() {
+        let tree = tree_with(&[IVec3::new(5, 2, 3), IVec3::new(9, 2, 3)]);
+
+        let hit = tree
+            .trace(Vec3::new(-10., 2.5, 3.5), Vec3::new(2., 0., 0.))
+            .unwrap();
+
+        assert_eq!(hit.voxel_pos, IVec3::new(5, 2, 3));
+        assert_eq!(hit.voxel_size, 1);
+        assert_eq!(hit.normal, Vec3::NEG_X);
+        assert!((hit.distance - 15.).abs() < EPS);
+        assert!(hit.position.abs_diff_eq(Vec3::new(5., 2.5, 3.5), EPS));
+    }
+
+    #[test]
+    fn diagonal_ray_steps_through_cells() {
+        let tree = tree_with(&[IVec3::new(3, 3, 3)]);
+
+        // Enters z = 3 in the empty cell (2, 3, 3), then x = 3 in the occupied one
+        let hit = tree
+            .trace(Vec3::new(-2.5, 3.5, -2.4), Vec3::new(1., 0., 1.))
+            .unwrap();
+
+        assert_eq!(hit.voxel_pos, IVec3::new(3, 3, 3));
+        assert_eq!(hit.normal, Vec3::NEG_X);
+        assert!((hit.distance - 5.5 * 2f32.sqrt()).abs() < EPS);
+        assert!(hit.position.abs_diff_eq(Vec3::new(3., 3.5, 3.1), EPS));
+    }
+
+    #[test]
+    fn normals_face_the_ray() {
+        let tree = tree_with(&[IVec3::new(6, 6, 6)]);
+
+        let from_above = tree.trace(Vec3::new(6.5, 20., 6.5), Vec3::NEG_Y).unwrap();
+        assert_eq!(from_above.normal, Vec3::Y);
+
+        let from_back = tree.trace(Vec3::new(6.5, 6.5, 10.), Vec3::NEG_Z).unwrap();
+        assert_eq!(from_back.normal, Vec3::Z);
+
+        // Starting inside of the voxel there is no face
+        let inside = tree.trace(Vec3::splat(6.5), Vec3::X).unwrap();
+        assert_eq!(inside.normal, Vec3::ZERO);
+        assert_eq!(inside.distance, 0.);
+    }
+
+    #[test]
+    fn ray_misses() {
+        let tree = tree_with(&[IVec3::new(5, 2, 3)]);
+
+        // Away from the tree
+        assert!(tree.trace(Vec3::new(-10., 2.5, 3.5), Vec3::NEG_X).is_none());
+        // Through the tree next to the voxel
+        assert!(tree.trace(Vec3::new(-10., 2.5, 4.5), Vec3::X).is_none());
+        // Zero direction
+        assert!(tree.trace(Vec3::ZERO, Vec3::ZERO).is_none());
+    }
+
+    #[test]
+    fn lod_hit_returns_the_collapsed_brick() {
+        let mut tree = VoxelTree::new_centered(2, 4);
+        let voxel = Voxel::from_color(IVec3::new(255, 0, 0));
+        // The brick `[0, 4)` in local coords
+        let min = tree.min();
+        for x in 0..4 {
+            for y in 0..4 {
+                for z in 0..4 {
+                    tree.set_voxel(min + IVec3::new(x, y, z), voxel).unwrap();
+                }
+            }
+        }
+        tree.build_lods();
+        assert!(tree.is_collapsed(0, 0));
+
+        let hit = tree
+            .trace(min.as_vec3() + Vec3::new(2., 10., 1.5), Vec3::NEG_Y)
+            .unwrap();
+
+        assert_eq!(hit.voxel_pos, min);
+        assert_eq!(hit.voxel_size, 4);
+        assert_eq!(hit.voxel, voxel);
+        assert_eq!(hit.normal, Vec3::Y);
+        assert!((hit.distance - 6.).abs() < EPS);
+    }
+}
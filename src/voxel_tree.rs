@@ -1,26 +1,56 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
 use bevy::{
     prelude::*,
     render::{render_asset::RenderAsset, render_resource::ShaderType},
 };
 
+use crate::{
+    voxel_history::BrickVoxels,
+    voxel_material::{VOXEL_MATERIALS_LEN, VOXEL_MATERIAL_DEFAULT},
+};
+
+// Defaults, every `VoxelTree` stores its own `depth` and `dim`
 pub const VOXEL_DIM: usize = 8;
 pub const VOXEL_TREE_DEPTH: usize = 6;
-pub const VOXEL_COUNT: usize = VOXEL_DIM * VOXEL_DIM * VOXEL_DIM;
 
-//pub const VOXEL_MASK_LEN: usize = (VOXEL_COUNT / 32) + ((VOXEL_COUNT % 32 != 0) as usize);
 pub const VOXEL_IDX_EMPTY: u32 = u32::MAX;
 
-pub fn pos_to_idx(ipos: IVec3) -> i32 {
-    let dim = VOXEL_DIM as i32;
+// Number of voxels in a brick of `dim^3`
+pub fn voxel_count(dim: u8) -> usize {
+    (dim as usize).pow(3)
+}
+
+// Number of `u32` words in the occupancy mask of a brick of `dim^3`
+pub fn mask_len(dim: u8) -> usize {
+    let count = voxel_count(dim);
+    count.div_ceil(32)
+}
+
+pub fn pos_to_idx(ipos: IVec3, dim: u8) -> i32 {
+    let dim = dim as i32;
     ipos.x * dim * dim + ipos.y * dim + ipos.z
 }
 
-pub fn idx_to_pos(idx: i32) -> IVec3 {
-    let dim = VOXEL_DIM as i32;
+pub fn idx_to_pos(idx: i32, dim: u8) -> IVec3 {
+    let dim = dim as i32;
     IVec3::new(idx / (dim * dim), (idx / dim) % dim, idx % dim)
 }
 
-#[derive(Reflect, Clone, Copy, Default, ShaderType, Debug)]
+/// `true` if a tree of `depth` levels with bricks of `dim^3` can be built,
+/// positions along each axis have to fit into `i32`
+pub fn is_valid_tree_dims(depth: u8, dim: u8) -> bool {
+    depth >= 2
+        && dim >= 2
+        && (dim as i64)
+            .checked_pow(depth as u32)
+            .is_some_and(|size| size <= i32::MAX as i64)
+}
+
+#[derive(Reflect, Clone, Copy, Default, ShaderType, Debug, PartialEq, Eq)]
 pub struct Voxel {
     pub data: u32,
 }
@@ -32,9 +62,18 @@ impl Voxel {
         }
     }
 
-    pub fn from_color(color: IVec3) -> Self {
-        let payload: u8 = 0;
-        let data = ((payload as u32) << 24)
+    pub fn is_empty(&self) -> bool {
+        self.data == VOXEL_IDX_EMPTY
+    }
+
+    /// `material` indexes `VoxelMaterials` and is stored in the payload byte
+    pub fn new(color: IVec3, material: u8) -> Self {
+        assert!(
+            (material as usize) < VOXEL_MATERIALS_LEN,
+            "Voxel material {material} is reserved"
+        );
+
+        let data = ((material as u32) << 24)
             | ((color.z as u32) << 16)
             | ((color.y as u32) << 8)
             | ((color.x as u32) << 0);
@@ -42,6 +81,31 @@ impl Voxel {
         Self { data }
     }
 
+    pub fn from_color(color: IVec3) -> Self {
+        Self::new(color, VOXEL_MATERIAL_DEFAULT)
+    }
+
+    /// Color in `[0, 1]`, as `unpack4x8unorm(data).xyz` in the tracer
+    pub fn color(&self) -> Vec3 {
+        let channel = |shift: u32| ((self.data >> shift) & 0xff) as f32 / 255.;
+        Vec3::new(channel(0), channel(8), channel(16))
+    }
+
+    pub fn material(&self) -> u8 {
+        (self.data >> 24) as u8
+    }
+
+    pub fn with_material(self, material: u8) -> Self {
+        assert!(
+            (material as usize) < VOXEL_MATERIALS_LEN,
+            "Voxel material {material} is reserved"
+        );
+
+        Self {
+            data: (self.data & 0x00ff_ffff) | ((material as u32) << 24),
+        }
+    }
+
     pub fn from_colorf(color: Vec3) -> Self {
         let color = color * 255.;
         let color = IVec3::new(color.x as i32, color.y as i32, color.z as i32);
@@ -49,28 +113,103 @@ impl Voxel {
     }
 }
 
-#[derive(Reflect, Clone, ShaderType, Debug)]
+#[derive(Reflect, Clone, Debug)]
 pub struct VoxelLeaf {
-    // pub mask: [u32; VOXEL_MASK_LEN],
-    pub voxels: [Voxel; VOXEL_COUNT],
+    // Bit is set for every non-empty voxel
+    pub mask: Vec<u32>,
+    pub voxels: Vec<Voxel>,
 }
 
-impl Default for VoxelLeaf {
-    fn default() -> Self {
+impl VoxelLeaf {
+    pub fn new(dim: u8) -> Self {
         Self {
-            // mask: [0; VOXEL_MASK_LEN],
-            voxels: [Voxel::empty(); VOXEL_COUNT],
+            mask: vec![0; mask_len(dim)],
+            voxels: vec![Voxel::empty(); voxel_count(dim)],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.mask.fill(0);
+        self.voxels.fill(Voxel::empty());
+    }
+
+    /// Writes the voxel keeping `mask` in sync
+    pub fn set(&mut self, idx: usize, voxel: Voxel) {
+        self.voxels[idx] = voxel;
+
+        if voxel.is_empty() {
+            clear_mask(&mut self.mask, idx as i32);
+        } else {
+            set_mask(&mut self.mask, idx as i32);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        is_mask_empty(&self.mask)
+    }
+
+    /// Recalculates `mask` from `voxels`
+    pub fn update_mask(&mut self) {
+        self.mask.fill(0);
+        for (idx, voxel) in self.voxels.iter().enumerate() {
+            if !voxel.is_empty() {
+                set_mask(&mut self.mask, idx as i32);
+            }
         }
     }
 }
 
-#[derive(Reflect, Clone, ShaderType, Debug)]
+#[derive(Reflect, Clone, Debug)]
 pub struct VoxelNode {
-    //pub mask: [u32; VOXEL_MASK_LEN],
-    pub indices: [u32; VOXEL_COUNT],
+    // LOD brick in `leafs` with a voxel per child, `VOXEL_IDX_EMPTY` until `build_lods`.
+    // A non-empty LOD voxel of a slot without a child is a collapsed uniform brick, see
+    // `VoxelTree`.
+    pub leaf: u32,
+    // Bit is set for every allocated child
+    pub mask: Vec<u32>,
+    pub indices: Vec<u32>,
 }
 
 impl VoxelNode {
+    pub fn new(dim: u8) -> Self {
+        Self {
+            leaf: VOXEL_IDX_EMPTY,
+            mask: vec![0; mask_len(dim)],
+            indices: vec![VOXEL_IDX_EMPTY; voxel_count(dim)],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.leaf = VOXEL_IDX_EMPTY;
+        self.mask.fill(0);
+        self.indices.fill(VOXEL_IDX_EMPTY);
+    }
+
+    /// Writes the child index keeping `mask` in sync
+    pub fn set(&mut self, idx: usize, child_idx: u32) {
+        self.indices[idx] = child_idx;
+
+        if child_idx == VOXEL_IDX_EMPTY {
+            clear_mask(&mut self.mask, idx as i32);
+        } else {
+            set_mask(&mut self.mask, idx as i32);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        is_mask_empty(&self.mask)
+    }
+
+    /// Recalculates `mask` from `indices`
+    pub fn update_mask(&mut self) {
+        self.mask.fill(0);
+        for (idx, &child_idx) in self.indices.iter().enumerate() {
+            if child_idx != VOXEL_IDX_EMPTY {
+                set_mask(&mut self.mask, idx as i32);
+            }
+        }
+    }
+
     //    pub fn debug_print(&self, self_idx: usize, depth: usize, tree: &VoxelTree) {
     //        let mask: u64 = ((self.mask[1] as u64) << 32) | (self.mask[0] as u64);
     //
@@ -98,124 +237,747 @@ impl VoxelNode {
     //    }
 }
 
-impl Default for VoxelNode {
-    fn default() -> Self {
-        Self {
-            //mask: [0; VOXEL_MASK_LEN],
-            indices: [VOXEL_IDX_EMPTY; VOXEL_COUNT],
+// Depending on query's depth is either:
+// - `nodes[parent_idx].indices[idx]`
+// - `leafs[parent_idx].voxels[idx]`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueryResult {
+    pub parent_idx: u32,
+    pub idx: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoxelError {
+    /// `pos` is outside of the tree's `[min, max)`
+    OutOfBounds { pos: IVec3, min: IVec3, max: IVec3 },
+    /// The rotation isn't a valid `.vox` encoding, see `is_valid_rot`
+    InvalidRotation(u8),
+}
+
+impl std::fmt::Display for VoxelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OutOfBounds { pos, min, max } => {
+                write!(f, "position {pos} is out of tree bounds [{min}, {max})")
+            }
+            Self::InvalidRotation(rot) => write!(f, "invalid rotation {rot:#09b}"),
         }
     }
 }
 
+impl std::error::Error for VoxelError {}
+
+/// Sparse tree of `dim^3` bricks, `depth - 1` levels of nodes above the leafs.
+///
+/// Collapsed uniform bricks: a brick where every voxel is the same and nothing is subdivided
+/// isn't stored. The slot in its parent has no child, the mask bit is clear and the index is
+/// `VOXEL_IDX_EMPTY`, while the slot's voxel in the parent's LOD brick (`VoxelNode::leaf`)
+/// is non-empty and stands for every voxel of the brick. A slot without a child is only
+/// empty if its LOD voxel is empty too, see `is_collapsed`. `build_lods` and the GPU draws
+/// in `draw.wgsl` collapse bricks, `expand_lod` turns them back into children for editing
+/// and everything reading the tree (`get_voxel`, `TreeWalk`, ...) handles both forms.
 #[derive(Asset, Reflect, Clone, Default, Debug)]
 pub struct VoxelTree {
+    // Number of levels, the last one is `leafs`
     pub depth: u8,
+    // Bricks are `dim^3` voxels
+    pub dim: u8,
+    // World position of the tree's min corner, positions taken and returned by the
+    // public API are in world coords. Nodes and leafs are addressed from zero.
+    pub origin: IVec3,
     pub leafs: Vec<VoxelLeaf>,
     pub nodes: Vec<VoxelNode>,
+
+    // Slots in `leafs` and `nodes` released by removal, reused before growing
+    pub free_leafs: Vec<u32>,
+    pub free_nodes: Vec<u32>,
+
+    // Number of parents referencing each brick, more than one once `dedup` shares it.
+    // LOD bricks count as children of their node.
+    pub leaf_refs: Vec<u32>,
+    pub node_refs: Vec<u32>,
+
+    // Off for new trees, so scratch trees and imports don't pay for the tracking. Once on,
+    // edits are recorded in `dirty` and bump `edit_generation`, otherwise a modified tree
+    // is extracted whole.
+    pub track_dirty: bool,
+    // Bumped by every tracked `mark_dirty`
+    pub edit_generation: u64,
+    // Cells edited through the public API by local min corner and size, with the
+    // `edit_generation` of their last edit. The render world redraws the cells newer
+    // than its last sync instead of the whole tree, see `VoxelDrawImportNode`.
+    #[reflect(ignore)]
+    pub dirty: HashMap<(IVec3, i32), u64>,
+    // `edit_generation` the render world copied the dirty bricks up to, the cells it
+    // covers are dropped by the next `mark_dirty`
+    #[reflect(ignore)]
+    pub extracted_generation: ExtractedGeneration,
+    // Set by `VoxelHistory::record`, bricks passed to `mark_dirty` are saved by world
+    // min corner before their first change
+    #[reflect(ignore)]
+    pub journal: Option<HashMap<IVec3, BrickVoxels>>,
+}
+
+/// Written through a shared reference by the render world's extraction, which mustn't
+/// mark the asset as modified
+#[derive(Default, Debug)]
+pub struct ExtractedGeneration(AtomicU64);
+
+impl ExtractedGeneration {
+    pub fn set(&self, generation: u64) {
+        self.0.store(generation, Ordering::Relaxed);
+    }
+
+    /// The generation set since the last call
+    pub fn take(&mut self) -> Option<u64> {
+        let generation = std::mem::take(self.0.get_mut());
+        (generation != 0).then_some(generation)
+    }
+}
+
+impl Clone for ExtractedGeneration {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.0.load(Ordering::Relaxed)))
+    }
 }
 
 impl VoxelTree {
-    pub fn new(depth: u8) -> Self {
-        let root = VoxelNode {
-            // mask: [0; VOXEL_MASK_LEN],
-            indices: [VOXEL_IDX_EMPTY; VOXEL_COUNT],
-        };
+    pub fn new(depth: u8, dim: u8) -> Self {
+        assert!(
+            is_valid_tree_dims(depth, dim),
+            "Invalid tree dims; depth: {depth}, dim: {dim}"
+        );
 
         Self {
             depth,
+            dim,
+            origin: IVec3::ZERO,
             leafs: Vec::new(),
-            nodes: vec![root],
+            nodes: vec![VoxelNode::new(dim)],
+            free_leafs: Vec::new(),
+            free_nodes: Vec::new(),
+            leaf_refs: Vec::new(),
+            node_refs: vec![1],
+            track_dirty: false,
+            edit_generation: 0,
+            dirty: HashMap::new(),
+            extracted_generation: default(),
+            journal: None,
         }
     }
 
+    /// The tree spans `[-size / 2, size / 2)`, so negative positions can be written
+    pub fn new_centered(depth: u8, dim: u8) -> Self {
+        let mut res = Self::new(depth, dim);
+        res.origin = IVec3::splat(-res.size() / 2);
+        res
+    }
+
     //    pub fn debug_print(&self) {
     //        error!("Num of nodes: {}", self.nodes.len());
     //
     //        self.nodes[0].debug_print(0, 0, self);
     //    }
 
-    pub fn set_or_create_node(&mut self, parent_idx: u32, pos: IVec3) -> u32 {
-        let nodes_len = self.nodes.len();
-        let parent = &mut self.nodes[parent_idx as usize];
-        let idx = pos_to_idx(pos);
+    pub(crate) fn alloc_node(&mut self) -> u32 {
+        if let Some(idx) = self.free_nodes.pop() {
+            self.node_refs[idx as usize] = 1;
+            return idx;
+        }
+
+        self.nodes.push(VoxelNode::new(self.dim));
+        self.node_refs.push(1);
+
+        (self.nodes.len() - 1) as u32
+    }
+
+    pub(crate) fn alloc_leaf(&mut self) -> u32 {
+        if let Some(idx) = self.free_leafs.pop() {
+            self.leaf_refs[idx as usize] = 1;
+            return idx;
+        }
+
+        self.leafs.push(VoxelLeaf::new(self.dim));
+        self.leaf_refs.push(1);
+
+        (self.leafs.len() - 1) as u32
+    }
+
+    /// Drops one reference to the node, it's released with its LOD brick once unreferenced
+    pub(crate) fn free_node(&mut self, idx: u32) {
+        assert_ne!(idx, 0, "Root node can't be freed");
+
+        self.node_refs[idx as usize] -= 1;
+        if self.node_refs[idx as usize] > 0 {
+            return;
+        }
+
+        let lod_idx = self.nodes[idx as usize].leaf;
+        if lod_idx != VOXEL_IDX_EMPTY {
+            self.free_leaf(lod_idx);
+        }
+
+        self.nodes[idx as usize].clear();
+        self.free_nodes.push(idx);
+    }
+
+    /// Drops one reference to the leaf, it's released once unreferenced
+    pub(crate) fn free_leaf(&mut self, idx: u32) {
+        self.leaf_refs[idx as usize] -= 1;
+        if self.leaf_refs[idx as usize] > 0 {
+            return;
+        }
+
+        self.leafs[idx as usize].clear();
+        self.free_leafs.push(idx);
+    }
+
+    /// Frees the node and everything below it. `depth` is the depth of the node itself.
+    /// A shared node only loses one reference and keeps its children.
+    pub(crate) fn free_node_recursive(&mut self, idx: u32, depth: u8) {
+        if self.node_refs[idx as usize] > 1 {
+            self.free_node(idx);
+            return;
+        }
+
+        let mask = self.nodes[idx as usize].mask.clone();
+        for i in mask_iter(&mask) {
+            let child_idx = self.nodes[idx as usize].indices[i];
+
+            if depth == self.depth - 2 {
+                self.free_leaf(child_idx);
+            } else {
+                self.free_node_recursive(child_idx, depth + 1);
+            }
+        }
 
-        // if get_mask(&parent.mask, idx) {
-        if parent.indices[idx as usize] != VOXEL_IDX_EMPTY {
-            parent.indices[idx as usize]
+        self.free_node(idx);
+    }
+
+    /// Recounts `leaf_refs` and `node_refs` from the structure, e.g. after loading.
+    /// Indices out of range are skipped, `validate` reports them.
+    pub fn update_refs(&mut self) {
+        self.leaf_refs = vec![0; self.leafs.len()];
+        self.node_refs = vec![0; self.nodes.len()];
+        self.node_refs[0] = 1;
+
+        let mut stack = vec![(0u32, 0u8)];
+        while let Some((idx, depth)) = stack.pop() {
+            let node = &self.nodes[idx as usize];
+            if node.leaf != VOXEL_IDX_EMPTY {
+                if let Some(refs) = self.leaf_refs.get_mut(node.leaf as usize) {
+                    *refs += 1;
+                }
+            }
+
+            for i in mask_iter(&node.mask) {
+                let child_idx = node.indices[i];
+                if depth == self.depth - 2 {
+                    if let Some(refs) = self.leaf_refs.get_mut(child_idx as usize) {
+                        *refs += 1;
+                    }
+                } else if let Some(refs) = self.node_refs.get_mut(child_idx as usize) {
+                    *refs += 1;
+                    // Children of a shared node are only counted once
+                    if *refs == 1 {
+                        stack.push((child_idx, depth + 1));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Copy-on-write, gives the slot its own copy of a child shared with other parents.
+    /// `depth` is the depth of the node itself.
+    pub fn unshare_child(&mut self, node_idx: u32, idx: usize, depth: u8) -> u32 {
+        let child_idx = self.nodes[node_idx as usize].indices[idx];
+
+        let new_idx = if depth == self.depth - 2 {
+            if self.leaf_refs[child_idx as usize] <= 1 {
+                return child_idx;
+            }
+
+            self.leaf_refs[child_idx as usize] -= 1;
+            let new_idx = self.alloc_leaf();
+            self.leafs[new_idx as usize] = self.leafs[child_idx as usize].clone();
+            new_idx
         } else {
-            let res = nodes_len as u32;
-            parent.indices[idx as usize] = res;
-            //set_mask(&mut parent.mask, idx);
+            if self.node_refs[child_idx as usize] <= 1 {
+                return child_idx;
+            }
 
-            self.nodes.push(VoxelNode {
-                //mask: [0; VOXEL_MASK_LEN],
-                indices: [VOXEL_IDX_EMPTY; VOXEL_COUNT],
-            });
+            self.node_refs[child_idx as usize] -= 1;
+            let new_idx = self.alloc_node();
+            let node = self.nodes[child_idx as usize].clone();
 
-            res
+            // The copy is one more parent of the children and the LOD brick
+            if node.leaf != VOXEL_IDX_EMPTY {
+                self.leaf_refs[node.leaf as usize] += 1;
+            }
+            for i in mask_iter(&node.mask) {
+                let grandchild_idx = node.indices[i] as usize;
+                if depth + 1 == self.depth - 2 {
+                    self.leaf_refs[grandchild_idx] += 1;
+                } else {
+                    self.node_refs[grandchild_idx] += 1;
+                }
+            }
+
+            self.nodes[new_idx as usize] = node;
+            new_idx
+        };
+
+        self.nodes[node_idx as usize].indices[idx] = new_idx;
+        new_idx
+    }
+
+    /// Copy-on-write for the LOD brick of the node, `VOXEL_IDX_EMPTY` if there is none
+    pub fn unshare_lod(&mut self, node_idx: u32) -> u32 {
+        let lod_idx = self.nodes[node_idx as usize].leaf;
+        if lod_idx == VOXEL_IDX_EMPTY || self.leaf_refs[lod_idx as usize] <= 1 {
+            return lod_idx;
+        }
+
+        self.leaf_refs[lod_idx as usize] -= 1;
+        let new_idx = self.alloc_leaf();
+        self.leafs[new_idx as usize] = self.leafs[lod_idx as usize].clone();
+        self.nodes[node_idx as usize].leaf = new_idx;
+        new_idx
+    }
+
+    /// `depth` is the depth of the parent node.
+    /// Shared nodes are copied, see `unshare_child`.
+    pub fn set_or_create_node(&mut self, parent_idx: u32, pos: IVec3, depth: u8) -> u32 {
+        let idx = pos_to_idx(pos, self.dim);
+
+        let parent = &self.nodes[parent_idx as usize];
+        if get_mask(&parent.mask, idx) {
+            self.unshare_child(parent_idx, idx as usize, depth)
+        } else {
+            self.expand_lod(parent_idx, idx as usize, false)
         }
     }
 
+    /// Shared leafs are copied, see `unshare_child`
     pub fn set_or_create_leaf(&mut self, parent_idx: u32, pos: IVec3) -> u32 {
         assert!(pos.x >= 0);
         assert!(pos.y >= 0);
         assert!(pos.z >= 0);
 
-        let parent = &mut self.nodes[parent_idx as usize];
-        let idx = pos_to_idx(pos);
+        let idx = pos_to_idx(pos, self.dim);
 
-        // if get_mask(&parent.mask, idx) {
-        if parent.indices[idx as usize] != VOXEL_IDX_EMPTY {
-            parent.indices[idx as usize]
+        let parent = &self.nodes[parent_idx as usize];
+        if get_mask(&parent.mask, idx) {
+            self.unshare_child(parent_idx, idx as usize, self.depth - 2)
         } else {
-            let res = self.leafs.len() as u32;
-            parent.indices[idx as usize] = res;
-            // set_mask(&mut parent.mask, idx);
+            self.expand_lod(parent_idx, idx as usize, true)
+        }
+    }
 
-            self.leafs.push(VoxelLeaf {
-                // mask: [0; VOXEL_MASK_LEN],
-                voxels: [Voxel::empty(); VOXEL_COUNT],
-            });
+    /// LOD voxel of the node's slot, empty if the node has no LOD brick
+    pub fn lod_voxel(&self, node_idx: u32, idx: usize) -> Voxel {
+        let lod_idx = self.nodes[node_idx as usize].leaf;
+        if lod_idx == VOXEL_IDX_EMPTY {
+            return Voxel::empty();
+        }
+
+        self.leafs[lod_idx as usize].voxels[idx]
+    }
+
+    /// Allocates the missing child of the slot, filled with the slot's LOD voxel,
+    /// so a collapsed uniform brick can be edited. Like `value_if_empty` in `voxel_write.wgsl`.
+    pub fn expand_lod(&mut self, parent_idx: u32, idx: usize, is_leaf: bool) -> u32 {
+        let voxel = self.lod_voxel(parent_idx, idx);
+
+        let child_idx = if is_leaf {
+            let leaf_idx = self.alloc_leaf();
+            if !voxel.is_empty() {
+                let leaf = &mut self.leafs[leaf_idx as usize];
+                leaf.voxels.fill(voxel);
+                leaf.update_mask();
+            }
+            leaf_idx
+        } else {
+            let node_idx = self.alloc_node();
+            if !voxel.is_empty() {
+                // Children of a collapsed node are collapsed too
+                let lod_idx = self.alloc_leaf();
+                let lod = &mut self.leafs[lod_idx as usize];
+                lod.voxels.fill(voxel);
+                lod.update_mask();
+                self.nodes[node_idx as usize].leaf = lod_idx;
+            }
+            node_idx
+        };
 
-            res
+        self.nodes[parent_idx as usize].set(idx, child_idx);
+        child_idx
+    }
+
+    /// Sets the child of the slot, removing a child also clears the slot's LOD voxel
+    /// so the slot doesn't turn into a collapsed brick
+    pub fn set_child(&mut self, node_idx: u32, idx: usize, child_idx: u32) {
+        self.nodes[node_idx as usize].set(idx, child_idx);
+
+        if child_idx == VOXEL_IDX_EMPTY && !self.lod_voxel(node_idx, idx).is_empty() {
+            let lod_idx = self.unshare_lod(node_idx);
+            self.leafs[lod_idx as usize].set(idx, Voxel::empty());
         }
     }
 
-    pub fn set_voxel(&mut self, pos: IVec3, voxel: Voxel) {
-        assert_ne!(self.depth, 0);
+    /// `true` if the node has neither children nor collapsed bricks
+    pub fn is_node_empty(&self, node_idx: u32) -> bool {
+        let node = &self.nodes[node_idx as usize];
+        node.is_empty()
+            && (node.leaf == VOXEL_IDX_EMPTY || self.leafs[node.leaf as usize].is_empty())
+    }
 
-        let max = (VOXEL_DIM as i32).pow(self.depth as u32);
+    /// `true` if the slot has no child but a non-empty LOD voxel, a collapsed uniform brick
+    /// as described on `VoxelTree`
+    pub fn is_collapsed(&self, node_idx: u32, idx: usize) -> bool {
+        !get_mask(&self.nodes[node_idx as usize].mask, idx as i32)
+            && !self.lod_voxel(node_idx, idx).is_empty()
+    }
 
-        if pos.x < 0 || pos.x >= max {
+    /// Records `[min, max)` in local coords as edited if `track_dirty` is on. The region
+    /// is covered by the cells of the smallest brick or node size it fits into, at most 8
+    /// of them. Cells the render world has extracted since the last call are dropped.
+    pub fn mark_dirty(&mut self, min: IVec3, max: IVec3) {
+        let extent = (max - min).max_element();
+        if extent <= 0 {
             return;
         }
 
-        if pos.y < 0 || pos.y >= max {
-            return;
+        let mut size = self.dim as i32;
+        while size < extent && size < self.size() {
+            size *= self.dim as i32;
         }
 
-        if pos.z < 0 || pos.z >= max {
+        if self.journal.is_some() {
+            self.journal_bricks(min, max);
+        }
+
+        if !self.track_dirty {
             return;
         }
 
-        // TODO: assert pos vs tree size
+        if let Some(extracted) = self.extracted_generation.take() {
+            self.dirty.retain(|_, generation| *generation > extracted);
+        }
+
+        self.edit_generation += 1;
+
+        let cell_min = min / size;
+        let cell_max = (max - 1) / size + 1;
+        for x in cell_min.x..cell_max.x {
+            for y in cell_min.y..cell_max.y {
+                for z in cell_min.z..cell_max.z {
+                    let cell = IVec3::new(x, y, z) * size;
+                    self.dirty.insert((cell, size), self.edit_generation);
+                }
+            }
+        }
+    }
+
+    /// `mark_dirty` for operations that may rewrite any slot, like `dedup` and `build_lods`
+    pub fn mark_all_dirty(&mut self) {
+        self.mark_dirty(IVec3::ZERO, IVec3::splat(self.size()));
+    }
+
+    /// Writing an empty voxel removes it, see `remove_voxel`
+    pub fn set_voxel(&mut self, pos: IVec3, voxel: Voxel) -> Result<(), VoxelError> {
+        assert_ne!(self.depth, 0);
+
+        if voxel.is_empty() {
+            return self.remove_voxel(pos).map(|_| ());
+        }
+
+        let pos = self.to_local_checked(pos)?;
+        self.mark_dirty(pos, pos + 1);
 
         let mut parent_idx = 0;
 
         for depth in (1..self.depth).rev() {
-            let local_pos = pos / (VOXEL_DIM as i32).pow(depth as u32) % (VOXEL_DIM as i32);
+            let local_pos = pos / (self.dim as i32).pow(depth as u32) % (self.dim as i32);
 
             if depth == 1 {
                 let idx = self.set_or_create_leaf(parent_idx, local_pos);
 
                 let leaf = &mut self.leafs[idx as usize];
-                let local_pos = pos % (VOXEL_DIM as i32);
-                let idx = pos_to_idx(local_pos);
-                // set_mask(&mut leaf.mask, idx);
-                leaf.voxels[idx as usize] = voxel;
+                let local_pos = pos % (self.dim as i32);
+                let idx = pos_to_idx(local_pos, self.dim);
+                leaf.set(idx as usize, voxel);
+            } else {
+                parent_idx = self.set_or_create_node(parent_idx, local_pos, self.depth - 1 - depth);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Clears the voxel and releases the leaf and every ancestor node that become empty.
+    /// Returns the removed voxel, if there was one.
+    pub fn remove_voxel(&mut self, pos: IVec3) -> Result<Option<Voxel>, VoxelError> {
+        let local = self.to_local_checked(pos)?;
+        let Some(prev) = self.get_voxel_local(local) else {
+            return Ok(None);
+        };
+
+        let idx = pos_to_idx(local % (self.dim as i32), self.dim) as usize;
+        self.update_leaf(local, |leaf| leaf.set(idx, Voxel::empty()));
+
+        Ok(Some(prev))
+    }
+
+    /// Runs `f` on the leaf containing `pos` (local coords), creating it and expanding
+    /// collapsed bricks on the way. The leaf and every ancestor node that become empty
+    /// are released afterwards.
+    pub fn update_leaf(&mut self, pos: IVec3, f: impl FnOnce(&mut VoxelLeaf)) {
+        let brick_min = pos / (self.dim as i32) * (self.dim as i32);
+        self.mark_dirty(brick_min, brick_min + self.dim as i32);
+
+        // Collect (node, slot) pairs on the path to the leaf
+        let mut path = Vec::with_capacity(self.depth as usize - 1);
+        let mut parent_idx = 0;
+        for depth in (1..self.depth).rev() {
+            let local_pos = pos / (self.dim as i32).pow(depth as u32) % (self.dim as i32);
+
+            path.push((parent_idx, pos_to_idx(local_pos, self.dim) as usize));
+            parent_idx = if depth == 1 {
+                self.set_or_create_leaf(parent_idx, local_pos)
             } else {
-                parent_idx = self.set_or_create_node(parent_idx, local_pos);
+                self.set_or_create_node(parent_idx, local_pos, self.depth - 1 - depth)
+            };
+        }
+
+        let leaf = &mut self.leafs[parent_idx as usize];
+        f(leaf);
+        if !leaf.is_empty() {
+            return;
+        }
+
+        self.free_leaf(parent_idx);
+
+        while let Some((node_idx, idx)) = path.pop() {
+            self.set_child(node_idx, idx, VOXEL_IDX_EMPTY);
+
+            if node_idx == 0 || !self.is_node_empty(node_idx) {
+                break;
             }
+
+            self.free_node(node_idx);
+        }
+    }
+
+    /// Clears all voxels in `[min, max)`, releasing bricks and nodes that become empty.
+    /// Subtrees completely inside the region are released without visiting their voxels.
+    /// The region is clipped to the tree.
+    pub fn clear_region(&mut self, min: IVec3, max: IVec3) {
+        assert_ne!(self.depth, 0);
+
+        let min = (min - self.origin).max(IVec3::ZERO);
+        let max = max - self.origin;
+        let max = max.min(IVec3::splat(self.size()));
+        if min.cmpge(max).any() {
+            return;
+        }
+
+        self.mark_dirty(min, max);
+
+        let child_size = (self.dim as i32).pow(self.depth as u32 - 1);
+        self.clear_region_node(0, IVec3::ZERO, child_size, 0, min, max);
+    }
+
+    /// Returns `true` if the node has no children left
+    fn clear_region_node(
+        &mut self,
+        node_idx: u32,
+        offset: IVec3,
+        child_size: i32,
+        depth: u8,
+        min: IVec3,
+        max: IVec3,
+    ) -> bool {
+        for idx in 0..self.voxel_count() {
+            let collapsed = self.is_collapsed(node_idx, idx);
+            if !collapsed && !get_mask(&self.nodes[node_idx as usize].mask, idx as i32) {
+                continue;
+            }
+
+            let child_min = offset + idx_to_pos(idx as i32, self.dim) * child_size;
+            let child_max = child_min + child_size;
+
+            if child_max.cmple(min).any() || child_min.cmpge(max).any() {
+                continue;
+            }
+
+            let inside = child_min.cmpge(min).all() && child_max.cmple(max).all();
+
+            if collapsed {
+                if inside {
+                    self.set_child(node_idx, idx, VOXEL_IDX_EMPTY);
+                    continue;
+                }
+
+                self.expand_lod(node_idx, idx, depth == self.depth - 2);
+            }
+
+            // Only partially cleared children are modified
+            let child_idx = if inside {
+                self.nodes[node_idx as usize].indices[idx]
+            } else {
+                self.unshare_child(node_idx, idx, depth)
+            };
+
+            let is_empty = if depth == self.depth - 2 {
+                let leaf = &mut self.leafs[child_idx as usize];
+                if !inside {
+                    let lmin = (min - child_min).max(IVec3::ZERO);
+                    let lmax = (max - child_min).min(IVec3::splat(self.dim as i32));
+
+                    for x in lmin.x..lmax.x {
+                        for y in lmin.y..lmax.y {
+                            for z in lmin.z..lmax.z {
+                                let i = pos_to_idx(IVec3::new(x, y, z), self.dim);
+                                leaf.set(i as usize, Voxel::empty());
+                            }
+                        }
+                    }
+                }
+
+                let is_empty = inside || leaf.is_empty();
+                if is_empty {
+                    self.free_leaf(child_idx);
+                }
+                is_empty
+            } else if inside {
+                self.free_node_recursive(child_idx, depth + 1);
+                true
+            } else {
+                let is_empty = self.clear_region_node(
+                    child_idx,
+                    child_min,
+                    child_size / (self.dim as i32),
+                    depth + 1,
+                    min,
+                    max,
+                );
+                if is_empty {
+                    self.free_node(child_idx);
+                }
+                is_empty
+            };
+
+            if is_empty {
+                self.set_child(node_idx, idx, VOXEL_IDX_EMPTY);
+            }
+        }
+
+        self.is_node_empty(node_idx)
+    }
+
+    /// Number of voxels in a brick
+    pub fn voxel_count(&self) -> usize {
+        voxel_count(self.dim)
+    }
+
+    /// Number of voxels along each axis
+    pub fn size(&self) -> i32 {
+        (self.dim as i32).pow(self.depth as u32)
+    }
+
+    /// World position of the min corner, including
+    pub fn min(&self) -> IVec3 {
+        self.origin
+    }
+
+    /// World position of the max corner, excluding
+    pub fn max(&self) -> IVec3 {
+        self.origin + self.size()
+    }
+
+    pub fn contains(&self, pos: IVec3) -> bool {
+        pos.cmpge(self.min()).all() && pos.cmplt(self.max()).all()
+    }
+
+    /// World position to position inside of the tree
+    pub fn to_local_checked(&self, pos: IVec3) -> Result<IVec3, VoxelError> {
+        if !self.contains(pos) {
+            return Err(VoxelError::OutOfBounds {
+                pos,
+                min: self.min(),
+                max: self.max(),
+            });
+        }
+
+        Ok(pos - self.origin)
+    }
+
+    /// Mirrors `query()` from `voxel_write.wgsl`.
+    ///
+    /// `pos` is in local coords of `depth`, i.e. the grid at `depth` has
+    /// `dim^(depth + 1)` cells along each axis.
+    /// Returns `None` if `pos` is out of range or the path is not allocated.
+    pub fn get_at_depth(&self, pos: IVec3, depth: u8) -> Option<QueryResult> {
+        assert!(depth < self.depth);
+
+        let max = (self.dim as i32).pow(depth as u32 + 1);
+        if pos.cmplt(IVec3::ZERO).any() || pos.cmpge(IVec3::splat(max)).any() {
+            return None;
+        }
+
+        let mut parent_idx = 0;
+
+        for i in (1..=depth).rev() {
+            let local_pos = pos / (self.dim as i32).pow(i as u32) % (self.dim as i32);
+            let idx = pos_to_idx(local_pos, self.dim);
+
+            let node = &self.nodes[parent_idx as usize];
+            if !get_mask(&node.mask, idx) {
+                return None;
+            }
+
+            parent_idx = node.indices[idx as usize];
+        }
+
+        let local_pos = pos % (self.dim as i32);
+        Some(QueryResult {
+            parent_idx,
+            idx: pos_to_idx(local_pos, self.dim) as u32,
+        })
+    }
+
+    /// Voxels of collapsed uniform bricks are read from the LOD of their parent node.
+    /// Positions outside of the tree are empty.
+    pub fn get_voxel(&self, pos: IVec3) -> Option<Voxel> {
+        assert_ne!(self.depth, 0);
+
+        self.get_voxel_local(self.to_local_checked(pos).ok()?)
+    }
+
+    fn get_voxel_local(&self, pos: IVec3) -> Option<Voxel> {
+        let mut parent_idx = 0;
+
+        for depth in (1..self.depth).rev() {
+            let local_pos = pos / (self.dim as i32).pow(depth as u32) % (self.dim as i32);
+            let idx = pos_to_idx(local_pos, self.dim);
+
+            let node = &self.nodes[parent_idx as usize];
+            if !get_mask(&node.mask, idx) {
+                let voxel = self.lod_voxel(parent_idx, idx as usize);
+                return (!voxel.is_empty()).then_some(voxel);
+            }
+
+            parent_idx = node.indices[idx as usize];
+        }
+
+        let idx = pos_to_idx(pos % (self.dim as i32), self.dim);
+        let voxel = self.leafs[parent_idx as usize].voxels[idx as usize];
+
+        if voxel.is_empty() {
+            None
+        } else {
+            Some(voxel)
         }
     }
 
@@ -227,12 +989,10 @@ impl VoxelTree {
         let leaf = &self.leafs[leaf_idx as usize];
 
         let mut res = (IVec3::MAX, IVec3::MIN);
-        for (idx, voxel) in leaf.voxels.iter().enumerate() {
-            if voxel.data != VOXEL_IDX_EMPTY {
-                let pos = idx_to_pos(idx as i32);
-                res.0 = res.0.min(pos);
-                res.1 = res.1.max(pos + 1);
-            }
+        for idx in mask_iter(&leaf.mask) {
+            let pos = idx_to_pos(idx as i32, self.dim);
+            res.0 = res.0.min(pos);
+            res.1 = res.1.max(pos + 1);
         }
 
         return if res.0 != IVec3::MAX { Some(res) } else { None };
@@ -251,13 +1011,18 @@ impl VoxelTree {
         let parent = &self.nodes[node_idx as usize];
 
         let mut res = (IVec3::MAX, IVec3::MIN);
-        for (idx, child_idx) in parent.indices.iter().enumerate() {
-            if let Some((min, max)) = if depth == self.depth - 2 {
-                self.calc_bbox_leaf(*child_idx)
+        for idx in 0..self.voxel_count() {
+            let child_idx = parent.indices[idx];
+            let bbox = if self.is_collapsed(node_idx, idx) {
+                Some((IVec3::ZERO, IVec3::splat(child_size)))
+            } else if depth == self.depth - 2 {
+                self.calc_bbox_leaf(child_idx)
             } else {
-                self.calc_bbox_node(*child_idx, child_size / (VOXEL_DIM as i32), depth + 1)
-            } {
-                let pos = idx_to_pos(idx as i32);
+                self.calc_bbox_node(child_idx, child_size / (self.dim as i32), depth + 1)
+            };
+
+            if let Some((min, max)) = bbox {
+                let pos = idx_to_pos(idx as i32, self.dim);
                 let offset = pos * child_size;
 
                 res.0 = res.0.min(offset + min);
@@ -268,25 +1033,52 @@ impl VoxelTree {
         return if res.0 != IVec3::MAX { Some(res) } else { None };
     }
 
+    /// Bounding box of the voxels relative to `origin`, as uploaded to the GPU
     pub fn calc_bbox(&self) -> Option<(UVec3, UVec3)> {
-        let child_size = (VOXEL_DIM as i32).pow(self.depth as u32 - 1);
+        let child_size = (self.dim as i32).pow(self.depth as u32 - 1);
         self.calc_bbox_node(0, child_size, 0)
             .map(|(min, max)| (min.try_into().unwrap(), max.try_into().unwrap()))
     }
 }
 
-// pub fn set_mask(mask: &mut [u32; VOXEL_MASK_LEN], idx: i32) {
-//     let a = idx >> 5;
-//     let b = a * 32;
-//     mask[a as usize] |= 1u32 << (idx - b);
-// }
-//
-// pub fn get_mask(mask: &[u32; VOXEL_MASK_LEN], idx: i32) -> bool {
-//     let a = idx >> 5;
-//     let b = a * 32;
-//     return (mask[a as usize] & 1u32 << (idx - b)) > 0;
-// }
-//
+pub fn set_mask(mask: &mut [u32], idx: i32) {
+    let a = idx >> 5;
+    let b = a * 32;
+    mask[a as usize] |= 1u32 << (idx - b);
+}
+
+pub fn clear_mask(mask: &mut [u32], idx: i32) {
+    let a = idx >> 5;
+    let b = a * 32;
+    mask[a as usize] &= !(1u32 << (idx - b));
+}
+
+pub fn get_mask(mask: &[u32], idx: i32) -> bool {
+    let a = idx >> 5;
+    let b = a * 32;
+    (mask[a as usize] & 1u32 << (idx - b)) > 0
+}
+
+pub fn is_mask_empty(mask: &[u32]) -> bool {
+    mask.iter().all(|&m| m == 0)
+}
+
+/// Indices of the set bits in ascending order
+pub fn mask_iter(mask: &[u32]) -> impl Iterator<Item = usize> + '_ {
+    mask.iter().enumerate().flat_map(|(i, &m)| {
+        let mut bits = m;
+        std::iter::from_fn(move || {
+            if bits == 0 {
+                return None;
+            }
+
+            let bit = bits.trailing_zeros() as usize;
+            bits &= bits - 1;
+            Some(i * 32 + bit)
+        })
+    })
+}
+
 // pub fn gen_voxel_leaf(offset: IVec3, f: &impl Fn(IVec3) -> bool) -> Option<VoxelLeaf> {
 //     let mut mask = [0u32; VOXEL_MASK_LEN];
 //     let mut add = false;
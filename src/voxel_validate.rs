@@ -0,0 +1,454 @@
+use std::fmt;
+
+use bevy::prelude::*;
+
+use crate::voxel_tree::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BrickKind {
+    Node,
+    Leaf,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TreeError {
+    /// `mask` or `indices`/`voxels` don't have the length of the tree's `dim`
+    InvalidBrickSize {
+        kind: BrickKind,
+        idx: u32,
+    },
+    /// `nodes[node].indices[slot]` is outside of `leafs` or `nodes`, whichever its depth requires
+    ChildOutOfRange {
+        node: u32,
+        slot: usize,
+        child: u32,
+    },
+    LodOutOfRange {
+        node: u32,
+        lod: u32,
+    },
+    /// The mask bit disagrees with the slot being `VOXEL_IDX_EMPTY`
+    MaskMismatch {
+        kind: BrickKind,
+        idx: u32,
+        slot: usize,
+    },
+    RootReferenced {
+        node: u32,
+        slot: usize,
+    },
+    /// The same node is reachable at different depths
+    DepthMismatch {
+        node: u32,
+    },
+    /// Referenced by more than one parent while the tree isn't sharing it
+    Shared {
+        kind: BrickKind,
+        idx: u32,
+        refs: u32,
+    },
+    /// `leaf_refs` or `node_refs` disagree with the structure
+    RefCountMismatch {
+        kind: BrickKind,
+        idx: u32,
+        expected: u32,
+        found: u32,
+    },
+    /// Neither reachable from the root nor in the free list
+    Orphan {
+        kind: BrickKind,
+        idx: u32,
+    },
+    /// Out of range, listed twice or still referenced
+    InvalidFree {
+        kind: BrickKind,
+        idx: u32,
+    },
+}
+
+impl fmt::Display for TreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidBrickSize { kind, idx } => write!(f, "{kind:?} {idx} has invalid size"),
+            Self::ChildOutOfRange { node, slot, child } => {
+                write!(f, "node {node} slot {slot} points out of range: {child}")
+            }
+            Self::LodOutOfRange { node, lod } => {
+                write!(f, "node {node} LOD points out of range: {lod}")
+            }
+            Self::MaskMismatch { kind, idx, slot } => {
+                write!(f, "{kind:?} {idx} mask disagrees with slot {slot}")
+            }
+            Self::RootReferenced { node, slot } => {
+                write!(f, "node {node} slot {slot} references the root")
+            }
+            Self::DepthMismatch { node } => write!(f, "node {node} is reachable at several depths"),
+            Self::Shared { kind, idx, refs } => {
+                write!(f, "{kind:?} {idx} is referenced {refs} times")
+            }
+            Self::RefCountMismatch {
+                kind,
+                idx,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{kind:?} {idx} has {found} references, ref count says {expected}"
+            ),
+            Self::Orphan { kind, idx } => write!(f, "{kind:?} {idx} is an orphan"),
+            Self::InvalidFree { kind, idx } => write!(f, "{kind:?} {idx} is wrongly in free list"),
+        }
+    }
+}
+
+impl std::error::Error for TreeError {}
+
+impl VoxelTree {
+    /// Checks that every index points into the right array for its depth, masks agree with
+    /// `VOXEL_IDX_EMPTY`, every brick is either reachable or free, and bricks referenced by
+    /// several parents are accounted for in `leaf_refs`/`node_refs` (see `dedup`).
+    pub fn validate(&self) -> Result<(), Vec<TreeError>> {
+        let mut errors = Vec::new();
+
+        let count = self.voxel_count();
+        let mask_len = mask_len(self.dim);
+
+        let mut leaf_found = vec![0u32; self.leafs.len()];
+        let mut node_found = vec![0u32; self.nodes.len()];
+        let mut node_depth = vec![None; self.nodes.len()];
+
+        if !self.nodes.is_empty() {
+            node_found[0] = 1;
+            node_depth[0] = Some(0);
+        }
+
+        let mut stack = if self.nodes.is_empty() {
+            vec![]
+        } else {
+            vec![(0u32, 0u8)]
+        };
+        while let Some((node_idx, depth)) = stack.pop() {
+            let node = &self.nodes[node_idx as usize];
+            if node.mask.len() != mask_len || node.indices.len() != count {
+                errors.push(TreeError::InvalidBrickSize {
+                    kind: BrickKind::Node,
+                    idx: node_idx,
+                });
+                continue;
+            }
+
+            if node.leaf != VOXEL_IDX_EMPTY {
+                match leaf_found.get_mut(node.leaf as usize) {
+                    Some(found) => *found += 1,
+                    None => errors.push(TreeError::LodOutOfRange {
+                        node: node_idx,
+                        lod: node.leaf,
+                    }),
+                }
+            }
+
+            if let Some(slot) = mask_iter(&node.mask).find(|&i| i >= count) {
+                errors.push(TreeError::MaskMismatch {
+                    kind: BrickKind::Node,
+                    idx: node_idx,
+                    slot,
+                });
+            }
+
+            for (slot, &child_idx) in node.indices.iter().enumerate() {
+                let is_set = get_mask(&node.mask, slot as i32);
+                if is_set != (child_idx != VOXEL_IDX_EMPTY) {
+                    errors.push(TreeError::MaskMismatch {
+                        kind: BrickKind::Node,
+                        idx: node_idx,
+                        slot,
+                    });
+                }
+
+                if child_idx == VOXEL_IDX_EMPTY {
+                    continue;
+                }
+
+                let out_of_range = TreeError::ChildOutOfRange {
+                    node: node_idx,
+                    slot,
+                    child: child_idx,
+                };
+
+                if depth == self.depth - 2 {
+                    match leaf_found.get_mut(child_idx as usize) {
+                        Some(found) => *found += 1,
+                        None => errors.push(out_of_range),
+                    }
+                    continue;
+                }
+
+                if child_idx == 0 {
+                    errors.push(TreeError::RootReferenced {
+                        node: node_idx,
+                        slot,
+                    });
+                    continue;
+                }
+
+                let Some(found) = node_found.get_mut(child_idx as usize) else {
+                    errors.push(out_of_range);
+                    continue;
+                };
+
+                *found += 1;
+                match node_depth[child_idx as usize] {
+                    None => {
+                        node_depth[child_idx as usize] = Some(depth + 1);
+                        stack.push((child_idx, depth + 1));
+                    }
+                    Some(d) if d != depth + 1 => {
+                        errors.push(TreeError::DepthMismatch { node: child_idx });
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        for (idx, leaf) in self.leafs.iter().enumerate() {
+            if leaf_found[idx] == 0 {
+                continue;
+            }
+
+            if leaf.mask.len() != mask_len || leaf.voxels.len() != count {
+                errors.push(TreeError::InvalidBrickSize {
+                    kind: BrickKind::Leaf,
+                    idx: idx as u32,
+                });
+                continue;
+            }
+
+            let slot = (0..count)
+                .find(|&i| get_mask(&leaf.mask, i as i32) == leaf.voxels[i].is_empty())
+                .or_else(|| mask_iter(&leaf.mask).find(|&i| i >= count));
+            if let Some(slot) = slot {
+                errors.push(TreeError::MaskMismatch {
+                    kind: BrickKind::Leaf,
+                    idx: idx as u32,
+                    slot,
+                });
+            }
+        }
+
+        check_refs(
+            BrickKind::Leaf,
+            &leaf_found,
+            &self.leaf_refs,
+            &self.free_leafs,
+            &mut errors,
+        );
+        check_refs(
+            BrickKind::Node,
+            &node_found,
+            &self.node_refs,
+            &self.free_nodes,
+            &mut errors,
+        );
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// `validate` for trees from `from_gpu_words`. The GPU drops freed slots when its free
+    /// lists are full or a pop races a push, so orphans are expected there and only counted.
+    pub fn validate_gpu(&self) -> (Result<(), Vec<TreeError>>, usize) {
+        let Err(mut errors) = self.validate() else {
+            return (Ok(()), 0);
+        };
+
+        let len = errors.len();
+        errors.retain(|err| !matches!(err, TreeError::Orphan { .. }));
+        let orphans = len - errors.len();
+
+        let res = if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        };
+        (res, orphans)
+    }
+
+    /// Tree from the words of the GPU `nodes` and `leafs` buffers, laid out as `VoxelNode`
+    /// and `VoxelLeaf` from `voxel_common.wgsl`. Masks are kept as read so `validate`
+    /// can check them. The GPU doesn't share bricks, every allocated one has a single parent.
+    /// Free list entries already popped by `alloc_leaf`/`alloc_node` are `VOXEL_IDX_EMPTY`
+    /// and skipped.
+    pub fn from_gpu_words(
+        depth: u8,
+        dim: u8,
+        nodes: &[u32],
+        leafs: &[u32],
+        free_nodes: &[u32],
+        free_leafs: &[u32],
+    ) -> Self {
+        let count = voxel_count(dim);
+        let mask_len = mask_len(dim);
+
+        let mut tree = VoxelTree {
+            depth,
+            dim,
+            ..Default::default()
+        };
+
+        for words in nodes.chunks_exact(1 + mask_len + count) {
+            let mut node = VoxelNode::new(dim);
+            node.leaf = words[0];
+            node.mask.copy_from_slice(&words[1..1 + mask_len]);
+            node.indices.copy_from_slice(&words[1 + mask_len..]);
+            tree.nodes.push(node);
+        }
+
+        for words in leafs.chunks_exact(mask_len + count) {
+            let mut leaf = VoxelLeaf::new(dim);
+            leaf.mask.copy_from_slice(&words[..mask_len]);
+            for (voxel, &data) in leaf.voxels.iter_mut().zip(&words[mask_len..]) {
+                voxel.data = data;
+            }
+            tree.leafs.push(leaf);
+        }
+
+        let popped = |idx: &&u32| **idx != VOXEL_IDX_EMPTY;
+        tree.free_nodes = free_nodes.iter().filter(popped).copied().collect();
+        tree.free_leafs = free_leafs.iter().filter(popped).copied().collect();
+
+        tree.node_refs = vec![1; tree.nodes.len()];
+        tree.leaf_refs = vec![1; tree.leafs.len()];
+        for &idx in &tree.free_nodes {
+            if let Some(refs) = tree.node_refs.get_mut(idx as usize) {
+                *refs = 0;
+            }
+        }
+        for &idx in &tree.free_leafs {
+            if let Some(refs) = tree.leaf_refs.get_mut(idx as usize) {
+                *refs = 0;
+            }
+        }
+
+        tree
+    }
+}
+
+/// `found` is the number of parents of each brick found by the walk
+fn check_refs(
+    kind: BrickKind,
+    found: &[u32],
+    refs: &[u32],
+    free: &[u32],
+    errors: &mut Vec<TreeError>,
+) {
+    let mut is_free = vec![false; found.len()];
+    for &idx in free {
+        let valid = (idx as usize) < found.len() && !is_free[idx as usize];
+        if !valid || found[idx as usize] > 0 {
+            errors.push(TreeError::InvalidFree { kind, idx });
+        }
+
+        if valid {
+            is_free[idx as usize] = true;
+        }
+    }
+
+    for (idx, &found) in found.iter().enumerate() {
+        let idx_u32 = idx as u32;
+        if found == 0 {
+            if !is_free[idx] {
+                errors.push(TreeError::Orphan { kind, idx: idx_u32 });
+            }
+            continue;
+        }
+
+        let expected = refs.get(idx).copied().unwrap_or(1);
+        if found == expected {
+            continue;
+        }
+
+        errors.push(if expected <= 1 && found > 1 {
+            TreeError::Shared {
+                kind,
+                idx: idx_u32,
+                refs: found,
+            }
+        } else {
+            TreeError::RefCountMismatch {
+                kind,
+                idx: idx_u32,
+                expected,
+                found,
+            }
+        });
+    }
+}
+
+/// Logs the first errors of `VoxelTree::validate`
+pub fn log_validation(what: &str, res: &Result<(), Vec<TreeError>>) {
+    const MAX_LOGGED: usize = 32;
+
+    let Err(errors) = res else {
+        info!("{what} is valid");
+        return;
+    };
+
+    error!("{what} has {} errors", errors.len());
+    for err in errors.iter().take(MAX_LOGGED) {
+        error!("  {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const E: u32 = VOXEL_IDX_EMPTY;
+
+    // Depth 2, dim 2: the root points at leaf 0, leaf 1 is free and leaf 2 was dropped
+    fn gpu_tree() -> VoxelTree {
+        let red = Voxel::from_color(IVec3::new(255, 0, 0)).data;
+
+        let nodes = [E, 0b1, 0, E, E, E, E, E, E, E];
+        let leafs = [
+            [0b1, red, E, E, E, E, E, E, E],
+            [0b0, E, E, E, E, E, E, E, E],
+            [0b10, E, red, E, E, E, E, E, E],
+        ]
+        .concat();
+
+        VoxelTree::from_gpu_words(2, 2, &nodes, &leafs, &[], &[E, 1, E])
+    }
+
+    #[test]
+    fn from_gpu_words_reads_bricks() {
+        let tree = gpu_tree();
+
+        assert_eq!(tree.nodes.len(), 1);
+        assert_eq!(tree.leafs.len(), 3);
+        assert_eq!(tree.free_leafs, vec![1]);
+        assert_eq!(tree.leaf_refs, vec![1, 0, 1]);
+        assert_eq!(
+            tree.get_voxel(IVec3::ZERO),
+            Some(Voxel::from_color(IVec3::new(255, 0, 0)))
+        );
+        assert_eq!(tree.get_voxel(IVec3::X), None);
+    }
+
+    #[test]
+    fn validate_gpu_counts_dropped_slots() {
+        let tree = gpu_tree();
+
+        assert_eq!(
+            tree.validate(),
+            Err(vec![TreeError::Orphan {
+                kind: BrickKind::Leaf,
+                idx: 2
+            }])
+        );
+        assert_eq!(tree.validate_gpu(), (Ok(()), 1));
+    }
+}